@@ -8,6 +8,10 @@
 //! Finally, 4 additional bytes are a CRC32 of the diff to add some security in
 //! parsing a slightly incorrect diff.
 //!
+//! Diffs that are written as their changes are produced can't know how many
+//! changes they contain up front. These use a different version byte, omit the
+//! change count, and end with an end-of-diff change byte instead.
+//!
 //! The Change byte uses the top for bits for the variant id. The lower 4 bits
 //! are able to encode additional change-specific information.
 use std::borrow::Cow;
@@ -18,14 +22,16 @@ use pot::format::Nucleus;
 use pot::reader::SliceReader;
 use pot::Value;
 
-use crate::{Change, Diff};
+use crate::{Change, ContainerKind, DeltaInstruction, Diff};
 
 const VERSION: u8 = 0;
+const STREAMED_VERSION: u8 = 1;
 // const HEADER_FLAG_CRC: u8 = 1 << 7;
 
 const KEY_FLAG: u8 = 1 << 0;
 const ROOT_FLAG: u8 = 1 << 1;
 const MAPPING_FLAG: u8 = 1 << 2;
+const RANGE_FLAG: u8 = 1 << 3;
 
 const ENTER_SEQUENCE: u8 = 0;
 const ENTER_MAP: u8 = 1;
@@ -34,83 +40,226 @@ const REPLACE: u8 = 3;
 const REMOVE: u8 = 4;
 const TRUNCATE: u8 = 5;
 const INSERT: u8 = 6;
+const MOVE: u8 = 7;
+const SPLICE: u8 = 8;
+const DELTA: u8 = 9;
+const ADD: u8 = 10;
+const COPY: u8 = 11;
+const INSERT_COPY: u8 = 12;
+const ENTER_KEY: u8 = 13;
+const END: u8 = 15;
 
 pub fn encode<W: Write>(diff: &Diff, mut writer: W) -> io::Result<()> {
     writer.write_all(&[VERSION])?;
     diff.changes.len().encode_variable(&mut writer)?;
     for change in &diff.changes {
-        match change {
-            Change::EnterSequence { index, key } => {
-                let mut flags = 0;
-                if *key {
-                    flags |= KEY_FLAG;
-                }
-                if index.is_none() {
-                    flags |= ROOT_FLAG
-                }
-                write_change_byte(&mut writer, ENTER_SEQUENCE, flags)?;
-                if let Some(index) = index {
-                    index.encode_variable(&mut writer)?;
-                }
+        encode_change(change, &mut writer)?;
+    }
+    Ok(())
+}
+
+/// Writes the header of a diff whose changes are written using
+/// [`encode_change()`] as they are produced. The diff must be finished using
+/// [`write_stream_end()`].
+pub fn write_stream_header<W: Write>(mut writer: W) -> io::Result<()> {
+    writer.write_all(&[STREAMED_VERSION])
+}
+
+/// Writes the end of a diff started with [`write_stream_header()`].
+pub fn write_stream_end<W: Write>(writer: W) -> io::Result<()> {
+    write_change_byte(writer, END, 0)
+}
+
+pub fn encode_change<W: Write>(change: &Change, mut writer: W) -> io::Result<()> {
+    match change {
+        Change::EnterSequence { index, key } => {
+            let mut flags = 0;
+            if *key {
+                flags |= KEY_FLAG;
             }
-            Change::EnterMap { index, key } => {
-                let mut flags = 0;
-                if *key {
-                    flags |= KEY_FLAG;
-                }
-                if index.is_none() {
-                    flags |= ROOT_FLAG
-                }
-                write_change_byte(&mut writer, ENTER_MAP, flags)?;
-                if let Some(index) = index {
-                    index.encode_variable(&mut writer)?;
-                }
+            if index.is_none() {
+                flags |= ROOT_FLAG
             }
-            Change::Exit => {
-                write_change_byte(&mut writer, EXIT, 0)?;
+            write_change_byte(&mut writer, ENTER_SEQUENCE, flags)?;
+            if let Some(index) = index {
+                index.encode_variable(&mut writer)?;
             }
-            Change::Replace { index, value } => {
-                let mut flags = 0;
-                if index.is_none() {
-                    flags |= ROOT_FLAG
-                }
-                write_change_byte(&mut writer, REPLACE, flags)?;
-                if let Some(index) = index {
-                    index.encode_variable(&mut writer)?;
-                }
-                write_value(&mut writer, value)?;
+        }
+        Change::EnterMap { index, key } => {
+            let mut flags = 0;
+            if *key {
+                flags |= KEY_FLAG;
+            }
+            if index.is_none() {
+                flags |= ROOT_FLAG
             }
-            Change::ReplaceKey { index, key } => {
-                write_change_byte(&mut writer, REPLACE, KEY_FLAG)?;
+            write_change_byte(&mut writer, ENTER_MAP, flags)?;
+            if let Some(index) = index {
                 index.encode_variable(&mut writer)?;
-                write_value(&mut writer, key)?;
             }
-            Change::ReplaceMapping { index, key, value } => {
-                write_change_byte(&mut writer, REPLACE, MAPPING_FLAG)?;
+        }
+        Change::Exit => {
+            write_change_byte(&mut writer, EXIT, 0)?;
+        }
+        Change::Replace { index, value } => {
+            let mut flags = 0;
+            if index.is_none() {
+                flags |= ROOT_FLAG
+            }
+            write_change_byte(&mut writer, REPLACE, flags)?;
+            if let Some(index) = index {
                 index.encode_variable(&mut writer)?;
+            }
+            write_value(&mut writer, value)?;
+        }
+        Change::ReplaceKey { index, key } => {
+            write_change_byte(&mut writer, REPLACE, KEY_FLAG)?;
+            index.encode_variable(&mut writer)?;
+            write_value(&mut writer, key)?;
+        }
+        Change::ReplaceMapping { index, key, value } => {
+            write_change_byte(&mut writer, REPLACE, MAPPING_FLAG)?;
+            index.encode_variable(&mut writer)?;
+            write_value(&mut writer, key)?;
+            write_value(&mut writer, value)?;
+        }
+        Change::ReplaceRange { index, values } => {
+            write_change_byte(&mut writer, REPLACE, RANGE_FLAG)?;
+            index.encode_variable(&mut writer)?;
+            values.len().encode_variable(&mut writer)?;
+            for value in values {
+                write_value(&mut writer, value)?;
+            }
+        }
+        Change::ReplaceMappingRange { index, mappings } => {
+            write_change_byte(&mut writer, REPLACE, RANGE_FLAG | MAPPING_FLAG)?;
+            index.encode_variable(&mut writer)?;
+            mappings.len().encode_variable(&mut writer)?;
+            for (key, value) in mappings {
                 write_value(&mut writer, key)?;
                 write_value(&mut writer, value)?;
             }
-            Change::Remove { index, length } => {
-                write_change_byte(&mut writer, REMOVE, 0)?;
+        }
+        Change::Remove { index, length } => {
+            write_change_byte(&mut writer, REMOVE, 0)?;
+            index.encode_variable(&mut writer)?;
+            length.encode_variable(&mut writer)?;
+        }
+        Change::Truncate { length } => {
+            write_change_byte(&mut writer, TRUNCATE, 0)?;
+            length.encode_variable(&mut writer)?;
+        }
+        Change::Move { from, to, length } => {
+            write_change_byte(&mut writer, MOVE, 0)?;
+            from.encode_variable(&mut writer)?;
+            to.encode_variable(&mut writer)?;
+            length.encode_variable(&mut writer)?;
+        }
+        Change::Splice {
+            index,
+            offset,
+            delete,
+            insert,
+        } => {
+            let mut flags = 0;
+            if index.is_none() {
+                flags |= ROOT_FLAG
+            }
+            write_change_byte(&mut writer, SPLICE, flags)?;
+            if let Some(index) = index {
                 index.encode_variable(&mut writer)?;
-                length.encode_variable(&mut writer)?;
             }
-            Change::Truncate { length } => {
-                write_change_byte(&mut writer, TRUNCATE, 0)?;
-                length.encode_variable(&mut writer)?;
+            offset.encode_variable(&mut writer)?;
+            delete.encode_variable(&mut writer)?;
+            insert.len().encode_variable(&mut writer)?;
+            writer.write_all(insert.as_bytes())?;
+        }
+        Change::Delta {
+            index,
+            instructions,
+        } => {
+            let mut flags = 0;
+            if index.is_none() {
+                flags |= ROOT_FLAG
             }
-            Change::Insert { index, value } => {
-                write_change_byte(&mut writer, INSERT, 0)?;
+            write_change_byte(&mut writer, DELTA, flags)?;
+            if let Some(index) = index {
                 index.encode_variable(&mut writer)?;
-                write_value(&mut writer, value)?;
             }
-            Change::InsertMapping { index, key, value } => {
-                write_change_byte(&mut writer, INSERT, MAPPING_FLAG)?;
+            instructions.len().encode_variable(&mut writer)?;
+            for instruction in instructions {
+                // The low bit of the length distinguishes copies from
+                // literals.
+                match instruction {
+                    DeltaInstruction::Copy { offset, length } => {
+                        (length << 1 | 1).encode_variable(&mut writer)?;
+                        offset.encode_variable(&mut writer)?;
+                    }
+                    DeltaInstruction::Literal(bytes) => {
+                        (bytes.len() << 1).encode_variable(&mut writer)?;
+                        writer.write_all(bytes)?;
+                    }
+                }
+            }
+        }
+        Change::Add { index, delta } => {
+            let mut flags = 0;
+            if index.is_none() {
+                flags |= ROOT_FLAG
+            }
+            write_change_byte(&mut writer, ADD, flags)?;
+            if let Some(index) = index {
+                index.encode_variable(&mut writer)?;
+            }
+            write_value(&mut writer, delta)?;
+        }
+        Change::Copy { index, source } => {
+            let mut flags = 0;
+            if index.is_none() {
+                flags |= ROOT_FLAG
+            }
+            write_change_byte(&mut writer, COPY, flags)?;
+            if let Some(index) = index {
                 index.encode_variable(&mut writer)?;
-                write_value(&mut writer, key)?;
-                write_value(&mut writer, value)?;
             }
+            write_path(&mut writer, source)?;
+        }
+        Change::InsertCopy { index, source } => {
+            write_change_byte(&mut writer, INSERT_COPY, 0)?;
+            index.encode_variable(&mut writer)?;
+            write_path(&mut writer, source)?;
+        }
+        Change::Insert { index, value } => {
+            write_change_byte(&mut writer, INSERT, 0)?;
+            index.encode_variable(&mut writer)?;
+            write_value(&mut writer, value)?;
+        }
+        Change::InsertMapping { index, key, value } => {
+            write_change_byte(&mut writer, INSERT, MAPPING_FLAG)?;
+            index.encode_variable(&mut writer)?;
+            write_value(&mut writer, key)?;
+            write_value(&mut writer, value)?;
+        }
+        Change::EnterKey { key } => {
+            write_change_byte(&mut writer, ENTER_KEY, 0)?;
+            write_value(&mut writer, key)?;
+        }
+        Change::SetKey { key, value } => {
+            write_change_byte(&mut writer, INSERT, KEY_FLAG | MAPPING_FLAG)?;
+            write_value(&mut writer, key)?;
+            write_value(&mut writer, value)?;
+        }
+        Change::RemoveKey { key } => {
+            write_change_byte(&mut writer, REMOVE, KEY_FLAG | MAPPING_FLAG)?;
+            write_value(&mut writer, key)?;
+        }
+        Change::InsertMember { value } => {
+            write_change_byte(&mut writer, INSERT, KEY_FLAG)?;
+            write_value(&mut writer, value)?;
+        }
+        Change::RemoveMember { value } => {
+            write_change_byte(&mut writer, REMOVE, KEY_FLAG)?;
+            write_value(&mut writer, value)?;
         }
     }
     Ok(())
@@ -172,10 +321,59 @@ fn write_value<W: Write>(writer: &mut W, value: &Value<'_>) -> io::Result<()> {
     Ok(())
 }
 
+/// Returns the number of bytes used to encode `argument` as a variable integer.
+pub fn argument_len(argument: usize) -> usize {
+    (argument as u64)
+        .encode_variable(io::sink())
+        .expect("infallible")
+}
+
+/// Returns the number of bytes `write_value` writes for `value`.
+pub fn value_len(value: &Value<'_>) -> usize {
+    let mut counter = ByteCounter::default();
+    write_value(&mut counter, value).expect("infallible");
+    counter.0
+}
+
+/// Returns the number of bytes `write_value` writes for the header of a
+/// container with `length` entries.
+pub fn container_header_len(kind: ContainerKind, length: usize) -> usize {
+    let kind = match kind {
+        ContainerKind::Sequence => pot::format::Kind::Sequence,
+        ContainerKind::Mappings => pot::format::Kind::Map,
+    };
+    pot::format::write_atom_header(io::sink(), kind, Some(length as u64)).expect("infallible")
+}
+
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 pub fn decode(bytes: &[u8]) -> Result<Diff, DecodeError> {
     let mut bytes = SliceReader::from(bytes);
     let header = read_byte(&mut bytes)?;
-    if header & 0x7F != 0 {
+    if header & 0x7F == STREAMED_VERSION {
+        let mut diff = Diff {
+            changes: Vec::new(),
+        };
+        loop {
+            let header = read_byte(&mut bytes)?;
+            if header >> 4 == END {
+                return Ok(diff);
+            }
+            diff.changes.push(read_change(header, &mut bytes)?);
+        }
+    } else if header & 0x7F != VERSION {
         Err(DecodeError::UnsupportedVersion)
     } else {
         let number_of_changes = usize::decode_variable(&mut bytes)?;
@@ -188,18 +386,36 @@ pub fn decode(bytes: &[u8]) -> Result<Diff, DecodeError> {
             changes: Vec::with_capacity(number_of_changes),
         };
         for _ in 0..number_of_changes {
-            diff.changes.push(read_change(&mut bytes)?);
+            let header = read_byte(&mut bytes)?;
+            diff.changes.push(read_change(header, &mut bytes)?);
         }
         Ok(diff)
     }
 }
 
+fn write_path<W: Write>(mut writer: W, path: &[usize]) -> io::Result<()> {
+    path.len().encode_variable(&mut writer)?;
+    for index in path {
+        index.encode_variable(&mut writer)?;
+    }
+    Ok(())
+}
+
+fn read_path(bytes: &mut SliceReader<'_>) -> Result<Vec<usize>, DecodeError> {
+    let length = usize::decode_variable(&mut *bytes)?;
+    if length > bytes.len() {
+        return Err(DecodeError::InvalidData);
+    }
+    (0..length)
+        .map(|_| usize::decode_variable(&mut *bytes).map_err(DecodeError::from))
+        .collect()
+}
+
 fn check_bit(source: u8, flag: u8) -> bool {
     (source & flag) != 0
 }
 
-fn read_change(bytes: &mut SliceReader<'_>) -> Result<Change, DecodeError> {
-    let header = read_byte(bytes)?;
+fn read_change(header: u8, bytes: &mut SliceReader<'_>) -> Result<Change, DecodeError> {
     let variant = header >> 4;
     match variant {
         ENTER_SEQUENCE => {
@@ -227,8 +443,9 @@ fn read_change(bytes: &mut SliceReader<'_>) -> Result<Change, DecodeError> {
             let key = check_bit(header, KEY_FLAG);
             let is_root = check_bit(header, ROOT_FLAG);
             let is_mapping = check_bit(header, MAPPING_FLAG);
-            match (is_root, key, is_mapping) {
-                (_, false, false) => {
+            let is_range = check_bit(header, RANGE_FLAG);
+            match (is_root, key, is_mapping, is_range) {
+                (_, false, false, false) => {
                     let index = if is_root {
                         None
                     } else {
@@ -237,20 +454,50 @@ fn read_change(bytes: &mut SliceReader<'_>) -> Result<Change, DecodeError> {
                     let value = read_value(bytes)?;
                     Ok(Change::Replace { index, value })
                 }
-                (false, true, false) => {
+                (false, true, false, false) => {
                     let index = usize::decode_variable(&mut *bytes)?;
                     let key = read_value(bytes)?;
                     Ok(Change::ReplaceKey { index, key })
                 }
-                (false, false, true) => {
+                (false, false, true, false) => {
                     let index = usize::decode_variable(&mut *bytes)?;
                     let key = read_value(bytes)?;
                     let value = read_value(bytes)?;
                     Ok(Change::ReplaceMapping { index, key, value })
                 }
+                (false, false, false, true) => {
+                    let index = usize::decode_variable(&mut *bytes)?;
+                    let length = usize::decode_variable(&mut *bytes)?;
+                    if length > bytes.len() {
+                        return Err(DecodeError::InvalidData);
+                    }
+                    let values = (0..length)
+                        .map(|_| read_value(bytes))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Change::ReplaceRange { index, values })
+                }
+                (false, false, true, true) => {
+                    let index = usize::decode_variable(&mut *bytes)?;
+                    let length = usize::decode_variable(&mut *bytes)?;
+                    if length > bytes.len() {
+                        return Err(DecodeError::InvalidData);
+                    }
+                    let mappings = (0..length)
+                        .map(|_| Ok((read_value(bytes)?, read_value(bytes)?)))
+                        .collect::<Result<_, DecodeError>>()?;
+                    Ok(Change::ReplaceMappingRange { index, mappings })
+                }
                 _ => Err(DecodeError::InvalidData),
             }
         }
+        REMOVE if check_bit(header, KEY_FLAG) => {
+            let value = read_value(bytes)?;
+            if check_bit(header, MAPPING_FLAG) {
+                Ok(Change::RemoveKey { key: value })
+            } else {
+                Ok(Change::RemoveMember { value })
+            }
+        }
         REMOVE => {
             let index = usize::decode_variable(&mut *bytes)?;
             let length = usize::decode_variable(&mut *bytes)?;
@@ -260,6 +507,108 @@ fn read_change(bytes: &mut SliceReader<'_>) -> Result<Change, DecodeError> {
             let length = usize::decode_variable(&mut *bytes)?;
             Ok(Change::Truncate { length })
         }
+        MOVE => {
+            let from = usize::decode_variable(&mut *bytes)?;
+            let to = usize::decode_variable(&mut *bytes)?;
+            let length = usize::decode_variable(&mut *bytes)?;
+            Ok(Change::Move { from, to, length })
+        }
+        SPLICE => {
+            let is_root = check_bit(header, ROOT_FLAG);
+            let index = if is_root {
+                None
+            } else {
+                Some(usize::decode_variable(&mut *bytes)?)
+            };
+            let offset = usize::decode_variable(&mut *bytes)?;
+            let delete = usize::decode_variable(&mut *bytes)?;
+            let insert_length = usize::decode_variable(&mut *bytes)?;
+            if insert_length > bytes.len() {
+                return Err(DecodeError::InvalidData);
+            }
+            let mut insert = vec![0; insert_length];
+            bytes.read_exact(&mut insert)?;
+            let insert = String::from_utf8(insert).map_err(|_| DecodeError::InvalidData)?;
+            Ok(Change::Splice {
+                index,
+                offset,
+                delete,
+                insert,
+            })
+        }
+        DELTA => {
+            let is_root = check_bit(header, ROOT_FLAG);
+            let index = if is_root {
+                None
+            } else {
+                Some(usize::decode_variable(&mut *bytes)?)
+            };
+            let instruction_count = usize::decode_variable(&mut *bytes)?;
+            if instruction_count > bytes.len() {
+                return Err(DecodeError::InvalidData);
+            }
+            let mut instructions = Vec::with_capacity(instruction_count);
+            for _ in 0..instruction_count {
+                let length = usize::decode_variable(&mut *bytes)?;
+                if length & 1 == 1 {
+                    let offset = usize::decode_variable(&mut *bytes)?;
+                    instructions.push(DeltaInstruction::Copy {
+                        offset,
+                        length: length >> 1,
+                    });
+                } else {
+                    let length = length >> 1;
+                    if length > bytes.len() {
+                        return Err(DecodeError::InvalidData);
+                    }
+                    let mut literal = vec![0; length];
+                    bytes.read_exact(&mut literal)?;
+                    instructions.push(DeltaInstruction::Literal(literal));
+                }
+            }
+            Ok(Change::Delta {
+                index,
+                instructions,
+            })
+        }
+        ADD => {
+            let is_root = check_bit(header, ROOT_FLAG);
+            let index = if is_root {
+                None
+            } else {
+                Some(usize::decode_variable(&mut *bytes)?)
+            };
+            let delta = read_value(bytes)?;
+            Ok(Change::Add { index, delta })
+        }
+        COPY => {
+            let is_root = check_bit(header, ROOT_FLAG);
+            let index = if is_root {
+                None
+            } else {
+                Some(usize::decode_variable(&mut *bytes)?)
+            };
+            let source = read_path(bytes)?;
+            Ok(Change::Copy { index, source })
+        }
+        INSERT_COPY => {
+            let index = usize::decode_variable(&mut *bytes)?;
+            let source = read_path(bytes)?;
+            Ok(Change::InsertCopy { index, source })
+        }
+        ENTER_KEY => {
+            let key = read_value(bytes)?;
+            Ok(Change::EnterKey { key })
+        }
+        INSERT if check_bit(header, KEY_FLAG) => {
+            let key = read_value(bytes)?;
+            if check_bit(header, MAPPING_FLAG) {
+                let value = read_value(bytes)?;
+                Ok(Change::SetKey { key, value })
+            } else {
+                Ok(Change::InsertMember { value: key })
+            }
+        }
         INSERT => {
             let is_mapping = check_bit(header, MAPPING_FLAG);
             let index = usize::decode_variable(&mut *bytes)?;
@@ -302,7 +651,7 @@ fn read_value(bytes: &mut SliceReader<'_>) -> Result<Value<'static>, DecodeError
         }
         pot::format::Kind::Sequence => {
             let length = atom.arg as usize;
-            if length < bytes.len() {
+            if length <= bytes.len() {
                 let mut values = Vec::with_capacity(length);
                 for _ in 0..length {
                     values.push(read_value(bytes)?);
@@ -314,7 +663,7 @@ fn read_value(bytes: &mut SliceReader<'_>) -> Result<Value<'static>, DecodeError
         }
         pot::format::Kind::Map => {
             let length = atom.arg as usize;
-            if length < bytes.len() {
+            if length <= bytes.len() {
                 let mut values = Vec::with_capacity(length);
                 for _ in 0..length {
                     let key = read_value(bytes)?;
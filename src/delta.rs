@@ -0,0 +1,305 @@
+//! Block-matching delta encoding of byte buffers.
+//!
+//! The original buffer is split into fixed-size blocks, which are indexed by a
+//! rolling hash. The updated buffer is then scanned one byte at a time looking
+//! for windows whose hash matches an original block. Each confirmed match is
+//! extended in both directions and emitted as a copy, and the bytes between
+//! matches are emitted as literals. This is the same approach used by rsync.
+//!
+//! Bytes that are unchanged at the start and the end of the buffers are copied
+//! without being indexed, so only the window between them is scanned.
+
+use std::collections::HashMap;
+
+use crate::{myers, Budget, CostModel, DeltaInstruction};
+
+const BLOCK_SIZE: usize = 16;
+const BASE: u64 = 257;
+/// The factor of the byte leaving the rolling hash's window.
+const OUTGOING_FACTOR: u64 = BASE.wrapping_pow(BLOCK_SIZE as u32 - 1);
+/// The most blocks sharing a hash that are checked for each window. Only the
+/// most recently indexed blocks are checked, which keeps repetitive buffers
+/// from comparing every window against every block.
+const MAX_CANDIDATES: usize = 8;
+/// The furthest a match is extended in each direction. Longer runs are
+/// emitted as consecutive copies.
+const MAX_EXTENSION: usize = 1 << 16;
+
+/// Returns the instructions that produce `updated` from `original`, or None
+/// if `budget` was spent before they were found. Each candidate block that is
+/// checked for a match counts as a comparison.
+pub fn instructions(
+    original: &[u8],
+    updated: &[u8],
+    budget: &Budget,
+) -> Option<Vec<DeltaInstruction>> {
+    let (prefix, suffix) = myers::common_affixes(
+        original.len(),
+        updated.len(),
+        |original_index, updated_index| original[original_index] == updated[updated_index],
+    );
+    let mut instructions = Vec::new();
+    if prefix > 0 {
+        instructions.push(DeltaInstruction::Copy {
+            offset: 0,
+            length: prefix,
+        });
+    }
+    match_blocks(
+        &original[prefix..original.len() - suffix],
+        &updated[prefix..updated.len() - suffix],
+        prefix,
+        budget,
+        &mut instructions,
+    )?;
+    if suffix > 0 {
+        instructions.push(DeltaInstruction::Copy {
+            offset: original.len() - suffix,
+            length: suffix,
+        });
+    }
+    Some(instructions)
+}
+
+/// Pushes the instructions that produce `updated` from `original`, which
+/// starts at `base` within the original buffer. Returns None if `budget` was
+/// spent first.
+fn match_blocks(
+    original: &[u8],
+    updated: &[u8],
+    base: usize,
+    budget: &Budget,
+    instructions: &mut Vec<DeltaInstruction>,
+) -> Option<()> {
+    let mut blocks = HashMap::<u64, Vec<usize>>::new();
+    for offset in (0..original.len() / BLOCK_SIZE).map(|block| block * BLOCK_SIZE) {
+        blocks
+            .entry(hash(&original[offset..offset + BLOCK_SIZE]))
+            .or_default()
+            .push(offset);
+    }
+
+    let mut literal_start = 0;
+    let mut position = 0;
+    if !blocks.is_empty() && updated.len() >= BLOCK_SIZE {
+        let mut window_hash = hash(&updated[..BLOCK_SIZE]);
+        loop {
+            let mut best_match = None;
+            for &offset in blocks
+                .get(&window_hash)
+                .into_iter()
+                .flat_map(|offsets| offsets.iter().rev().take(MAX_CANDIDATES))
+            {
+                budget.compare();
+                if budget.is_exhausted() {
+                    return None;
+                }
+                if original[offset..offset + BLOCK_SIZE] != updated[position..position + BLOCK_SIZE]
+                {
+                    continue;
+                }
+                let forward = original[offset..]
+                    .iter()
+                    .zip(&updated[position..])
+                    .take(MAX_EXTENSION)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let backward = original[..offset]
+                    .iter()
+                    .rev()
+                    .zip(updated[literal_start..position].iter().rev())
+                    .take(MAX_EXTENSION)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let length = backward + forward;
+                // Ties go to the earliest block.
+                if best_match.map_or(true, |(_, _, best)| length >= best) {
+                    best_match = Some((offset - backward, backward, length));
+                }
+            }
+
+            if let Some((offset, backward, length)) = best_match {
+                if literal_start < position - backward {
+                    instructions.push(DeltaInstruction::Literal(
+                        updated[literal_start..position - backward].to_vec(),
+                    ));
+                }
+                instructions.push(DeltaInstruction::Copy {
+                    offset: base + offset,
+                    length,
+                });
+                position += length - backward;
+                literal_start = position;
+                if position + BLOCK_SIZE > updated.len() {
+                    break;
+                }
+                window_hash = hash(&updated[position..position + BLOCK_SIZE]);
+            } else if position + BLOCK_SIZE < updated.len() {
+                window_hash = roll(
+                    window_hash,
+                    updated[position],
+                    updated[position + BLOCK_SIZE],
+                );
+                position += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    if literal_start < updated.len() {
+        instructions.push(DeltaInstruction::Literal(updated[literal_start..].to_vec()));
+    }
+    Some(())
+}
+
+/// Returns the cost of `instructions` once encoded, excluding the instruction
+/// count.
+pub fn cost(instructions: &[DeltaInstruction], costs: &dyn CostModel) -> usize {
+    instructions
+        .iter()
+        .map(|instruction| match instruction {
+            DeltaInstruction::Copy { offset, length } => {
+                costs.argument(length << 1 | 1) + costs.argument(*offset)
+            }
+            DeltaInstruction::Literal(bytes) => {
+                costs.argument(bytes.len() << 1) + costs.raw_bytes(bytes)
+            }
+        })
+        .fold(0, usize::saturating_add)
+}
+
+/// Applies `instructions` to `original`, returning None if an instruction
+/// copies bytes outside of `original`.
+pub fn apply(original: &[u8], instructions: &[DeltaInstruction]) -> Option<Vec<u8>> {
+    let mut updated = Vec::new();
+    for instruction in instructions {
+        match instruction {
+            DeltaInstruction::Copy { offset, length } => {
+                updated.extend_from_slice(original.get(*offset..offset.checked_add(*length)?)?);
+            }
+            DeltaInstruction::Literal(bytes) => updated.extend_from_slice(bytes),
+        }
+    }
+    Some(updated)
+}
+
+fn hash(window: &[u8]) -> u64 {
+    window.iter().fold(0, |hash, &byte| {
+        hash.wrapping_mul(BASE).wrapping_add(u64::from(byte))
+    })
+}
+
+fn roll(hash: u64, outgoing: u8, incoming: u8) -> u64 {
+    hash.wrapping_sub(u64::from(outgoing).wrapping_mul(OUTGOING_FACTOR))
+        .wrapping_mul(BASE)
+        .wrapping_add(u64::from(incoming))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, hash, roll, BLOCK_SIZE, MAX_EXTENSION};
+    use crate::{Budget, DeltaInstruction, DiffOptions};
+
+    fn instructions(original: &[u8], updated: &[u8]) -> Vec<DeltaInstruction> {
+        super::instructions(original, updated, &Budget::new(&DiffOptions::default())).unwrap()
+    }
+
+    #[test]
+    fn rolling_hash() {
+        let bytes = (0..=255).collect::<Vec<u8>>();
+        let mut window_hash = hash(&bytes[..BLOCK_SIZE]);
+        for position in 1..bytes.len() - BLOCK_SIZE {
+            window_hash = roll(
+                window_hash,
+                bytes[position - 1],
+                bytes[position + BLOCK_SIZE - 1],
+            );
+            assert_eq!(window_hash, hash(&bytes[position..position + BLOCK_SIZE]));
+        }
+    }
+
+    #[test]
+    fn copies_and_literals() {
+        let original = (0..=255).collect::<Vec<u8>>();
+        let mut updated = original.clone();
+        updated[100] = 0;
+        updated.splice(200..200, *b"inserted");
+        assert_eq!(
+            instructions(&original, &updated),
+            vec![
+                DeltaInstruction::Copy {
+                    offset: 0,
+                    length: 100
+                },
+                DeltaInstruction::Literal(vec![0]),
+                DeltaInstruction::Copy {
+                    offset: 101,
+                    length: 99
+                },
+                DeltaInstruction::Literal(b"inserted".to_vec()),
+                DeltaInstruction::Copy {
+                    offset: 200,
+                    length: 56
+                },
+            ]
+        );
+        assert_eq!(
+            apply(&original, &instructions(&original, &updated)).unwrap(),
+            updated
+        );
+
+        assert_eq!(
+            instructions(&original[1..10], &updated),
+            vec![DeltaInstruction::Literal(updated.clone())]
+        );
+        // The unchanged start is copied even though it is shorter than a block.
+        assert_eq!(
+            instructions(&original[..10], &updated),
+            vec![
+                DeltaInstruction::Copy {
+                    offset: 0,
+                    length: 10
+                },
+                DeltaInstruction::Literal(updated[10..].to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn repetitive_buffers() {
+        // Every block of these buffers shares a hash, and the unchanged run
+        // between the edited ends is longer than a single copy may be.
+        let original = b"0123456789abcdef".repeat(3 * MAX_EXTENSION / BLOCK_SIZE);
+        let mut updated = original.clone();
+        updated[0] = b'x';
+        *updated.last_mut().unwrap() = b'x';
+        let delta = instructions(&original, &updated);
+        assert!(delta.iter().all(|instruction| match instruction {
+            DeltaInstruction::Copy { length, .. } => *length <= 2 * MAX_EXTENSION,
+            DeltaInstruction::Literal(_) => true,
+        }));
+        assert_eq!(apply(&original, &delta).unwrap(), updated);
+    }
+
+    #[test]
+    fn budgets() {
+        // Every block of the original is found in reverse order, which checks
+        // a candidate for each of them.
+        let original = (0..=255).collect::<Vec<u8>>();
+        let updated = original
+            .chunks(BLOCK_SIZE)
+            .rev()
+            .flatten()
+            .copied()
+            .collect::<Vec<u8>>();
+        assert_eq!(instructions(&original, &updated).len(), 256 / BLOCK_SIZE);
+        let budget = Budget::new(&DiffOptions::default().max_comparisons(10));
+        assert_eq!(super::instructions(&original, &updated, &budget), None);
+        // Without blocks to check, the budget isn't spent.
+        assert_eq!(
+            super::instructions(&original[..8], &updated[..8], &budget),
+            Some(vec![DeltaInstruction::Literal(updated[..8].to_vec())])
+        );
+    }
+}
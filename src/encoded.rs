@@ -0,0 +1,709 @@
+//! Diffing of Pot-encoded documents without decoding them into [`Value`]s.
+//!
+//! Both documents are walked one atom at a time. Values whose encodings are
+//! byte-identical are skipped without being decoded. When both documents
+//! contain a sequence, or a mapping, the walk continues within it so that
+//! only the entries that differ are visited. Containers whose lengths differ
+//! are first aligned by the encodings of their values, or of their keys for
+//! mappings, and the unmatched values are removed and inserted. Every other
+//! pair of differing values is decoded and diffed as usual.
+//!
+//! Pot encodes identifiers such as field names as symbols, which are written
+//! out the first time they appear and referenced by their id afterwards.
+//! Identical bytes that contain symbols are therefore only treated as equal
+//! when both documents defined the same symbols before them.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::{iter, str};
+
+use pot::format::{Atom, Kind, Nucleus};
+use pot::reader::SliceReader;
+use pot::Value;
+
+use crate::binary::DecodeError;
+use crate::hash::HashTree;
+use crate::{
+    myers, Budget, Change, ChangeKind, Changes, ContainerKind, Context, Diff, DiffOptions,
+    Estimated, SubtreeIndex,
+};
+
+/// The argument of a special atom that precedes an enum variant's name and
+/// contents.
+const NAMED: u64 = 4;
+/// The argument of a special atom that begins a mapping of unknown length.
+const DYNAMIC_MAP: u64 = 5;
+/// The argument of a special atom that ends a mapping of unknown length.
+const DYNAMIC_END: u64 = 6;
+
+pub fn diff(original: &[u8], updated: &[u8], options: &DiffOptions) -> Result<Diff, DecodeError> {
+    let mut original = Document::new(original)?;
+    let mut updated = Document::new(updated)?;
+    let original_root = original.skip(original.start)?;
+    let updated_root = updated.skip(updated.start)?;
+    let mut walk = Walk { original, updated };
+    if walk.same(&original_root, &updated_root) {
+        return Ok(Diff {
+            changes: Vec::new(),
+        });
+    }
+
+    // The walk has no decoded original value, so nothing can be copied from
+    // it. Values that are decoded are diffed using their own context.
+    let subtrees = SubtreeIndex::default();
+    let budget = Budget::new(options);
+    let context = Context {
+        original: &Value::None,
+        subtrees: &subtrees,
+        options,
+        budget: &budget,
+        depth: 0,
+        path: None,
+    };
+    let mut diff = Changes::new(&context);
+    if let Some(contents) = walk.contents(&original_root, &updated_root, &context)? {
+        walk.diff_contents(None, contents, &context, &mut diff)?;
+    } else {
+        let original = walk.original.decode(original_root.start)?.0;
+        let updated = walk.updated.decode(updated_root.start)?.0;
+        return Ok(Diff::create(&original, &updated, options).0);
+    }
+    if options
+        .max_changes
+        .map_or(false, |max_changes| diff.changes.len() > max_changes)
+    {
+        let updated = walk.updated.decode(updated_root.start)?.0;
+        return Ok(Diff {
+            changes: vec![Change::Replace {
+                index: None,
+                value: Estimated::measure(&updated, context.costs()).to_value(),
+            }],
+        });
+    }
+    Ok(Diff {
+        changes: diff.changes,
+    })
+}
+
+/// The state of walking an original and an updated document side by side.
+struct Walk<'a> {
+    original: Document<'a>,
+    updated: Document<'a>,
+}
+
+/// The contents of an original and an updated container that are walked in
+/// place.
+struct Contents {
+    kind: ContainerKind,
+    /// The edits that turn the original contents into the updated contents,
+    /// in the order they are applied.
+    edits: Vec<Edit>,
+}
+
+/// A change to the contents of a container that is walked in place. Indices
+/// are positions within the container as it is being updated, after the
+/// preceding edits have been applied.
+enum Edit {
+    /// The values at `index` differ, and are diffed in turn.
+    Diff {
+        index: usize,
+        original: Span,
+        updated: Span,
+    },
+    /// The `length` original values at `index` are removed.
+    Remove { index: usize, length: usize },
+    /// The updated value, along with its key within a mapping, is inserted
+    /// at `index`.
+    Insert {
+        index: usize,
+        key: Option<Span>,
+        value: Span,
+    },
+}
+
+impl<'a> Walk<'a> {
+    /// Returns true if the values at `original` and `updated` are encoded
+    /// identically.
+    fn same(&self, original: &Span, updated: &Span) -> bool {
+        self.original.bytes[original.start..original.end]
+            == self.updated.bytes[updated.start..updated.end]
+            && (!original.has_symbols
+                || self.original.symbols[..original.symbols_before]
+                    .iter()
+                    .map(|(_, symbol)| symbol)
+                    .eq(self.updated.symbols[..updated.symbols_before]
+                        .iter()
+                        .map(|(_, symbol)| symbol)))
+    }
+
+    /// Returns the edits to the values within `original` and `updated`, if
+    /// both are containers that can be walked in place.
+    fn contents(
+        &mut self,
+        original: &Span,
+        updated: &Span,
+        context: &Context<'_>,
+    ) -> Result<Option<Contents>, DecodeError> {
+        // Policies select values by their paths, which requires decoding the
+        // keys that lead to them. The tree edit distance is found between the
+        // decoded documents.
+        if !context.can_enter()
+            || !context.options.policies.is_empty()
+            || context.options.tree_edit_distance.is_some()
+        {
+            return Ok(None);
+        }
+        // The contents of a mapping alternate between keys and values.
+        let (kind, stride) = match (original.shape, updated.shape) {
+            (Shape::Sequence(_), Shape::Sequence(_)) => (ContainerKind::Sequence, 1),
+            // Entries of unordered mappings are changed by their keys, which
+            // requires decoding them.
+            (Shape::Mappings(_), Shape::Mappings(_)) if !context.options.unordered_mappings => {
+                (ContainerKind::Mappings, 2)
+            }
+            _ => return Ok(None),
+        };
+        let originals = self.original.contents(original)?;
+        let updateds = self.updated.contents(updated)?;
+        let edits = if originals.len() == updateds.len() {
+            self.edits_in_place(&originals, &updateds, stride)
+        } else {
+            self.aligned_edits(&originals, &updateds, stride, context)
+        };
+        Ok(edits.map(|edits| Contents { kind, edits }))
+    }
+
+    /// Returns the edits that diff each pair of values at the same index, or
+    /// None if the keys of two mappings differ.
+    fn edits_in_place(
+        &self,
+        originals: &[Span],
+        updateds: &[Span],
+        stride: usize,
+    ) -> Option<Vec<Edit>> {
+        let mut edits = Vec::new();
+        for (index, (original, updated)) in originals
+            .chunks(stride)
+            .zip(updateds.chunks(stride))
+            .enumerate()
+        {
+            if stride == 2 && !self.same(&original[0], &updated[0]) {
+                // The keys differ, which requires aligning the entries of
+                // the decoded mappings instead.
+                return None;
+            } else if !self.same(&original[stride - 1], &updated[stride - 1]) {
+                edits.push(Edit::Diff {
+                    index,
+                    original: original[stride - 1],
+                    updated: updated[stride - 1],
+                });
+            }
+        }
+        Some(edits)
+    }
+
+    /// Returns the edits between containers whose lengths differ. Values are
+    /// aligned by their encodings, and entries by the encodings of their
+    /// keys, keeping as many of them in place as possible. Returns None if
+    /// the budget was spent while aligning them.
+    fn aligned_edits(
+        &self,
+        originals: &[Span],
+        updateds: &[Span],
+        stride: usize,
+        context: &Context<'_>,
+    ) -> Option<Vec<Edit>> {
+        let (original_len, updated_len) = (originals.len() / stride, updateds.len() / stride);
+        let matches = myers::longest_common_subsequence(
+            original_len,
+            updated_len,
+            context.options.max_lookahead,
+            |original, updated| {
+                context.budget.compare();
+                self.same(&originals[original * stride], &updateds[updated * stride])
+            },
+            || context.is_exhausted(),
+        );
+        if context.is_exhausted() {
+            return None;
+        }
+
+        let mut edits = Vec::new();
+        let (mut original_index, mut updated_index) = (0, 0);
+        for (matched_original, matched_updated) in matches
+            .into_iter()
+            .chain(iter::once((original_len, updated_len)))
+        {
+            // The updated values before each gap are already in place, so
+            // the gap starts at `updated_index`. Within sequences, unmatched
+            // values at the same location are diffed in place, and the rest
+            // are removed or inserted.
+            let removed = matched_original - original_index;
+            let paired = if stride == 1 {
+                removed.min(matched_updated - updated_index)
+            } else {
+                0
+            };
+            for offset in 0..paired {
+                let (original, updated) = (
+                    originals[original_index + offset],
+                    updateds[updated_index + offset],
+                );
+                if !self.same(&original, &updated) {
+                    edits.push(Edit::Diff {
+                        index: updated_index + offset,
+                        original,
+                        updated,
+                    });
+                }
+            }
+            if removed > paired {
+                edits.push(Edit::Remove {
+                    index: updated_index + paired,
+                    length: removed - paired,
+                });
+            }
+            for index in updated_index + paired..matched_updated {
+                let (key, value) = if stride == 2 {
+                    (Some(updateds[index * 2]), updateds[index * 2 + 1])
+                } else {
+                    (None, updateds[index])
+                };
+                edits.push(Edit::Insert { index, key, value });
+            }
+
+            // Entries with matching keys can still have differing values.
+            if stride == 2 && matched_original < original_len {
+                let original = originals[matched_original * 2 + 1];
+                let updated = updateds[matched_updated * 2 + 1];
+                if !self.same(&original, &updated) {
+                    edits.push(Edit::Diff {
+                        index: matched_updated,
+                        original,
+                        updated,
+                    });
+                }
+            }
+            original_index = matched_original + 1;
+            updated_index = matched_updated + 1;
+        }
+        Some(edits)
+    }
+
+    /// Diffs the values within containers that are walked in place, where
+    /// `context` is the context of the containers.
+    fn diff_contents(
+        &mut self,
+        index: Option<usize>,
+        contents: Contents,
+        context: &Context<'_>,
+        diff: &mut Changes<'_>,
+    ) -> Result<(), DecodeError> {
+        let (kind, enter) = match contents.kind {
+            ContainerKind::Sequence => (
+                ChangeKind::EnterSequence,
+                Change::EnterSequence { index, key: false },
+            ),
+            ContainerKind::Mappings => {
+                (ChangeKind::EnterMap, Change::EnterMap { index, key: false })
+            }
+        };
+        diff.enter(kind, context.optional_argument(index), enter);
+        let context = context.entered();
+        for edit in contents.edits {
+            match edit {
+                Edit::Diff {
+                    index,
+                    original,
+                    updated,
+                } => {
+                    if let Some(contents) = self.contents(&original, &updated, &context)? {
+                        self.diff_contents(Some(index), contents, &context, diff)?;
+                    } else {
+                        self.diff_decoded(index, &original, &updated, &context, diff)?;
+                    }
+                }
+                Edit::Remove { index, length } => diff.log_change(
+                    ChangeKind::Remove,
+                    context.argument(index) + context.argument(length),
+                    Change::Remove { index, length },
+                ),
+                Edit::Insert { index, key, value } => {
+                    let value = self.updated.decode(value.start)?.0;
+                    let value = Estimated::measure(&value, context.costs());
+                    if let Some(key) = key {
+                        let key = self.updated.decode(key.start)?.0;
+                        let key = Estimated::measure(&key, context.costs());
+                        diff.log_change(
+                            ChangeKind::InsertMapping,
+                            key.cost + value.cost + context.argument(index),
+                            Change::InsertMapping {
+                                index,
+                                key: key.to_value(),
+                                value: value.to_value(),
+                            },
+                        );
+                    } else {
+                        diff.log_change(
+                            ChangeKind::Insert,
+                            value.cost + context.argument(index),
+                            Change::Insert {
+                                index,
+                                value: value.to_value(),
+                            },
+                        );
+                    }
+                }
+            }
+        }
+        diff.exit();
+        Ok(())
+    }
+
+    /// Decodes and diffs the values at `index` within the containers being
+    /// walked, deciding whether to keep the diff or replace the value.
+    fn diff_decoded(
+        &self,
+        index: usize,
+        original: &Span,
+        updated: &Span,
+        context: &Context<'_>,
+        diff: &mut Changes<'_>,
+    ) -> Result<(), DecodeError> {
+        let original = self.original.decode(original.start)?.0;
+        let updated = self.updated.decode(updated.start)?.0;
+        let hashes = HashTree::new(&original);
+        let context = Context {
+            original: &original,
+            ..*context
+        };
+        let updated = Estimated::measure(&updated, context.costs());
+        let mut changes = Changes::new(&context);
+        Diff::create_diff(
+            Some(index),
+            &original,
+            &hashes,
+            &updated,
+            false,
+            &context,
+            &mut changes,
+        );
+        let replacement = updated.cost + context.argument(index);
+        if context.prefers_replacement(changes.cost, ChangeKind::Replace, replacement) {
+            diff.log_change(
+                ChangeKind::Replace,
+                replacement,
+                Change::Replace {
+                    index: Some(index),
+                    value: updated.to_value(),
+                },
+            );
+        } else {
+            diff.append(changes);
+        }
+        Ok(())
+    }
+}
+
+/// A Pot-encoded document, along with the symbols found in it so far.
+struct Document<'a> {
+    bytes: &'a [u8],
+    /// The position of the root value, after the header.
+    start: usize,
+    /// The symbols defined by the walked portion of the document, along with
+    /// the positions of their definitions.
+    symbols: Vec<(usize, &'a str)>,
+    /// The spans of the values within each skipped container that hasn't
+    /// been walked yet, by the position of the container. Recording them
+    /// while skipping means that each value is only skipped once, rather
+    /// than once for each container it is nested in.
+    contents: HashMap<usize, Vec<Span>>,
+}
+
+/// The location of an encoded value within a [`Document`].
+#[derive(Clone, Copy)]
+struct Span {
+    start: usize,
+    /// The position of the value's contents, after its atom.
+    contents: usize,
+    end: usize,
+    shape: Shape,
+    /// Whether the value defines or references any symbols.
+    has_symbols: bool,
+    /// The number of symbols defined before the value.
+    symbols_before: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Shape {
+    Sequence(usize),
+    Mappings(usize),
+    Other,
+}
+
+impl<'a> Document<'a> {
+    fn new(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = SliceReader::from(bytes);
+        pot::format::read_header(&mut reader)?;
+        Ok(Self {
+            bytes,
+            start: bytes.len() - reader.len(),
+            symbols: Vec::new(),
+            contents: HashMap::new(),
+        })
+    }
+
+    /// Reads the atom at `position`, returning it along with the position
+    /// after it.
+    fn atom(&self, position: usize) -> Result<(Atom<'a>, usize), DecodeError> {
+        let mut reader = SliceReader::from(
+            self.bytes
+                .get(position..)
+                .ok_or(DecodeError::UnexpectedEof)?,
+        );
+        // An atom can't contain more bytes than remain in the document.
+        let mut allocation_budget = reader.len();
+        let atom = pot::format::read_atom(&mut reader, &mut allocation_budget)?;
+        Ok((atom, self.bytes.len() - reader.len()))
+    }
+
+    /// Returns the `length` bytes at `position`.
+    fn bytes_at(&self, position: usize, length: u64) -> Result<&'a [u8], DecodeError> {
+        let end = usize::try_from(length)
+            .ok()
+            .and_then(|length| position.checked_add(length))
+            .ok_or(DecodeError::InvalidData)?;
+        self.bytes
+            .get(position..end)
+            .ok_or(DecodeError::UnexpectedEof)
+    }
+
+    /// Returns the symbol encoded by `atom`, along with the position after
+    /// it. New symbols are recorded the first time they are read.
+    fn symbol(
+        &mut self,
+        atom: &Atom<'a>,
+        position: usize,
+    ) -> Result<(&'a str, usize), DecodeError> {
+        if atom.arg & 1 == 0 {
+            let name = self.bytes_at(position, atom.arg >> 1)?;
+            let name = str::from_utf8(name).map_err(|_| DecodeError::InvalidData)?;
+            if self
+                .symbols
+                .last()
+                .map_or(true, |(defined_at, _)| *defined_at < position)
+            {
+                self.symbols.push((position, name));
+            }
+            Ok((name, position + name.len()))
+        } else {
+            let (_, name) = usize::try_from(atom.arg >> 1)
+                .ok()
+                .and_then(|id| self.symbols.get(id))
+                .ok_or(DecodeError::InvalidData)?;
+            Ok((name, position))
+        }
+    }
+
+    /// Locates the value at `position` without decoding it.
+    fn skip(&mut self, position: usize) -> Result<Span, DecodeError> {
+        let symbols_before = self
+            .symbols
+            .partition_point(|(defined_at, _)| *defined_at < position);
+        let (atom, contents) = self.atom(position)?;
+        let mut end = contents;
+        let mut has_symbols = false;
+        let shape = match atom.kind {
+            Kind::Sequence => {
+                let length = self.length(atom.arg)?;
+                has_symbols = self.skip_contents(position, &mut end, length)?;
+                Shape::Sequence(length)
+            }
+            Kind::Map => {
+                let length = self.length(atom.arg)?;
+                has_symbols = self.skip_contents(position, &mut end, length * 2)?;
+                Shape::Mappings(length)
+            }
+            Kind::Special if atom.arg == NAMED => {
+                has_symbols = self.skip_values(&mut end, 2)?;
+                Shape::Other
+            }
+            Kind::Special if atom.arg == DYNAMIC_MAP => {
+                while !self.is_dynamic_end(end)? {
+                    has_symbols |= self.skip_values(&mut end, 2)?;
+                }
+                end = self.atom(end)?.1;
+                Shape::Other
+            }
+            Kind::Symbol => {
+                end = self.symbol(&atom, end)?.1;
+                has_symbols = true;
+                Shape::Other
+            }
+            _ => Shape::Other,
+        };
+        Ok(Span {
+            start: position,
+            contents,
+            end,
+            shape,
+            has_symbols,
+            symbols_before,
+        })
+    }
+
+    /// Skips the `count` values at `position`, advancing it past them.
+    /// Returns true if any of the values contain symbols.
+    fn skip_values(&mut self, position: &mut usize, count: u64) -> Result<bool, DecodeError> {
+        let mut has_symbols = false;
+        for _ in 0..count {
+            let value = self.skip(*position)?;
+            *position = value.end;
+            has_symbols |= value.has_symbols;
+        }
+        Ok(has_symbols)
+    }
+
+    /// Skips the `count` values within the container at `container`,
+    /// advancing `position` past them and recording their spans. Returns
+    /// true if any of the values contain symbols.
+    fn skip_contents(
+        &mut self,
+        container: usize,
+        position: &mut usize,
+        count: usize,
+    ) -> Result<bool, DecodeError> {
+        let mut values = Vec::new();
+        let mut has_symbols = false;
+        for _ in 0..count {
+            let value = self.skip(*position)?;
+            *position = value.end;
+            has_symbols |= value.has_symbols;
+            values.push(value);
+        }
+        self.contents.insert(container, values);
+        Ok(has_symbols)
+    }
+
+    /// Returns the spans of the values within the sequence or mapping at
+    /// `span`, which were recorded when it was skipped.
+    fn contents(&mut self, span: &Span) -> Result<Vec<Span>, DecodeError> {
+        self.contents
+            .remove(&span.start)
+            .ok_or(DecodeError::InvalidData)
+    }
+
+    /// Converts the length of a container, ensuring it can't exceed the size
+    /// of the document.
+    fn length(&self, length: u64) -> Result<usize, DecodeError> {
+        usize::try_from(length)
+            .ok()
+            .filter(|length| *length <= self.bytes.len())
+            .ok_or(DecodeError::InvalidData)
+    }
+
+    fn is_dynamic_end(&self, position: usize) -> Result<bool, DecodeError> {
+        let (atom, _) = self.atom(position)?;
+        Ok(atom.kind == Kind::Special && atom.arg == DYNAMIC_END)
+    }
+
+    /// Decodes the value at `position`, which must have already been
+    /// skipped, returning it along with the position after it. Strings and
+    /// bytes are borrowed from the document.
+    fn decode(&self, position: usize) -> Result<(Value<'a>, usize), DecodeError> {
+        let (atom, mut end) = self.atom(position)?;
+        let value = match atom.kind {
+            Kind::Special if atom.arg == NAMED => {
+                let (name, name_end) = self.decode(end)?;
+                let (value, value_end) = self.decode(name_end)?;
+                end = value_end;
+                Value::Mappings(vec![(name, value)])
+            }
+            Kind::Special if atom.arg == DYNAMIC_MAP => {
+                let mut mappings = Vec::new();
+                while !self.is_dynamic_end(end)? {
+                    let (key, key_end) = self.decode(end)?;
+                    let (value, value_end) = self.decode(key_end)?;
+                    end = value_end;
+                    mappings.push((key, value));
+                }
+                end = self.atom(end)?.1;
+                Value::Mappings(mappings)
+            }
+            Kind::Special => match atom.nucleus {
+                Some(Nucleus::Unit) => Value::Unit,
+                Some(Nucleus::Boolean(bool)) => Value::Bool(bool),
+                None => Value::None,
+                _ => return Err(DecodeError::InvalidData),
+            },
+            Kind::Int | Kind::UInt => {
+                if let Some(Nucleus::Integer(integer)) = atom.nucleus {
+                    Value::Integer(integer)
+                } else {
+                    return Err(DecodeError::InvalidData);
+                }
+            }
+            Kind::Float => {
+                if let Some(Nucleus::Float(float)) = atom.nucleus {
+                    Value::Float(float)
+                } else {
+                    return Err(DecodeError::InvalidData);
+                }
+            }
+            Kind::Sequence => {
+                let mut values = Vec::with_capacity(self.length(atom.arg)?);
+                for _ in 0..atom.arg {
+                    let (value, value_end) = self.decode(end)?;
+                    end = value_end;
+                    values.push(value);
+                }
+                Value::Sequence(values)
+            }
+            Kind::Map => {
+                let mut mappings = Vec::with_capacity(self.length(atom.arg)?);
+                for _ in 0..atom.arg {
+                    let (key, key_end) = self.decode(end)?;
+                    let (value, value_end) = self.decode(key_end)?;
+                    end = value_end;
+                    mappings.push((key, value));
+                }
+                Value::Mappings(mappings)
+            }
+            Kind::Symbol => {
+                let (name, name_end) = self.symbol_at(&atom, end)?;
+                end = name_end;
+                Value::String(Cow::Borrowed(name))
+            }
+            Kind::Bytes => {
+                let bytes = &self.bytes[end - atom.arg as usize..end];
+                if let Ok(str) = str::from_utf8(bytes) {
+                    Value::String(Cow::Borrowed(str))
+                } else {
+                    Value::Bytes(Cow::Borrowed(bytes))
+                }
+            }
+        };
+        Ok((value, end))
+    }
+
+    /// Returns the symbol encoded by `atom`, which must have already been
+    /// recorded, along with the position after it.
+    fn symbol_at(&self, atom: &Atom<'a>, position: usize) -> Result<(&'a str, usize), DecodeError> {
+        let index = if atom.arg & 1 == 0 {
+            self.symbols
+                .binary_search_by_key(&position, |(defined_at, _)| *defined_at)
+                .ok()
+        } else {
+            usize::try_from(atom.arg >> 1).ok()
+        };
+        let (_, name) = index
+            .and_then(|index| self.symbols.get(index))
+            .ok_or(DecodeError::InvalidData)?;
+        let end = if atom.arg & 1 == 0 {
+            position + name.len()
+        } else {
+            position
+        };
+        Ok((name, end))
+    }
+}
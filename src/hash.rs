@@ -0,0 +1,264 @@
+//! Structural hashing of [`Value`]s.
+//!
+//! Two values that are equal will always produce the same hash, regardless of
+//! whether they are represented as a [`Value`] or an
+//! [`Estimated`](crate::Estimated). This allows looking up values using a hash
+//! index rather than scanning for them using deep equality checks. Because
+//! distinct values can share a hash, a matching hash must still be confirmed
+//! with an equality check when correctness depends on it.
+//!
+//! The hash of a sequence or mapping is computed from the hashes of the values
+//! it contains, like a Merkle tree. Each subtree is therefore only hashed once,
+//! and the hashes of every value within a tree can be computed in a single
+//! pass.
+
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::{self, Write};
+
+use pot::format::{Float, Integer};
+use pot::Value;
+
+use crate::{Estimated, EstimatedValue};
+
+const NONE: u8 = 0;
+const UNIT: u8 = 1;
+const BOOL: u8 = 2;
+const INTEGER: u8 = 3;
+const FLOAT: u8 = 4;
+const BYTES: u8 = 5;
+const STRING: u8 = 6;
+const SEQUENCE: u8 = 7;
+const MAPPINGS: u8 = 8;
+
+/// Returns the hash of a value that is not a sequence or a mapping.
+///
+/// # Panics
+///
+/// Panics if `value` is a sequence or a mapping, whose hashes are computed
+/// from their contents using [`sequence()`] and [`mappings()`].
+pub fn scalar(value: &Value<'_>) -> u64 {
+    let mut state = DefaultHasher::new();
+    match value {
+        Value::None => state.write_u8(NONE),
+        Value::Unit => state.write_u8(UNIT),
+        Value::Bool(value) => hash_bool(*value, &mut state),
+        Value::Integer(integer) => hash_integer(*integer, &mut state),
+        Value::Float(float) => hash_float(*float, &mut state),
+        Value::Bytes(bytes) => hash_bytes(BYTES, bytes, &mut state),
+        Value::String(string) => hash_bytes(STRING, string.as_bytes(), &mut state),
+        Value::Sequence(_) | Value::Mappings(_) => unreachable!("containers are not scalars"),
+    }
+    state.finish()
+}
+
+/// Returns the hash of a sequence containing values with `hashes`.
+pub fn sequence(hashes: impl ExactSizeIterator<Item = u64>) -> u64 {
+    let mut state = DefaultHasher::new();
+    state.write_u8(SEQUENCE);
+    state.write_usize(hashes.len());
+    for hash in hashes {
+        state.write_u64(hash);
+    }
+    state.finish()
+}
+
+/// Returns the hash of a mapping containing entries whose keys and values have
+/// `hashes`.
+pub fn mappings(hashes: impl ExactSizeIterator<Item = (u64, u64)>) -> u64 {
+    let mut state = DefaultHasher::new();
+    state.write_u8(MAPPINGS);
+    state.write_usize(hashes.len());
+    for (key, value) in hashes {
+        state.write_u64(key);
+        state.write_u64(value);
+    }
+    state.finish()
+}
+
+/// Returns the hash of a scalar value with `hash` that is reached through
+/// mapping keys whose combined hash is `path`. The result is also used as the
+/// `path` of the values within a mapping entry whose key has `hash`.
+pub fn leaf(path: u64, hash: u64) -> u64 {
+    let mut state = DefaultHasher::new();
+    state.write_u64(path);
+    state.write_u64(hash);
+    state.finish()
+}
+
+/// Returns the fraction of the leaves that two sorted lists of leaf hashes
+/// share, from 0 when they share none to 1 when they are the same.
+pub fn similarity(original: &[u64], updated: &[u64]) -> f64 {
+    let total = original.len() + updated.len();
+    if total == 0 {
+        return 0.;
+    }
+    let mut shared = 0;
+    let (mut original, mut updated) = (original.iter().peekable(), updated.iter().peekable());
+    while let (Some(a), Some(b)) = (original.peek(), updated.peek()) {
+        match a.cmp(b) {
+            Ordering::Less => {
+                original.next();
+            }
+            Ordering::Greater => {
+                updated.next();
+            }
+            Ordering::Equal => {
+                shared += 1;
+                original.next();
+                updated.next();
+            }
+        }
+    }
+    (2 * shared) as f64 / total as f64
+}
+
+/// The hashes of a [`Value`] and of every value it contains.
+#[derive(Debug)]
+pub struct HashTree {
+    pub hash: u64,
+    contents: Contents,
+}
+
+#[derive(Debug)]
+enum Contents {
+    Scalar,
+    Sequence(Vec<HashTree>),
+    Mappings(Vec<(HashTree, HashTree)>),
+}
+
+impl HashTree {
+    pub fn new(value: &Value<'_>) -> Self {
+        match value {
+            Value::Sequence(values) => {
+                let values = values.iter().map(Self::new).collect::<Vec<_>>();
+                Self {
+                    hash: sequence(values.iter().map(|value| value.hash)),
+                    contents: Contents::Sequence(values),
+                }
+            }
+            Value::Mappings(mappings) => {
+                let mappings = mappings
+                    .iter()
+                    .map(|(key, value)| (Self::new(key), Self::new(value)))
+                    .collect::<Vec<_>>();
+                Self {
+                    hash: self::mappings(
+                        mappings.iter().map(|(key, value)| (key.hash, value.hash)),
+                    ),
+                    contents: Contents::Mappings(mappings),
+                }
+            }
+            scalar => Self {
+                hash: self::scalar(scalar),
+                contents: Contents::Scalar,
+            },
+        }
+    }
+
+    /// Returns the hashes of the values in the sequence this tree was created
+    /// from, or an empty slice if it was not created from a sequence.
+    pub fn sequence(&self) -> &[HashTree] {
+        match &self.contents {
+            Contents::Sequence(values) => values,
+            _ => &[],
+        }
+    }
+
+    /// Returns the hashes of the entries in the mapping this tree was created
+    /// from, or an empty slice if it was not created from a mapping.
+    pub fn mappings(&self) -> &[(HashTree, HashTree)] {
+        match &self.contents {
+            Contents::Mappings(mappings) => mappings,
+            _ => &[],
+        }
+    }
+
+    /// Collects the [`leaf()`] hashes of the scalar values within this tree.
+    /// Sequence indices are not part of a leaf's path, so values are
+    /// compared regardless of where they moved within sequences.
+    pub fn leaves(&self, path: u64, leaves: &mut Vec<u64>) {
+        match &self.contents {
+            Contents::Scalar => leaves.push(leaf(path, self.hash)),
+            Contents::Sequence(values) => {
+                for value in values {
+                    value.leaves(path, leaves);
+                }
+            }
+            Contents::Mappings(mappings) => {
+                for (key, value) in mappings {
+                    value.leaves(leaf(path, key.hash), leaves);
+                }
+            }
+        }
+    }
+}
+
+impl From<&Estimated<'_>> for HashTree {
+    /// Collects the hashes that were computed while measuring a value, which
+    /// are equal to the hashes of the value it was measured from.
+    fn from(value: &Estimated<'_>) -> Self {
+        let contents = match &value.value {
+            EstimatedValue::Sequence(values) => {
+                Contents::Sequence(values.iter().map(Self::from).collect())
+            }
+            EstimatedValue::Mappings(mappings) => Contents::Mappings(
+                mappings
+                    .iter()
+                    .map(|(key, value)| (Self::from(key), Self::from(value)))
+                    .collect(),
+            ),
+            _ => Contents::Scalar,
+        };
+        Self {
+            hash: value.hash,
+            contents,
+        }
+    }
+}
+
+fn hash_bool<H: Hasher>(value: bool, state: &mut H) {
+    state.write_u8(BOOL);
+    state.write_u8(u8::from(value));
+}
+
+fn hash_integer<H: Hasher>(integer: Integer, state: &mut H) {
+    state.write_u8(INTEGER);
+    // Integers are hashed by their numeric value so that equal integers
+    // hash the same regardless of how they are stored.
+    if let Ok(value) = integer.as_i128() {
+        state.write_i128(value);
+    } else if let Ok(value) = integer.as_u128() {
+        state.write_u128(value);
+    }
+}
+
+fn hash_float<H: Hasher>(float: Float, state: &mut H) {
+    state.write_u8(FLOAT);
+    float
+        .write_to(HashWriter(state))
+        .expect("hashing is infallible");
+}
+
+fn hash_bytes<H: Hasher>(kind: u8, bytes: &[u8], state: &mut H) {
+    state.write_u8(kind);
+    state.write_usize(bytes.len());
+    state.write(bytes);
+}
+
+struct HashWriter<'a, H>(&'a mut H);
+
+impl<'a, H> Write for HashWriter<'a, H>
+where
+    H: Hasher,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,19 +1,31 @@
 use std::borrow::Cow;
-use std::collections::{vec_deque, VecDeque};
-use std::fmt::{Display, Write as _};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug, Display, Write as _};
+use std::io;
 use std::iter::{self, Cloned};
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, Range};
 use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use pot::format::{Float, Integer};
 use pot::Value;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use crate::text::ValueDisplay;
+use crate::hash::HashTree;
+use crate::path::{Path, Pattern, Segment};
+use crate::text::{BytesDisplay, PathDisplay, StringDisplay, ValueDisplay};
 
 mod binary;
+mod delta;
+mod encoded;
+mod hash;
+mod myers;
+mod path;
 mod text;
+mod tree;
 
 #[derive(Debug, PartialEq)]
 pub struct Diff {
@@ -32,428 +44,1586 @@ impl Diff {
     }
 
     pub fn between<T: Serialize>(original: &T, updated: &T) -> Self {
+        Self::between_with(original, updated, &DiffOptions::default())
+    }
+
+    pub fn between_with<T: Serialize>(original: &T, updated: &T, options: &DiffOptions) -> Self {
         let original = Value::from_serialize(original);
         let updated = Value::from_serialize(updated);
-        Self::between_values(&original, updated)
+        Self::between_borrowed_with(&original, &updated, options)
     }
 
     pub fn between_values(original: &Value<'_>, updated: Value<'static>) -> Self {
-        let mut diff = Self {
-            changes: Vec::new(),
+        Self::between_values_with(original, updated, &DiffOptions::default())
+    }
+
+    pub fn between_values_with(
+        original: &Value<'_>,
+        updated: Value<'static>,
+        options: &DiffOptions,
+    ) -> Self {
+        Self::between_borrowed_with(original, &updated, options)
+    }
+
+    /// Returns the changes needed to turn `original` into `updated`, without
+    /// taking ownership of either value. Only the parts of `updated` that are
+    /// included in the diff's changes are copied.
+    pub fn between_borrowed(original: &Value<'_>, updated: &Value<'_>) -> Self {
+        Self::between_borrowed_with(original, updated, &DiffOptions::default())
+    }
+
+    pub fn between_borrowed_with(
+        original: &Value<'_>,
+        updated: &Value<'_>,
+        options: &DiffOptions,
+    ) -> Self {
+        Self::create(original, updated, options).0
+    }
+
+    /// Returns the changes needed to turn the Pot-encoded document
+    /// `original` into the Pot-encoded document `updated`.
+    ///
+    /// Values that are encoded identically in both documents are skipped
+    /// without being decoded, and only the values that differ are decoded
+    /// and diffed. Sequences and mappings are diffed in place rather than
+    /// being considered for replacement as a whole, unless two mappings of
+    /// the same length have different keys. When their lengths differ, the
+    /// values that can't be aligned are removed and inserted rather than
+    /// moved. The values within them are never copied from elsewhere in
+    /// `original`.
+    pub fn between_encoded(original: &[u8], updated: &[u8]) -> Result<Self, binary::DecodeError> {
+        Self::between_encoded_with(original, updated, &DiffOptions::default())
+    }
+
+    pub fn between_encoded_with(
+        original: &[u8],
+        updated: &[u8],
+        options: &DiffOptions,
+    ) -> Result<Self, binary::DecodeError> {
+        encoded::diff(original, updated, options)
+    }
+
+    /// Writes the changes needed to turn `original` into `updated` to
+    /// `writer` as they are produced, rather than collecting them into a
+    /// [`Diff`] first. The written diff can be read using
+    /// [`Diff::deserialize()`].
+    ///
+    /// Because changes are written before the rest of the value has been
+    /// diffed, `updated` is only replaced as a whole when it can't be diffed,
+    /// and [`DiffOptions::max_changes()`] is not applied.
+    ///
+    /// Changes are only streamed at the root level: the changes within each
+    /// value contained by `updated` are collected before they are written,
+    /// as they may still be dropped in favor of replacing the value. Streaming
+    /// therefore doesn't bound the memory used, as a single value directly
+    /// within `updated` can account for nearly all of the diff.
+    pub fn write_between<W: io::Write>(
+        original: &Value<'_>,
+        updated: &Value<'_>,
+        options: &DiffOptions,
+        mut writer: W,
+    ) -> io::Result<()> {
+        binary::write_stream_header(&mut writer)?;
+        let hashes = HashTree::new(original);
+        Self::with_context(original, &hashes, options, |context| {
+            let updated = Estimated::measure(updated, context.costs());
+            let stream = ChangeStream {
+                writer: &mut writer,
+                result: Ok(()),
+            };
+            let mut diff = Changes::with_sink(context, stream);
+            Self::create_diff(None, original, &hashes, &updated, false, context, &mut diff);
+            if diff.requires_replacement() {
+                diff.log_change(
+                    ChangeKind::Replace,
+                    updated.cost,
+                    Change::Replace {
+                        index: None,
+                        value: updated.to_value(),
+                    },
+                );
+            }
+            diff.changes.result
+        })?;
+        binary::write_stream_end(writer)
+    }
+
+    /// Invokes `diff` with the context for diffing against `original`, whose
+    /// hashes are `hashes`.
+    fn with_context<R>(
+        original: &Value<'_>,
+        hashes: &HashTree,
+        options: &DiffOptions,
+        diff: impl FnOnce(&Context<'_>) -> R,
+    ) -> R {
+        let budget = Budget::new(options);
+        let subtrees = SubtreeIndex::new(original, hashes);
+        let context = Context {
+            original,
+            subtrees: &subtrees,
+            options,
+            budget: &budget,
+            depth: 0,
+            path: None,
         };
+        diff(&context)
+    }
+
+    /// Creates a diff, returning it along with its cost as measured by the
+    /// options' [`CostModel`].
+    fn create(original: &Value<'_>, updated: &Value<'_>, options: &DiffOptions) -> (Self, usize) {
+        let hashes = HashTree::new(original);
+        Self::with_context(original, &hashes, options, |context| {
+            let updated = Estimated::measure(updated, context.costs());
+            Self::create_with_context(original, &hashes, &updated, context)
+        })
+    }
+
+    /// Creates a diff against `original`, whose hashes are `hashes`,
+    /// returning it along with the hashes of `updated`. This lets a value's
+    /// hashes be reused when it is diffed against the value that replaces
+    /// it.
+    fn create_hashed(
+        original: &Value<'_>,
+        hashes: &HashTree,
+        updated: &Value<'_>,
+        options: &DiffOptions,
+    ) -> (Self, HashTree) {
+        Self::with_context(original, hashes, options, |context| {
+            let updated = Estimated::measure(updated, context.costs());
+            let (diff, _) = Self::create_with_context(original, hashes, &updated, context);
+            (diff, HashTree::from(&updated))
+        })
+    }
 
-        let updated = Estimated::from(updated);
+    fn create_with_context(
+        original: &Value<'_>,
+        hashes: &HashTree,
+        updated: &Estimated<'_>,
+        context: &Context<'_>,
+    ) -> (Self, usize) {
+        let options = context.options;
 
-        // We want to figure out if we should replace this value or
-        // generate a diff for the value.
-        let mut stats = Counter::default();
-        Self::create_diff(None, original, Cow::Borrowed(&updated), false, &mut stats);
-        if stats.estimated_bytes > updated.estimated_bytes {
-            // Just replace the value rather than creating a diff.
-            diff.log_change(updated.estimated_bytes, || Change::Replace {
+        // Diff the value, and then decide whether to keep the diff or replace
+        // the value.
+        let mut diff = Changes::new(context);
+        Self::create_diff(None, original, hashes, updated, false, context, &mut diff);
+        let costs = context.costs();
+        let copy = context.find_copy(updated);
+        let (changes, cost) = if let Some(copy) =
+            copy.filter(|copy| context.prefers_replacement(diff.cost, ChangeKind::Copy, copy.cost))
+        {
+            let change = Change::Copy {
                 index: None,
-                value: updated.value.into(),
-            })
+                source: copy.source.to_vec(),
+            };
+            let cost = costs.operation(ChangeKind::Copy).saturating_add(copy.cost);
+            (vec![change], cost)
+        } else if context.prefers_replacement(diff.cost, ChangeKind::Replace, updated.cost)
+            || options
+                .max_changes
+                .map_or(false, |max_changes| diff.changes.len() > max_changes)
+        {
+            // Just replace the value rather than keeping the diff.
+            let change = Change::Replace {
+                index: None,
+                value: updated.to_value(),
+            };
+            let cost = costs
+                .operation(ChangeKind::Replace)
+                .saturating_add(updated.cost);
+            (vec![change], cost)
         } else {
-            Self::create_diff(None, original, Cow::Owned(updated), false, &mut diff);
-        }
+            (diff.changes, diff.cost)
+        };
 
-        // Remove trailing exits, they're unnecessary
-        while let Some(Change::Exit) = diff.changes.last() {
-            diff.changes.pop();
+        if let Some(restructured) = options
+            .tree_edit_distance
+            .filter(|_| options.policies.is_empty() && !options.unordered_mappings)
+            .and_then(|max_nodes| tree::diff(original, hashes, updated, max_nodes, context))
+            .filter(|restructured| {
+                restructured.cost < cost
+                    && options.max_changes.map_or(true, |max_changes| {
+                        restructured.changes.len() <= max_changes
+                    })
+            })
+        {
+            return (
+                Self {
+                    changes: restructured.changes,
+                },
+                restructured.cost,
+            );
         }
 
-        diff
+        (Self { changes }, cost)
     }
 
-    fn create_diff<D>(
+    fn create_diff<S: ChangeSink>(
         diff_index: Option<usize>,
         original: &Value<'_>,
-        updated: Cow<'_, Estimated>,
+        original_hashes: &HashTree,
+        updated: &Estimated<'_>,
         is_key: bool,
-        diff: &mut D,
-    ) where
-        D: Differ,
-    {
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) {
+        if context.equal(original, original_hashes.hash, updated) {
+            // Unchanged subtrees are skipped without visiting their contents.
+            return;
+        }
+
+        // Policies apply to values rather than the keys that locate them.
+        let policy = if is_key { None } else { context.policy() };
+        match policy {
+            Some(PathPolicy::Ignore) => return,
+            Some(PathPolicy::Replace) => {
+                diff.require_replacement();
+                return;
+            }
+            _ => {}
+        }
+
         match (original, &updated.value) {
             (Value::None, EstimatedValue::None) | (Value::Unit, EstimatedValue::Unit) => {}
             (Value::Bool(original), EstimatedValue::Bool(updated)) if original == updated => {}
             (Value::Integer(original), EstimatedValue::Integer(updated)) if original == updated => {
             }
             (Value::Float(original), EstimatedValue::Float(updated)) if original == updated => {}
+            (
+                Value::Integer(_) | Value::Float(_),
+                EstimatedValue::Integer(_) | EstimatedValue::Float(_),
+            ) if !is_key => {
+                // Adding is only worthwhile when it is cheaper than replacing
+                // the value, as replacing is simpler to apply.
+                let costs = context.costs();
+                let delta = numeric_delta(original, &updated.value)
+                    .map(|delta| {
+                        let cost = costs.scalar(&delta);
+                        (delta, cost)
+                    })
+                    .filter(|(_, cost)| {
+                        costs.operation(ChangeKind::Add).saturating_add(*cost)
+                            < costs
+                                .operation(ChangeKind::Replace)
+                                .saturating_add(updated.cost)
+                    });
+                if let Some((delta, cost)) = delta {
+                    diff.log_change(
+                        ChangeKind::Add,
+                        context.optional_argument(diff_index) + cost,
+                        Change::Add {
+                            index: diff_index,
+                            delta,
+                        },
+                    );
+                } else {
+                    diff.require_replacement();
+                }
+            }
             (Value::Bytes(original), EstimatedValue::Bytes(updated)) if original == updated => {}
+            (Value::Bytes(original), EstimatedValue::Bytes(updated))
+                if !is_key && updated.len() >= DELTA_MINIMUM_BYTES =>
+            {
+                if let Some(instructions) = delta::instructions(original, updated, context.budget) {
+                    diff.log_change(
+                        ChangeKind::Delta,
+                        context.optional_argument(diff_index)
+                            + context.argument(instructions.len())
+                            + delta::cost(&instructions, context.costs()),
+                        Change::Delta {
+                            index: diff_index,
+                            instructions,
+                        },
+                    );
+                } else {
+                    diff.require_replacement();
+                }
+            }
             (Value::String(original), EstimatedValue::String(updated)) if original == updated => {}
+            (Value::String(original), EstimatedValue::String(updated)) if !is_key => {
+                Self::create_string_diff(diff_index, original, updated, context, diff);
+            }
+            (Value::Sequence(_), EstimatedValue::Sequence(_))
+            | (Value::Mappings(_), EstimatedValue::Mappings(_))
+                if !context.can_enter() && *updated != *original =>
+            {
+                diff.require_replacement();
+            }
             (Value::Sequence(original), EstimatedValue::Sequence(updated_sequence)) => {
-                if updated_sequence != original {
-                    diff.log_change(estimate_usize_bytes(diff_index.unwrap_or(0)), || Change::EnterSequence{ index: diff_index, key: is_key });
-                    Self::create_sequence_diff(
+                diff.enter(
+                    ChangeKind::EnterSequence,
+                    context.optional_argument(diff_index),
+                    Change::EnterSequence {
+                        index: diff_index,
+                        key: is_key,
+                    },
+                );
+                match policy {
+                    Some(PathPolicy::Set) => Self::create_set_diff(
                         original,
-                        match updated {
-                            Cow::Owned(Estimated {
-                                value: EstimatedValue::Sequence(deque),
-                                ..
-                            }) => CowDeque::Owned(deque),
-                            Cow::Borrowed(_) => CowDeque::Borrowed {
-                                deque: updated_sequence,
-                                index: 0,
-                            },
-                            Cow::Owned(_) => unreachable!(),
-                        },
+                        original_hashes.sequence(),
+                        updated_sequence,
+                        &context.entered(),
                         diff,
-                    );
-                    diff.log_change(0, || Change::Exit);
+                    ),
+                    _ => {
+                        let key = match policy {
+                            Some(PathPolicy::Keyed(field)) => Some(SequenceKey::Field(field)),
+                            Some(PathPolicy::KeyedBy(extractor)) => {
+                                Some(SequenceKey::Extractor(extractor))
+                            }
+                            _ => context
+                                .options
+                                .sequence_key
+                                .as_ref()
+                                .map(SequenceKey::Extractor),
+                        };
+                        Self::create_sequence_diff(
+                            original,
+                            original_hashes.sequence(),
+                            updated_sequence,
+                            key,
+                            &context.entered(),
+                            diff,
+                        );
+                    }
                 }
+                diff.exit();
             }
             (Value::Mappings(original), EstimatedValue::Mappings(updated_mappings)) => {
-                if original.len() != updated_mappings.len() || updated_mappings
-                    .iter()
-                    .zip(original.iter())
-                    .any(|(a, b)| a.0 != b.0 || a.1 != b.1)
-                {
-                    diff.log_change(diff_index.unwrap_or(0), || Change::EnterMap{ index: diff_index, key: is_key });
-                    Self::create_map_diff(original, match updated {
-                        Cow::Owned(Estimated {
-                            value: EstimatedValue::Mappings(deque),
-                            ..
-                        }) => CowDeque::Owned(deque),
-                        Cow::Borrowed(_) => CowDeque::Borrowed {
-                            deque: updated_mappings,
-                            index: 0,
-                        },
-                        Cow::Owned(_) => unreachable!(),
-                    }, diff);
-                    diff.log_change(0, || Change::Exit);
+                diff.enter(
+                    ChangeKind::EnterMap,
+                    context.optional_argument(diff_index),
+                    Change::EnterMap {
+                        index: diff_index,
+                        key: is_key,
+                    },
+                );
+                if context.options.unordered_mappings {
+                    Self::create_unordered_map_diff(
+                        original,
+                        original_hashes.mappings(),
+                        updated_mappings,
+                        &context.entered(),
+                        diff,
+                    );
+                } else {
+                    Self::create_map_diff(
+                        original,
+                        original_hashes.mappings(),
+                        updated_mappings,
+                        &context.entered(),
+                        diff,
+                    );
                 }
+                diff.exit();
             }
-            _ => diff.log_change(updated.estimated_bytes, || {
-                unreachable!("replace should happen after measurement due to log_change always adding 1 to estimated_bytes")
-            }),
+            _ => diff.require_replacement(),
         }
     }
 
-    fn create_sequence_diff<D>(
+    /// Diffs two sequences. Values are matched by their keys when `key` is
+    /// present, and by their entire value otherwise.
+    fn create_sequence_diff<S: ChangeSink>(
         original_values: &[Value<'_>],
-        mut updated_values: CowDeque<'_, Estimated>,
-        diff: &mut D,
-    ) where
-        D: Differ,
-    {
+        original_hashes: &[HashTree],
+        updated_values: &[Estimated<'_>],
+        key: Option<SequenceKey<'_>>,
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) {
+        if context.is_exhausted() {
+            diff.require_replacement();
+            return;
+        }
+
+        // Most updates only change part of a sequence, such as appending
+        // values to its end. The unchanged values at either end are skipped
+        // by comparing their hashes, leaving only the values between them to
+        // be aligned.
+        let (prefix, suffix) = myers::common_affixes(
+            original_values.len(),
+            updated_values.len(),
+            |original, updated| {
+                context.equal(
+                    &original_values[original],
+                    original_hashes[original].hash,
+                    &updated_values[updated],
+                )
+            },
+        );
+        let original_end = original_values.len() - suffix;
+        let updated_end = updated_values.len() - suffix;
+        let identities = Identities::new(
+            key,
+            &original_values[prefix..original_end],
+            &original_hashes[prefix..original_end],
+            &updated_values[prefix..updated_end],
+        );
+        Self::create_window_diff(prefix, suffix == 0, &identities, context, diff);
+    }
+
+    /// Diffs the values of two sequences that are between their unchanged
+    /// ends. `offset` is the index of the first value of the window, and
+    /// `is_tail` is true if the window extends to the end of the sequences.
+    fn create_window_diff<S: ChangeSink>(
+        offset: usize,
+        is_tail: bool,
+        identities: &Identities<'_, '_, '_>,
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) {
+        let (original_values, original_hashes, updated_values) = (
+            identities.original,
+            identities.original_hashes,
+            identities.updated,
+        );
+        // The alignment keeps the most values in place, which takes the
+        // fewest changes, though not necessarily the cheapest ones.
+        let mut matches = myers::longest_common_subsequence(
+            original_values.len(),
+            updated_values.len(),
+            context.options.max_lookahead,
+            |original, updated| identities.matches(original, updated, context),
+            || context.is_exhausted(),
+        );
+
+        // Relocate any unmatched values that can be found elsewhere in the
+        // original list. Afterwards, `order` contains the original index of
+        // each value in the sequence being updated.
+        let moves = Self::find_moves(
+            original_values.len(),
+            identities,
+            updated_values,
+            &matches,
+            context,
+        );
+        if context.is_exhausted() {
+            // The values weren't fully aligned, so replace the sequence
+            // rather than keeping a diff that may be far larger than needed.
+            diff.require_replacement();
+            return;
+        }
+
+        let order = if moves.is_empty() {
+            None
+        } else {
+            let (order, moved_matches) = Self::create_moves(
+                offset,
+                original_values.len(),
+                updated_values.len(),
+                &matches,
+                &moves,
+                context,
+                diff,
+            );
+            matches = moved_matches;
+            Some(order)
+        };
+        let original_at = |position: usize| {
+            let index = order.as_ref().map_or(position, |order| order[position]);
+            (&original_values[index], &original_hashes[index])
+        };
+        let matches = Self::align_similar(
+            &matches,
+            !identities.are_values(),
+            original_values.len(),
+            |position| original_at(position).1,
+            updated_values,
+            context,
+        );
+
+        // Values that were removed and inserted at the same location are
+        // replaced or updated in place, as are matching values that may
+        // differ. Diff each of these pairs up front, as each diff is
+        // independent of the others.
+        let mut pairs = Vec::new();
+        let mut original_index = 0;
+        let mut updated_index = 0;
+        for &(matched_original, matched_updated, differs) in matches.iter().chain(iter::once(&(
+            original_values.len(),
+            updated_values.len(),
+            false,
+        ))) {
+            let paired = (matched_original - original_index).min(matched_updated - updated_index);
+            pairs.extend(
+                (0..paired).map(|offset| (original_index + offset, updated_index + offset)),
+            );
+            if differs {
+                pairs.push((matched_original, matched_updated));
+            }
+            original_index = matched_original + 1;
+            updated_index = matched_updated + 1;
+        }
+        let mut pair_diffs = diff_each(
+            &pairs,
+            context,
+            |&(_, updated)| updated_values[updated].cost,
+            |&(original, updated)| {
+                let (original, original_hashes) = original_at(original);
+                let path = Path::new(Segment::Index(offset + updated), context.path);
+                let mut changes = Changes::new(context);
+                Self::create_diff(
+                    Some(offset + updated),
+                    original,
+                    original_hashes,
+                    &updated_values[updated],
+                    false,
+                    &context.at(&path),
+                    &mut changes,
+                );
+                changes
+            },
+        );
+
         let mut original_index = 0;
         let mut insert_index = 0;
+        let mut matches = matches.into_iter();
+        let mut replacements = ReplacementRun::default();
+        loop {
+            // Each iteration handles the unmatched values between the previous
+            // match and the next match, or the end of both lists.
+            let next_match = matches.next();
+            let is_end = next_match.is_none();
+            let (matched_original, matched_updated, differs) =
+                next_match.unwrap_or((original_values.len(), updated_values.len(), false));
+            let removed = matched_original - original_index;
+            let inserted = matched_updated - insert_index;
 
-        while let Some(updated) = updated_values.pop_front() {
-            if let Some(original) = original_values.get(original_index) {
-                if let Some(matching_index) = original_values[original_index..]
-                    .iter()
-                    .enumerate()
-                    .find_map(|(index, o)| (updated.as_ref() == o).then_some(index))
-                {
-                    // We found where the the updated value is located in the
-                    // original list.
-                    if matching_index > 0 {
-                        diff.log_change(
-                            estimate_usize_bytes(insert_index)
-                                + estimate_usize_bytes(matching_index),
-                            || Change::Remove {
-                                index: insert_index,
-                                length: matching_index,
-                            },
-                        );
-                        original_index += matching_index;
-                    }
+            let paired = removed.min(inserted);
+            for _ in 0..paired {
+                let changes = pair_diffs.next().expect("every pair was diffed");
+                Self::keep_or_replace(
+                    offset + insert_index,
+                    &updated_values[insert_index],
+                    changes,
+                    &mut replacements,
+                    context,
+                    diff,
+                );
+                original_index += 1;
+                insert_index += 1;
+            }
+            replacements.finish(context, diff);
 
-                    // Skip the match
-                    original_index += 1;
-                    insert_index += 1;
-                } else if let Some(matching_index) = updated_values
-                    .iter()
-                    .enumerate()
-                    .find_map(|(index, updated)| (updated == original).then_some(index))
-                {
-                    // We found where the the original value is located in the
-                    // updated list.
-                    let mut updated = updated;
-                    for _ in 0..matching_index + 1 {
-                        diff.log_change(
-                            updated.estimated_bytes + estimate_usize_bytes(insert_index),
-                            || Change::Insert {
-                                index: insert_index,
-                                value: updated.into_owned().into(),
-                            },
-                        );
-                        insert_index += 1;
-                        updated = updated_values.pop_front().expect("just iterated");
-                    }
+            if removed > paired {
+                let length = removed - paired;
+                let index = offset + insert_index;
+                if is_end && is_tail {
+                    // Extra values, need to truncate.
+                    diff.log_change(ChangeKind::Truncate, context.argument(index), {
+                        Change::Truncate { length: index }
+                    });
+                } else {
+                    diff.log_change(
+                        ChangeKind::Remove,
+                        context.argument(index) + context.argument(length),
+                        Change::Remove { index, length },
+                    );
+                }
+                original_index += length;
+            }
 
-                    // Skip the match
-                    original_index += 1;
-                    insert_index += 1;
+            for _ in paired..inserted {
+                let updated = &updated_values[insert_index];
+                let index = offset + insert_index;
+                if let Some(copy) = context.find_copy(updated) {
+                    diff.log_change(
+                        ChangeKind::InsertCopy,
+                        copy.cost + context.argument(index),
+                        Change::InsertCopy {
+                            index,
+                            source: copy.source.to_vec(),
+                        },
+                    );
                 } else {
-                    // We want to figure out if we should replace this value or
-                    // generate a diff for the value.
-                    let mut stats = Counter::default();
-                    Self::create_diff(
-                        Some(insert_index),
-                        original,
-                        Cow::Borrowed(&updated),
-                        false,
-                        &mut stats,
+                    diff.log_change(
+                        ChangeKind::Insert,
+                        updated.cost + context.argument(index),
+                        Change::Insert {
+                            index,
+                            value: updated.to_value(),
+                        },
                     );
-                    if stats.estimated_bytes > updated.estimated_bytes {
-                        // Just replace the value rather than creating a diff.
-                        diff.log_change(
-                            updated.estimated_bytes + estimate_usize_bytes(insert_index),
-                            || Change::Replace {
-                                index: Some(insert_index),
-                                value: updated.into_owned().into(),
-                            },
-                        )
-                    } else {
-                        Self::create_diff(Some(insert_index), original, updated, false, diff);
-                    }
-                    original_index += 1;
-                    insert_index += 1;
                 }
-            } else {
-                // Pushing a new value
-                diff.log_change(
-                    updated.estimated_bytes + estimate_usize_bytes(insert_index),
-                    || Change::Insert {
-                        index: insert_index,
-                        value: updated.into_owned().into(),
-                    },
-                );
                 insert_index += 1;
             }
-        }
 
-        if original_index < original_values.len() {
-            // Extra values, need to truncate.
-            diff.log_change(estimate_usize_bytes(insert_index), || Change::Truncate {
-                length: insert_index,
-            });
+            if is_end {
+                break;
+            }
+
+            if differs {
+                // Values with the same identity can still differ.
+                let changes = pair_diffs.next().expect("every pair was diffed");
+                Self::keep_or_replace(
+                    offset + insert_index,
+                    &updated_values[insert_index],
+                    changes,
+                    &mut replacements,
+                    context,
+                    diff,
+                );
+            }
+            original_index += 1;
+            insert_index += 1;
         }
+        replacements.finish(context, diff);
     }
 
-    fn create_map_diff<D>(
-        original_values: &[(Value<'_>, Value<'_>)],
-        mut updated_values: CowDeque<'_, (Estimated, Estimated)>,
-        diff: &mut D,
-    ) where
-        D: Differ,
-    {
+    /// Pairs the unmatched values between `matches` that are at least as
+    /// similar as [`DiffOptions::similarity_threshold()`], so that they are
+    /// diffed rather than removed and inserted. Returns the matches along
+    /// with the new pairs, each with a flag that is true if its values may
+    /// differ. `matches_differ` is the flag of the existing matches.
+    fn align_similar<'h>(
+        matches: &[(usize, usize)],
+        matches_differ: bool,
+        original_len: usize,
+        original_hashes: impl Fn(usize) -> &'h HashTree,
+        updated_values: &[Estimated<'_>],
+        context: &Context<'_>,
+    ) -> Vec<(usize, usize, bool)> {
+        let threshold = context.options.similarity_threshold;
+        let mut aligned = Vec::with_capacity(matches.len());
         let mut original_index = 0;
-        let mut insert_index = 0;
+        let mut updated_index = 0;
+        for &(matched_original, matched_updated) in matches
+            .iter()
+            .chain(iter::once(&(original_len, updated_values.len())))
+        {
+            if matched_original > original_index
+                && matched_updated > updated_index
+                && threshold <= 1.
+                && !context.is_exhausted()
+            {
+                let original_leaves = (original_index..matched_original)
+                    .map(|position| {
+                        let mut leaves = Vec::new();
+                        original_hashes(position).leaves(0, &mut leaves);
+                        leaves.sort_unstable();
+                        leaves
+                    })
+                    .collect::<Vec<_>>();
+                let updated_leaves = (updated_index..matched_updated)
+                    .map(|index| {
+                        let mut leaves = Vec::new();
+                        updated_values[index].leaves(0, &mut leaves);
+                        leaves.sort_unstable();
+                        leaves
+                    })
+                    .collect::<Vec<_>>();
+                let (original_start, updated_start) = (original_index, updated_index);
+                let similar = |original: usize, updated: usize| {
+                    context.budget.compare();
+                    hash::similarity(
+                        &original_leaves[original - original_start],
+                        &updated_leaves[updated - updated_start],
+                    ) >= threshold
+                };
 
-        while let Some(updated) = updated_values.pop_front() {
-            if let Some(original) = original_values.get(original_index) {
-                if let Some(matching_index) = original_values[original_index..]
-                    .iter()
-                    .enumerate()
-                    .find_map(|(index, o)| (updated.0 == o.0).then_some(index))
-                {
-                    // We found where the the updated value is located in the
-                    // original list.
-                    if matching_index > 0 {
-                        diff.log_change(
-                            estimate_usize_bytes(insert_index) * matching_index,
-                            || Change::Remove {
-                                index: insert_index,
-                                length: matching_index,
-                            },
-                        );
-                        original_index += matching_index;
+                // Greedily pair each value with the closest similar value,
+                // leaving the values skipped over to be paired by position.
+                while original_index < matched_original && updated_index < matched_updated {
+                    if similar(original_index, updated_index) {
+                        aligned.push((original_index, updated_index, true));
+                        original_index += 1;
+                        updated_index += 1;
+                        continue;
                     }
-
-                    if updated.1 != original_values[original_index].1 {
-                        // The value for this key has changed.
-                        let mut stats = Counter::default();
-                        Self::create_diff(
-                            Some(insert_index),
-                            &original_values[original_index].1,
-                            Cow::Borrowed(&updated.1),
-                            false,
-                            &mut stats,
-                        );
-                        if stats.estimated_bytes > updated.1.estimated_bytes {
-                            diff.log_change(
-                                updated.1.estimated_bytes + estimate_usize_bytes(insert_index),
-                                || Change::Replace {
-                                    index: Some(insert_index),
-                                    value: updated.into_owned().1.into(),
-                                },
-                            );
-                        } else {
-                            Self::create_diff(
-                                Some(insert_index),
-                                &original_values[original_index].1,
-                                Cow::Borrowed(&updated.1),
-                                false,
-                                diff,
-                            );
+                    let later_updated = (updated_index + 1
+                        ..matched_updated.min(updated_index + 1 + SIMILARITY_LOOKAHEAD))
+                        .find(|&updated| similar(original_index, updated));
+                    let later_original = (original_index + 1
+                        ..matched_original.min(original_index + 1 + SIMILARITY_LOOKAHEAD))
+                        .find(|&original| similar(original, updated_index));
+                    match (later_original, later_updated) {
+                        (Some(original), Some(updated))
+                            if original - original_index <= updated - updated_index =>
+                        {
+                            aligned.push((original, updated_index, true));
+                            original_index = original + 1;
+                            updated_index += 1;
+                        }
+                        (_, Some(updated)) => {
+                            aligned.push((original_index, updated, true));
+                            original_index += 1;
+                            updated_index = updated + 1;
+                        }
+                        (Some(original), None) => {
+                            aligned.push((original, updated_index, true));
+                            original_index = original + 1;
+                            updated_index += 1;
+                        }
+                        (None, None) => {
+                            original_index += 1;
+                            updated_index += 1;
                         }
                     }
+                }
+            }
+            if matched_updated < updated_values.len() {
+                aligned.push((matched_original, matched_updated, matches_differ));
+            }
+            original_index = matched_original + 1;
+            updated_index = matched_updated + 1;
+        }
+        aligned
+    }
 
-                    // Skip the match
-                    original_index += 1;
-                    insert_index += 1;
-                } else if let Some(matching_index) = updated_values
-                    .iter()
-                    .enumerate()
-                    .find_map(|(index, updated)| (updated.0 == original.0).then_some(index))
-                {
-                    // We found where the the original value is located in the
-                    // updated list.
-                    let mut updated_entry = updated;
-                    for _ in 0..matching_index + 1 {
-                        diff.log_change(
-                            updated_entry.0.estimated_bytes
-                                + updated_entry.1.estimated_bytes
-                                + estimate_usize_bytes(insert_index),
-                            || {
-                                let owned = updated_entry.into_owned();
-                                Change::InsertMapping {
-                                    index: insert_index,
-                                    key: owned.0.into(),
-                                    value: owned.1.into(),
-                                }
-                            },
-                        );
-                        insert_index += 1;
-                        updated_entry = updated_values.pop_front().expect("just iterated");
-                    }
+    /// Decides whether to keep the `changes` to the updated value at `index`
+    /// in a sequence, or to copy or replace the value instead.
+    fn keep_or_replace<'r, S: ChangeSink>(
+        index: usize,
+        updated: &'r Estimated<'r>,
+        changes: Changes<'_>,
+        replacements: &mut ReplacementRun<&'r Estimated<'r>>,
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) {
+        let copy = context.find_copy(updated);
+        if let Some(copy) = copy.filter(|copy| {
+            context.prefers_replacement(
+                changes.cost,
+                ChangeKind::Copy,
+                copy.cost + context.argument(index),
+            )
+        }) {
+            // An identical value exists elsewhere in the original.
+            replacements.finish(context, diff);
+            diff.log_change(
+                ChangeKind::Copy,
+                copy.cost + context.argument(index),
+                Change::Copy {
+                    index: Some(index),
+                    source: copy.source.to_vec(),
+                },
+            );
+        } else if context.prefers_replacement(
+            changes.cost,
+            ChangeKind::Replace,
+            updated.cost + context.argument(index),
+        ) {
+            // Just replace the value rather than keeping the diff.
+            replacements.push(index, updated, updated.cost, context, diff);
+        } else {
+            replacements.finish(context, diff);
+            diff.append(changes);
+        }
+    }
 
-                    // Skip the match
-                    original_index += 1;
-                    insert_index += 1;
-                } else {
-                    if updated.1 == original.1 {
-                        // This contains only a change to the key.
-                        let mut stats = Counter::default();
-                        // TODO we need to include Enter/Exit
-                        Self::create_diff(
-                            Some(insert_index),
-                            &original.0,
-                            Cow::Borrowed(&updated.0),
-                            true,
-                            &mut stats,
-                        );
-                        if stats.estimated_bytes > updated.0.estimated_bytes {
-                            diff.log_change(
-                                updated.0.estimated_bytes + estimate_usize_bytes(insert_index),
-                                || {
-                                    let owned = updated.into_owned();
-                                    Change::ReplaceKey {
-                                        index: insert_index,
-                                        key: owned.0.into(),
-                                    }
-                                },
-                            );
-                        } else {
-                            Self::create_diff(
-                                Some(insert_index),
-                                &original.0,
-                                Cow::Borrowed(&updated.0),
-                                true,
-                                diff,
-                            );
-                        }
-                    } else {
-                        // Replace the entire entry
-                        diff.log_change(
-                            updated.0.estimated_bytes
-                                + updated.1.estimated_bytes
-                                + estimate_usize_bytes(insert_index),
-                            || {
-                                let owned = updated.into_owned();
-                                Change::ReplaceMapping {
-                                    index: insert_index,
-                                    key: owned.0.into(),
-                                    value: owned.1.into(),
-                                }
-                            },
-                        );
-                    }
+    /// Diffs two sequences whose order is insignificant. Each distinct value
+    /// is a member, and the diff only contains the members that were removed
+    /// and added, which are located by their values rather than their
+    /// indices.
+    fn create_set_diff<S: ChangeSink>(
+        original_values: &[Value<'_>],
+        original_hashes: &[HashTree],
+        updated_values: &[Estimated<'_>],
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) {
+        if context.is_exhausted() {
+            diff.require_replacement();
+            return;
+        }
 
-                    original_index += 1;
-                    insert_index += 1;
-                }
-            } else {
-                // Pushing a new value
+        let mut original_members = KeyIndex::default();
+        for (index, hashes) in original_hashes.iter().enumerate() {
+            original_members.insert(hashes.hash, index);
+        }
+        let mut updated_members = KeyIndex::default();
+        for (index, updated) in updated_values.iter().enumerate() {
+            updated_members.insert(updated.hash, index);
+        }
+
+        // Duplicate values are a single member, which is only changed once.
+        let costs = context.costs();
+        for (index, (original, hashes)) in original_values.iter().zip(original_hashes).enumerate() {
+            let is_duplicate = original_members
+                .find(hashes.hash, 0..index, |earlier| {
+                    original_values[earlier] == *original
+                })
+                .is_some();
+            let is_kept = updated_members
+                .find(hashes.hash, 0..usize::MAX, |updated| {
+                    context.equal(original, hashes.hash, &updated_values[updated])
+                })
+                .is_some();
+            if !is_duplicate && !is_kept {
+                let original = Estimated::measure(original, costs);
                 diff.log_change(
-                    updated.0.estimated_bytes
-                        + updated.1.estimated_bytes
-                        + estimate_usize_bytes(insert_index),
-                    || {
-                        let owned = updated.into_owned();
-                        Change::InsertMapping {
-                            index: insert_index,
-                            key: owned.0.into(),
-                            value: owned.1.into(),
-                        }
+                    ChangeKind::RemoveMember,
+                    original.cost,
+                    Change::RemoveMember {
+                        value: original.to_value(),
                     },
                 );
-                insert_index += 1;
             }
         }
-
-        if original_index < original_values.len() {
-            // Extra values, need to truncate.
-            diff.log_change(estimate_usize_bytes(insert_index), || Change::Truncate {
-                length: insert_index,
-            });
+        for (index, updated) in updated_values.iter().enumerate() {
+            let is_duplicate = updated_members
+                .find(updated.hash, 0..index, |earlier| {
+                    updated_values[earlier] == *updated.source
+                })
+                .is_some();
+            let is_kept = original_members
+                .find(updated.hash, 0..usize::MAX, |original| {
+                    context.equal(&original_values[original], updated.hash, updated)
+                })
+                .is_some();
+            if !is_duplicate && !is_kept {
+                diff.log_change(
+                    ChangeKind::InsertMember,
+                    updated.cost,
+                    Change::InsertMember {
+                        value: updated.to_value(),
+                    },
+                );
+            }
         }
     }
 
-    pub fn apply<T: Serialize + DeserializeOwned>(&self, against: &T) -> Result<T, Error> {
-        let updated_value = self.apply_to_value(Value::from_serialize(against))?;
-        updated_value.deserialize_as().map_err(Error::from)
-    }
+    fn create_string_diff<S: ChangeSink>(
+        diff_index: Option<usize>,
+        original: &str,
+        updated: &str,
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) {
+        if context.is_exhausted() {
+            diff.require_replacement();
+            return;
+        }
 
-    pub fn apply_to_value(&self, mut value: Value<'static>) -> Result<Value<'static>, Error> {
-        let mut changes = self.changes.iter().cloned();
-        let apply_result = match changes.next() {
-            Some(Change::Replace { index: None, value }) => ApplyResult::Replace(value),
-            Some(Change::EnterSequence {
-                index: None,
-                key: false,
-            }) => {
-                if let Value::Sequence(sequence) = &mut value {
-                    apply_changes_to_sequence(sequence, &mut changes)?
-                } else {
-                    todo!("error")
+        // Only the characters between the unchanged bytes at either end are
+        // aligned.
+        let (prefix, suffix) = common_text_affixes(original, updated);
+        let original = &original[prefix..original.len() - suffix];
+        let updated = &updated[prefix..updated.len() - suffix];
+        let original_chars = original.char_indices().collect::<Vec<_>>();
+        let updated_chars = updated.char_indices().collect::<Vec<_>>();
+        let matches = myers::longest_common_subsequence(
+            original_chars.len(),
+            updated_chars.len(),
+            context.options.max_lookahead,
+            |original, updated| {
+                context.budget.compare();
+                original_chars[original].1 == updated_chars[updated].1
+            },
+            || context.is_exhausted(),
+        );
+        if context.is_exhausted() {
+            diff.require_replacement();
+            return;
+        }
+        let original_offset = |index: usize| {
+            original_chars
+                .get(index)
+                .map_or(original.len(), |(offset, _)| *offset)
+        };
+        let updated_offset = |index: usize| {
+            updated_chars
+                .get(index)
+                .map_or(updated.len(), |(offset, _)| *offset)
+        };
+
+        // Gather the byte ranges that differ between the two strings. Ranges
+        // separated by only a few unchanged bytes are combined, as a separate
+        // splice would cost more than re-sending the unchanged bytes.
+        let mut hunks = Vec::<(Range<usize>, Range<usize>)>::new();
+        let mut original_index = 0;
+        let mut updated_index = 0;
+        for (matched_original, matched_updated) in matches
+            .into_iter()
+            .chain(iter::once((original_chars.len(), updated_chars.len())))
+        {
+            if matched_original > original_index || matched_updated > updated_index {
+                let original_range =
+                    original_offset(original_index)..original_offset(matched_original);
+                let updated_range = updated_offset(updated_index)..updated_offset(matched_updated);
+                match hunks.last_mut() {
+                    Some((previous_original, previous_updated))
+                        if original_range.start - previous_original.end < SPLICE_MERGE_DISTANCE =>
+                    {
+                        previous_original.end = original_range.end;
+                        previous_updated.end = updated_range.end;
+                    }
+                    _ => hunks.push((original_range, updated_range)),
                 }
             }
-            Some(Change::EnterMap {
-                index: None,
-                key: false,
-            }) => {
-                if let Value::Mappings(mappings) = &mut value {
-                    apply_changes_to_mappings(mappings, &mut changes)?
-                } else {
-                    todo!("error")
+            original_index = matched_original + 1;
+            updated_index = matched_updated + 1;
+        }
+
+        for (original_range, updated_range) in hunks {
+            let insert = &updated[updated_range.clone()];
+            let offset = prefix + updated_range.start;
+            diff.log_change(
+                ChangeKind::Splice,
+                context.optional_argument(diff_index)
+                    + context.argument(offset)
+                    + context.argument(original_range.len())
+                    + context.argument(insert.len())
+                    + context.costs().raw_bytes(insert.as_bytes()),
+                Change::Splice {
+                    index: diff_index,
+                    offset,
+                    delete: original_range.len(),
+                    insert: insert.to_string(),
+                },
+            );
+        }
+    }
+
+    /// Finds runs of values that are not part of the longest common
+    /// subsequence but are equal to an unmatched original value. Only runs
+    /// that are cheaper to move than to insert are returned.
+    fn find_moves(
+        original_len: usize,
+        identities: &Identities<'_, '_, '_>,
+        updated_values: &[Estimated<'_>],
+        matches: &[(usize, usize)],
+        context: &Context<'_>,
+    ) -> Vec<MoveRun> {
+        let mut original_matched = vec![false; original_len];
+        let mut updated_matched = vec![false; updated_values.len()];
+        for &(original, updated) in matches {
+            original_matched[original] = true;
+            updated_matched[updated] = true;
+        }
+
+        let mut unmatched_originals = KeyIndex::default();
+        for (index, matched) in original_matched.iter().enumerate() {
+            if !matched {
+                unmatched_originals.insert(identities.original_hash(index), index);
+            }
+        }
+        if unmatched_originals.is_empty() {
+            return Vec::new();
+        }
+
+        let mut runs = Vec::<MoveRun>::new();
+        for (index, updated) in updated_values.iter().enumerate() {
+            if updated_matched[index] {
+                continue;
+            }
+
+            let hash = identities.updated_hash(index);
+            // Extend the previous run if this value is also next to it in the
+            // original list.
+            if let Some(run) = runs
+                .last_mut()
+                .filter(|run| run.updated + run.length == index)
+            {
+                let next_original = run.original + run.length;
+                if unmatched_originals
+                    .take(hash, |original| {
+                        original == next_original && identities.matches(original, index, context)
+                    })
+                    .is_some()
+                {
+                    run.length += 1;
+                    run.cost = run.cost.saturating_add(updated.cost);
+                    continue;
                 }
             }
-            None => ApplyResult::Ok,
-            _ => todo!("error"),
-        };
 
-        match apply_result {
-            ApplyResult::Ok => Ok(value),
-            ApplyResult::Replace(new_value) => Ok(new_value),
+            if let Some(original) = unmatched_originals.take(hash, |original| {
+                identities.matches(original, index, context)
+            }) {
+                runs.push(MoveRun {
+                    original,
+                    updated: index,
+                    length: 1,
+                    cost: updated.cost,
+                });
+            }
         }
-    }
 
-    // fn serialize_into<W: Write>(&self, writer: W) -> io::Result<()> {
+        let costs = context.costs();
+        runs.retain(|run| {
+            let move_cost = costs
+                .operation(ChangeKind::Move)
+                .saturating_add(context.argument(run.original))
+                .saturating_add(context.argument(run.updated))
+                .saturating_add(context.argument(run.length));
+            // Without the move, the original values are removed and the
+            // updated values are inserted.
+            let remove_cost = costs
+                .operation(ChangeKind::Remove)
+                .saturating_add(context.argument(run.original))
+                .saturating_add(context.argument(run.length));
+            let insert_cost = run.cost.saturating_add(
+                run.length.saturating_mul(
+                    costs
+                        .operation(ChangeKind::Insert)
+                        .saturating_add(context.argument(run.updated)),
+                ),
+            );
+            move_cost < remove_cost.saturating_add(insert_cost)
+        });
+        runs
+    }
 
-    // }
-}
+    /// Logs the changes to perform `moves`, returning the resulting order of
+    /// the original values and the matches between the reordered values and
+    /// the updated values. `offset` is the index of the first value in the
+    /// sequence being updated.
+    fn create_moves<S: ChangeSink>(
+        offset: usize,
+        original_len: usize,
+        updated_len: usize,
+        matches: &[(usize, usize)],
+        moves: &[MoveRun],
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) -> (Vec<usize>, Vec<(usize, usize)>) {
+        let mut order = (0..original_len).collect::<Vec<_>>();
+        let mut partners = vec![None; updated_len];
+        for &(original, updated) in matches {
+            partners[updated] = Some(original);
+        }
+
+        for run in moves {
+            // Each run is placed after the closest value before it that is
+            // already in its final position.
+            let anchor = partners[..run.updated]
+                .iter()
+                .rev()
+                .find_map(|partner| *partner);
+            let from = order
+                .iter()
+                .position(|original| *original == run.original)
+                .expect("original present");
+            let moved = order.drain(from..from + run.length).collect::<Vec<_>>();
+            let to = anchor.map_or(0, |anchor| {
+                order
+                    .iter()
+                    .position(|original| *original == anchor)
+                    .expect("anchor present")
+                    + 1
+            });
+            order.splice(to..to, moved);
+
+            if from != to {
+                let (from, to) = (offset + from, offset + to);
+                diff.log_change(
+                    ChangeKind::Move,
+                    context.argument(from) + context.argument(to) + context.argument(run.length),
+                    Change::Move {
+                        from,
+                        to,
+                        length: run.length,
+                    },
+                );
+            }
+
+            for moved in 0..run.length {
+                partners[run.updated + moved] = Some(run.original + moved);
+            }
+        }
+
+        let mut positions = vec![0; original_len];
+        for (position, original) in order.iter().enumerate() {
+            positions[*original] = position;
+        }
+        let matches = partners
+            .iter()
+            .enumerate()
+            .filter_map(|(updated, original)| {
+                original.map(|original| (positions[original], updated))
+            })
+            .collect();
+
+        (order, matches)
+    }
+
+    fn create_map_diff<S: ChangeSink>(
+        original_values: &[(Value<'_>, Value<'_>)],
+        original_hashes: &[(HashTree, HashTree)],
+        updated_values: &VecDeque<(Estimated<'_>, Estimated<'_>)>,
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) {
+        if context.is_exhausted() {
+            diff.require_replacement();
+            return;
+        }
+
+        let alignment =
+            Self::align_mappings(original_values, original_hashes, updated_values, context);
+        if context.is_exhausted() {
+            diff.require_replacement();
+            return;
+        }
+
+        // Diff the entries that were aligned with an original entry. Entries
+        // whose keys match have their values diffed, and entries whose values
+        // match have their keys diffed.
+        let work = |entry: &MapAlignment| match *entry {
+            MapAlignment::Matched { updated, .. } | MapAlignment::Replaced { updated, .. } => {
+                updated_values[updated].1.cost
+            }
+            _ => 0,
+        };
+        let entry_diffs = diff_each(&alignment, context, work, |entry| match *entry {
+            MapAlignment::Matched { original, updated } => {
+                let (original_key, original_value) = &original_values[original];
+                let (_, value_hashes) = &original_hashes[original];
+                let (_, updated_value) = &updated_values[updated];
+                (!context.equal(original_value, value_hashes.hash, updated_value)).then(|| {
+                    let path = Path::new(Segment::Key(original_key), context.path);
+                    let mut changes = Changes::new(context);
+                    Self::create_diff(
+                        Some(updated),
+                        original_value,
+                        value_hashes,
+                        updated_value,
+                        false,
+                        &context.at(&path),
+                        &mut changes,
+                    );
+                    changes
+                })
+            }
+            MapAlignment::Replaced { original, updated } => {
+                let (original_key, original_value) = &original_values[original];
+                let (key_hashes, value_hashes) = &original_hashes[original];
+                let (updated_key, updated_value) = &updated_values[updated];
+                context
+                    .equal(original_value, value_hashes.hash, updated_value)
+                    .then(|| {
+                        let mut changes = Changes::new(context);
+                        Self::create_diff(
+                            Some(updated),
+                            original_key,
+                            key_hashes,
+                            updated_key,
+                            true,
+                            context,
+                            &mut changes,
+                        );
+                        changes
+                    })
+            }
+            _ => None,
+        });
+
+        let mut replaced_values = ReplacementRun::default();
+        let mut replaced_mappings = ReplacementRun::default();
+        for (entry, changes) in alignment.iter().zip(entry_diffs) {
+            match *entry {
+                MapAlignment::Removed { index, length } => {
+                    replaced_mappings.finish(context, diff);
+                    replaced_values.finish(context, diff);
+                    diff.log_change(
+                        ChangeKind::Remove,
+                        context.argument(index) + context.argument(length),
+                        Change::Remove { index, length },
+                    );
+                }
+                MapAlignment::Matched { updated: index, .. } => {
+                    replaced_mappings.finish(context, diff);
+                    if let Some(changes) = changes {
+                        // The value for this key has changed.
+                        let updated = &updated_values[index].1;
+                        let copy = context.find_copy(updated);
+                        if let Some(copy) = copy.filter(|copy| {
+                            context.prefers_replacement(
+                                changes.cost,
+                                ChangeKind::Copy,
+                                copy.cost + context.argument(index),
+                            )
+                        }) {
+                            replaced_values.finish(context, diff);
+                            diff.log_change(
+                                ChangeKind::Copy,
+                                copy.cost + context.argument(index),
+                                Change::Copy {
+                                    index: Some(index),
+                                    source: copy.source.to_vec(),
+                                },
+                            );
+                        } else if context.prefers_replacement(
+                            changes.cost,
+                            ChangeKind::Replace,
+                            updated.cost + context.argument(index),
+                        ) {
+                            replaced_values.push(index, updated, updated.cost, context, diff);
+                        } else {
+                            replaced_values.finish(context, diff);
+                            diff.append(changes);
+                        }
+                    }
+                }
+                MapAlignment::Replaced { updated: index, .. } => {
+                    replaced_values.finish(context, diff);
+                    let updated = &updated_values[index];
+                    if let Some(changes) = changes {
+                        // This contains only a change to the key.
+                        replaced_mappings.finish(context, diff);
+                        if context.prefers_replacement(
+                            changes.cost,
+                            ChangeKind::ReplaceKey,
+                            updated.0.cost + context.argument(index),
+                        ) {
+                            diff.log_change(
+                                ChangeKind::ReplaceKey,
+                                updated.0.cost + context.argument(index),
+                                Change::ReplaceKey {
+                                    index,
+                                    key: updated.0.to_value(),
+                                },
+                            );
+                        } else {
+                            diff.append(changes);
+                        }
+                    } else {
+                        // Replace the entire entry
+                        let cost = updated.0.cost + updated.1.cost;
+                        replaced_mappings.push(index, updated, cost, context, diff);
+                    }
+                }
+                MapAlignment::Inserted { updated: index } => {
+                    replaced_values.finish(context, diff);
+                    replaced_mappings.finish(context, diff);
+                    let (key, value) = &updated_values[index];
+                    diff.log_change(
+                        ChangeKind::InsertMapping,
+                        key.cost + value.cost + context.argument(index),
+                        Change::InsertMapping {
+                            index,
+                            key: key.to_value(),
+                            value: value.to_value(),
+                        },
+                    );
+                }
+                MapAlignment::Truncated { length } => {
+                    // Extra values, need to truncate.
+                    replaced_values.finish(context, diff);
+                    replaced_mappings.finish(context, diff);
+                    diff.log_change(
+                        ChangeKind::Truncate,
+                        context.argument(length),
+                        Change::Truncate { length },
+                    );
+                }
+            }
+        }
+        replaced_values.finish(context, diff);
+        replaced_mappings.finish(context, diff);
+    }
+
+    /// Aligns the entries of an updated mapping with the entries of the
+    /// original mapping by their keys, returning the steps that transform the
+    /// original entries into the updated entries.
+    fn align_mappings(
+        original_values: &[(Value<'_>, Value<'_>)],
+        original_hashes: &[(HashTree, HashTree)],
+        updated_values: &VecDeque<(Estimated<'_>, Estimated<'_>)>,
+        context: &Context<'_>,
+    ) -> Vec<MapAlignment> {
+        // Index both lists of keys by their hashes, allowing each key to be
+        // located without scanning the remainder of the other list.
+        let mut original_keys = KeyIndex::default();
+        for (index, (key, _)) in original_hashes.iter().enumerate() {
+            original_keys.insert(key.hash, index);
+        }
+        let mut updated_keys = KeyIndex::default();
+        for (index, (key, _)) in updated_values.iter().enumerate() {
+            updated_keys.insert(key.hash, index);
+        }
+
+        let mut alignment = Vec::with_capacity(updated_values.len());
+        let mut original_index = 0;
+        let mut updated_index = 0;
+        while let Some(updated) = updated_values.get(updated_index) {
+            if let Some(original) = original_values.get(original_index) {
+                let (key_hashes, _) = &original_hashes[original_index];
+                if let Some(matching_index) =
+                    original_keys.find(updated.0.hash, context.lookahead(original_index), |index| {
+                        context.equal(&original_values[index].0, updated.0.hash, &updated.0)
+                    })
+                {
+                    // We found where the the updated value is located in the
+                    // original list.
+                    if matching_index > original_index {
+                        alignment.push(MapAlignment::Removed {
+                            index: updated_index,
+                            length: matching_index - original_index,
+                        });
+                    }
+                    alignment.push(MapAlignment::Matched {
+                        original: matching_index,
+                        updated: updated_index,
+                    });
+                    original_index = matching_index + 1;
+                    updated_index += 1;
+                } else if let Some(matching_index) = updated_keys.find(
+                    key_hashes.hash,
+                    context.lookahead(updated_index + 1),
+                    |index| context.equal(&original.0, key_hashes.hash, &updated_values[index].0),
+                ) {
+                    // We found where the the original value is located in the
+                    // updated list. Insert the entries that precede it, and
+                    // let the next iteration compare the matching entry.
+                    alignment.extend(
+                        (updated_index..matching_index)
+                            .map(|updated| MapAlignment::Inserted { updated }),
+                    );
+                    updated_index = matching_index;
+                } else {
+                    alignment.push(MapAlignment::Replaced {
+                        original: original_index,
+                        updated: updated_index,
+                    });
+                    original_index += 1;
+                    updated_index += 1;
+                }
+            } else {
+                // Pushing a new value
+                alignment.push(MapAlignment::Inserted {
+                    updated: updated_index,
+                });
+                updated_index += 1;
+            }
+        }
+
+        if original_index < original_values.len() {
+            alignment.push(MapAlignment::Truncated {
+                length: updated_index,
+            });
+        }
+
+        alignment
+    }
+
+    /// Diffs two mappings whose entries are matched by key regardless of their
+    /// positions. Each entry is changed by its key, so the diff can be applied
+    /// to the mapping in any order.
+    fn create_unordered_map_diff<S: ChangeSink>(
+        original_values: &[(Value<'_>, Value<'_>)],
+        original_hashes: &[(HashTree, HashTree)],
+        updated_values: &VecDeque<(Estimated<'_>, Estimated<'_>)>,
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) {
+        if context.is_exhausted() {
+            diff.require_replacement();
+            return;
+        }
+
+        let mut original_keys = KeyIndex::default();
+        for (index, (key, _)) in original_hashes.iter().enumerate() {
+            original_keys.insert(key.hash, index);
+        }
+        let mut removed = vec![true; original_values.len()];
+        let entries = updated_values
+            .iter()
+            .enumerate()
+            .map(|(updated, (key, _))| {
+                let original = original_keys.take(key.hash, |index| {
+                    context.equal(&original_values[index].0, key.hash, key)
+                });
+                if let Some(original) = original {
+                    removed[original] = false;
+                }
+                (original, updated)
+            })
+            .collect::<Vec<_>>();
+
+        // The values of matching keys are diffed as though each was the root
+        // value, as changes within an entry are applied after locating it by
+        // its key.
+        let work = |&(original, updated): &(Option<usize>, usize)| {
+            original.map_or(0, |_| updated_values[updated].1.cost)
+        };
+        let value_diffs = diff_each(&entries, context, work, |&(original, updated)| {
+            original.map(|original| {
+                let (original_key, original_value) = &original_values[original];
+                let (_, value_hashes) = &original_hashes[original];
+                let path = Path::new(Segment::Key(original_key), context.path);
+                let mut changes = Changes::new(context);
+                Self::create_diff(
+                    None,
+                    original_value,
+                    value_hashes,
+                    &updated_values[updated].1,
+                    false,
+                    &context.at(&path),
+                    &mut changes,
+                );
+                changes
+            })
+        });
+
+        let costs = context.costs();
+        for ((key, _), _) in original_values
+            .iter()
+            .zip(removed)
+            .filter(|(_, removed)| *removed)
+        {
+            let key = Estimated::measure(key, costs);
+            diff.log_change(
+                ChangeKind::RemoveKey,
+                key.cost,
+                Change::RemoveKey {
+                    key: key.to_value(),
+                },
+            );
+        }
+        for ((_, updated), changes) in entries.iter().zip(value_diffs) {
+            let (key, value) = &updated_values[*updated];
+            match changes {
+                Some(changes) if changes.changes.is_empty() && !changes.requires_replacement() => {}
+                Some(changes)
+                    if !context.prefers_replacement(
+                        changes
+                            .cost
+                            .saturating_add(costs.operation(ChangeKind::EnterKey))
+                            .saturating_add(key.cost)
+                            .saturating_add(costs.operation(ChangeKind::Exit)),
+                        ChangeKind::SetKey,
+                        key.cost.saturating_add(value.cost),
+                    ) =>
+                {
+                    diff.enter(
+                        ChangeKind::EnterKey,
+                        key.cost,
+                        Change::EnterKey {
+                            key: key.to_value(),
+                        },
+                    );
+                    diff.append(changes);
+                    diff.exit();
+                }
+                _ => {
+                    // The entry was inserted, or its value is replaced.
+                    diff.log_change(
+                        ChangeKind::SetKey,
+                        key.cost + value.cost,
+                        Change::SetKey {
+                            key: key.to_value(),
+                            value: value.to_value(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn apply<T: Serialize + DeserializeOwned>(&self, against: &T) -> Result<T, Error> {
+        let updated_value = self.apply_to_value(Value::from_serialize(against))?;
+        updated_value.deserialize_as().map_err(Error::from)
+    }
+
+    pub fn apply_to_value(&self, value: Value<'static>) -> Result<Value<'static>, Error> {
+        // Copies reference the value as it was before any changes were applied.
+        let snapshot = self
+            .changes
+            .iter()
+            .any(|change| matches!(change, Change::Copy { .. } | Change::InsertCopy { .. }))
+            .then(|| value.clone());
+        let snapshot = snapshot.as_ref();
+        apply_changes_to_value(value, &mut self.changes.iter().cloned(), snapshot)
+    }
+
+    // fn serialize_into<W: Write>(&self, writer: W) -> io::Result<()> {
+
+    // }
+}
+
+/// Applies the changes to a value that aren't addressed by an index, which are
+/// the changes to the root value or to a value entered using
+/// [`Change::EnterKey`]. Changes are applied until the exit from the value or
+/// the end of the changes.
+fn apply_changes_to_value(
+    mut value: Value<'static>,
+    changes: &mut Cloned<slice::Iter<'_, Change>>,
+    snapshot: Option<&Value<'static>>,
+) -> Result<Value<'static>, Error> {
+    let apply_result = match changes.next() {
+        Some(Change::Replace { index: None, value }) => ApplyResult::Replace(value),
+        Some(Change::Copy {
+            index: None,
+            source,
+        }) => ApplyResult::Replace(copy_from(snapshot, &source)?),
+        Some(Change::EnterSequence {
+            index: None,
+            key: false,
+        }) => {
+            if let Value::Sequence(sequence) = &mut value {
+                apply_changes_to_sequence(sequence, changes, snapshot)?
+            } else {
+                return Err(Error::TypeMismatch);
+            }
+        }
+        Some(Change::EnterMap {
+            index: None,
+            key: false,
+        }) => {
+            if let Value::Mappings(mappings) = &mut value {
+                apply_changes_to_mappings(mappings, changes, snapshot)?
+            } else {
+                return Err(Error::TypeMismatch);
+            }
+        }
+        Some(Change::Splice {
+            index: None,
+            offset,
+            delete,
+            insert,
+        }) => {
+            splice_string(&mut value, offset, delete, &insert)?;
+            // A string has no children, so only more splices can follow.
+            loop {
+                match changes.next() {
+                    Some(Change::Splice {
+                        index: None,
+                        offset,
+                        delete,
+                        insert,
+                    }) => splice_string(&mut value, offset, delete, &insert)?,
+                    Some(Change::Exit) | None => break,
+                    _ => return Err(Error::UnexpectedChange),
+                }
+            }
+            return Ok(value);
+        }
+        Some(Change::Delta {
+            index: None,
+            instructions,
+        }) => {
+            apply_delta(&mut value, &instructions)?;
+            ApplyResult::Ok
+        }
+        Some(Change::Add { index: None, delta }) => {
+            add_to_value(&mut value, &delta)?;
+            ApplyResult::Ok
+        }
+        None => ApplyResult::Ok,
+        _ => return Err(Error::UnexpectedChange),
+    };
+
+    // Consume the exit from the value, if there is one.
+    match changes.next() {
+        Some(Change::Exit) | None => {}
+        _ => return Err(Error::UnexpectedChange),
+    }
+    match apply_result {
+        ApplyResult::Ok => Ok(value),
+        ApplyResult::Replace(new_value) => Ok(new_value),
+    }
+}
 
 impl Display for Diff {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         enum StackEntry {
             Sequence,
             Map,
+            Key,
         }
         let mut stack = Vec::new();
         for change in &self.changes {
@@ -485,6 +1655,7 @@ impl Display for Diff {
                 Change::Exit => match stack.pop() {
                     Some(StackEntry::Sequence) => f.write_char(']')?,
                     Some(StackEntry::Map) => f.write_char('}')?,
+                    Some(StackEntry::Key) => f.write_char(')')?,
                     None => f.write_char('?')?,
                 },
                 Change::Replace {
@@ -496,12 +1667,81 @@ impl Display for Diff {
                 Change::ReplaceMapping { index, key, value } => {
                     write!(f, "~{index};{};{}", ValueDisplay(key), ValueDisplay(value))?
                 }
+                Change::ReplaceRange { index, values } => {
+                    write!(f, "~{index}..{}", index + values.len())?;
+                    for value in values {
+                        write!(f, ";{}", ValueDisplay(value))?;
+                    }
+                }
+                Change::ReplaceMappingRange { index, mappings } => {
+                    write!(f, "~{index}..{}", index + mappings.len())?;
+                    for (key, value) in mappings {
+                        write!(f, ";{};{}", ValueDisplay(key), ValueDisplay(value))?;
+                    }
+                }
                 Change::Remove { index, length } => write!(f, "-{index};{length}")?,
                 Change::Truncate { length } => write!(f, "${length}")?,
-                Change::Insert { index, value } => write!(f, "+{index};{value}")?,
+                Change::Move { from, to, length } => write!(f, ">{from};{to};{length}")?,
+                Change::Splice {
+                    index: Some(index),
+                    offset,
+                    delete,
+                    insert,
+                } => write!(f, "^{index};{offset};{delete};{}", StringDisplay(insert))?,
+                Change::Splice {
+                    index: None,
+                    offset,
+                    delete,
+                    insert,
+                } => write!(f, "^;{offset};{delete};{}", StringDisplay(insert))?,
+                Change::Delta {
+                    index,
+                    instructions,
+                } => {
+                    f.write_char('%')?;
+                    if let Some(index) = index {
+                        write!(f, "{index}")?;
+                    }
+                    for instruction in instructions {
+                        match instruction {
+                            DeltaInstruction::Copy { offset, length } => {
+                                write!(f, ";={offset},{length}")?;
+                            }
+                            DeltaInstruction::Literal(bytes) => {
+                                write!(f, ";{}", BytesDisplay(bytes))?;
+                            }
+                        }
+                    }
+                }
+                Change::Add {
+                    index: Some(index),
+                    delta,
+                } => write!(f, "!{index};{}", ValueDisplay(delta))?,
+                Change::Add { index: None, delta } => write!(f, "!;{}", ValueDisplay(delta))?,
+                Change::Copy { index, source } => {
+                    f.write_char('&')?;
+                    if let Some(index) = index {
+                        write!(f, "{index}")?;
+                    }
+                    write!(f, ";{}", PathDisplay(source))?;
+                }
+                Change::InsertCopy { index, source } => {
+                    write!(f, "&+{index};{}", PathDisplay(source))?;
+                }
+                Change::Insert { index, value } => write!(f, "+{index};{}", ValueDisplay(value))?,
                 Change::InsertMapping { index, key, value } => {
                     write!(f, "+{index};{};{}", ValueDisplay(key), ValueDisplay(value))?
                 }
+                Change::EnterKey { key } => {
+                    write!(f, "({};", ValueDisplay(key))?;
+                    stack.push(StackEntry::Key);
+                }
+                Change::SetKey { key, value } => {
+                    write!(f, "={};{}", ValueDisplay(key), ValueDisplay(value))?
+                }
+                Change::RemoveKey { key } => write!(f, "-={}", ValueDisplay(key))?,
+                Change::InsertMember { value } => write!(f, "+:{}", ValueDisplay(value))?,
+                Change::RemoveMember { value } => write!(f, "-:{}", ValueDisplay(value))?,
             }
         }
         Ok(())
@@ -511,6 +1751,7 @@ impl Display for Diff {
 fn apply_changes_to_sequence(
     values: &mut Vec<Value<'static>>,
     changes: &mut Cloned<slice::Iter<'_, Change>>,
+    snapshot: Option<&Value<'static>>,
 ) -> Result<ApplyResult, Error> {
     loop {
         match changes.next() {
@@ -520,6 +1761,16 @@ fn apply_changes_to_sequence(
             }) => {
                 values[index] = value;
             }
+            Some(Change::ReplaceRange {
+                index,
+                values: replacements,
+            }) => {
+                let end = index
+                    .checked_add(replacements.len())
+                    .filter(|&end| end <= values.len())
+                    .ok_or(Error::IndexOutOfRange)?;
+                values.splice(index..end, replacements);
+            }
             Some(Change::Remove { index, length }) => {
                 if index + length <= values.len() {
                     values.drain(index..index + length);
@@ -534,6 +1785,43 @@ fn apply_changes_to_sequence(
                     todo!("error")
                 }
             }
+            Some(Change::Move { from, to, length }) => move_values(values, from, to, length)?,
+            Some(Change::Splice {
+                index: Some(index),
+                offset,
+                delete,
+                insert,
+            }) => {
+                let value = values.get_mut(index).ok_or(Error::IndexOutOfRange)?;
+                splice_string(value, offset, delete, &insert)?;
+            }
+            Some(Change::Delta {
+                index: Some(index),
+                instructions,
+            }) => {
+                let value = values.get_mut(index).ok_or(Error::IndexOutOfRange)?;
+                apply_delta(value, &instructions)?;
+            }
+            Some(Change::Add {
+                index: Some(index),
+                delta,
+            }) => {
+                let value = values.get_mut(index).ok_or(Error::IndexOutOfRange)?;
+                add_to_value(value, &delta)?;
+            }
+            Some(Change::Copy {
+                index: Some(index),
+                source,
+            }) => {
+                let value = values.get_mut(index).ok_or(Error::IndexOutOfRange)?;
+                *value = copy_from(snapshot, &source)?;
+            }
+            Some(Change::InsertCopy { index, source }) => {
+                if index > values.len() {
+                    return Err(Error::IndexOutOfRange);
+                }
+                values.insert(index, copy_from(snapshot, &source)?);
+            }
             Some(Change::Insert { index, value }) => {
                 if index <= values.len() {
                     values.insert(index, value);
@@ -541,12 +1829,20 @@ fn apply_changes_to_sequence(
                     todo!("error")
                 }
             }
+            Some(Change::InsertMember { value }) => {
+                if !values.contains(&value) {
+                    values.push(value);
+                }
+            }
+            Some(Change::RemoveMember { value }) => {
+                values.retain(|existing| *existing != value);
+            }
             Some(Change::EnterSequence {
                 index: Some(index),
                 key: false,
             }) => {
                 if let Some(Value::Sequence(entered)) = values.get_mut(index) {
-                    apply_changes_to_sequence(entered, changes)?;
+                    apply_changes_to_sequence(entered, changes, snapshot)?;
                 } else {
                     todo!("error")
                 }
@@ -556,7 +1852,7 @@ fn apply_changes_to_sequence(
                 key: false,
             }) => {
                 if let Some(Value::Mappings(entered)) = values.get_mut(index) {
-                    apply_changes_to_mappings(entered, changes)?;
+                    apply_changes_to_mappings(entered, changes, snapshot)?;
                 } else {
                     todo!("error")
                 }
@@ -570,6 +1866,7 @@ fn apply_changes_to_sequence(
 fn apply_changes_to_mappings(
     values: &mut Vec<(Value<'static>, Value<'static>)>,
     changes: &mut Cloned<slice::Iter<'_, Change>>,
+    snapshot: Option<&Value<'static>>,
 ) -> Result<ApplyResult, Error> {
     loop {
         match changes.next() {
@@ -585,6 +1882,25 @@ fn apply_changes_to_mappings(
             Some(Change::ReplaceKey { index, key }) => {
                 values[index].0 = key;
             }
+            Some(Change::ReplaceRange {
+                index,
+                values: replacements,
+            }) => {
+                let end = index
+                    .checked_add(replacements.len())
+                    .filter(|&end| end <= values.len())
+                    .ok_or(Error::IndexOutOfRange)?;
+                for ((_, value), replacement) in values[index..end].iter_mut().zip(replacements) {
+                    *value = replacement;
+                }
+            }
+            Some(Change::ReplaceMappingRange { index, mappings }) => {
+                let end = index
+                    .checked_add(mappings.len())
+                    .filter(|&end| end <= values.len())
+                    .ok_or(Error::IndexOutOfRange)?;
+                values.splice(index..end, mappings);
+            }
             Some(Change::Remove { index, length }) => {
                 if index + length <= values.len() {
                     values.drain(index..index + length);
@@ -599,6 +1915,37 @@ fn apply_changes_to_mappings(
                     todo!("error")
                 }
             }
+            Some(Change::Move { from, to, length }) => move_values(values, from, to, length)?,
+            Some(Change::Splice {
+                index: Some(index),
+                offset,
+                delete,
+                insert,
+            }) => {
+                let (_, value) = values.get_mut(index).ok_or(Error::IndexOutOfRange)?;
+                splice_string(value, offset, delete, &insert)?;
+            }
+            Some(Change::Delta {
+                index: Some(index),
+                instructions,
+            }) => {
+                let (_, value) = values.get_mut(index).ok_or(Error::IndexOutOfRange)?;
+                apply_delta(value, &instructions)?;
+            }
+            Some(Change::Add {
+                index: Some(index),
+                delta,
+            }) => {
+                let (_, value) = values.get_mut(index).ok_or(Error::IndexOutOfRange)?;
+                add_to_value(value, &delta)?;
+            }
+            Some(Change::Copy {
+                index: Some(index),
+                source,
+            }) => {
+                let (_, value) = values.get_mut(index).ok_or(Error::IndexOutOfRange)?;
+                *value = copy_from(snapshot, &source)?;
+            }
             Some(Change::InsertMapping { index, key, value }) => {
                 if index <= values.len() {
                     values.insert(index, (key, value));
@@ -606,6 +1953,34 @@ fn apply_changes_to_mappings(
                     todo!("error")
                 }
             }
+            Some(Change::EnterKey { key }) => {
+                if let Some((_, entered)) = values.iter_mut().find(|(existing, _)| *existing == key)
+                {
+                    *entered = apply_changes_to_value(
+                        std::mem::replace(entered, Value::None),
+                        changes,
+                        snapshot,
+                    )?;
+                } else {
+                    return Err(Error::MissingKey);
+                }
+            }
+            Some(Change::SetKey { key, value }) => {
+                if let Some((_, existing)) =
+                    values.iter_mut().find(|(existing, _)| *existing == key)
+                {
+                    *existing = value;
+                } else {
+                    values.push((key, value));
+                }
+            }
+            Some(Change::RemoveKey { key }) => {
+                if let Some(index) = values.iter().position(|(existing, _)| *existing == key) {
+                    values.remove(index);
+                } else {
+                    return Err(Error::MissingKey);
+                }
+            }
             Some(Change::EnterSequence {
                 index: Some(index),
                 key,
@@ -615,7 +1990,7 @@ fn apply_changes_to_mappings(
                         .get_mut(index)
                         .map(|pair| if key { &mut pair.0 } else { &mut pair.1 })
                 {
-                    apply_changes_to_sequence(entered, changes)?;
+                    apply_changes_to_sequence(entered, changes, snapshot)?;
                 } else {
                     todo!("error")
                 }
@@ -629,7 +2004,7 @@ fn apply_changes_to_mappings(
                         .get_mut(index)
                         .map(|pair| if key { &mut pair.0 } else { &mut pair.1 })
                 {
-                    apply_changes_to_mappings(entered, changes)?;
+                    apply_changes_to_mappings(entered, changes, snapshot)?;
                 } else {
                     todo!("error")
                 }
@@ -640,251 +2015,1587 @@ fn apply_changes_to_mappings(
     }
 }
 
-enum ApplyResult {
-    Ok,
-    Replace(Value<'static>),
+fn move_values<T>(values: &mut Vec<T>, from: usize, to: usize, length: usize) -> Result<(), Error> {
+    let fits = |index: usize| matches!(index.checked_add(length), Some(end) if end <= values.len());
+    if fits(from) && fits(to) {
+        let moved = values.drain(from..from + length).collect::<Vec<_>>();
+        values.splice(to..to, moved);
+        Ok(())
+    } else {
+        Err(Error::IndexOutOfRange)
+    }
+}
+
+fn splice_string(
+    value: &mut Value<'static>,
+    offset: usize,
+    delete: usize,
+    insert: &str,
+) -> Result<(), Error> {
+    if let Value::String(string) = value {
+        match offset.checked_add(delete) {
+            Some(end) if string.is_char_boundary(offset) && string.is_char_boundary(end) => {
+                string.to_mut().replace_range(offset..end, insert);
+                Ok(())
+            }
+            _ => Err(Error::InvalidSplice),
+        }
+    } else {
+        Err(Error::TypeMismatch)
+    }
+}
+
+fn apply_delta(value: &mut Value<'static>, instructions: &[DeltaInstruction]) -> Result<(), Error> {
+    if let Value::Bytes(bytes) = value {
+        let updated = delta::apply(bytes, instructions).ok_or(Error::InvalidDelta)?;
+        *bytes = Cow::Owned(updated);
+        Ok(())
+    } else {
+        Err(Error::TypeMismatch)
+    }
+}
+
+fn copy_from(snapshot: Option<&Value<'static>>, source: &[usize]) -> Result<Value<'static>, Error> {
+    snapshot
+        .and_then(|snapshot| value_at(snapshot, source))
+        .cloned()
+        .ok_or(Error::InvalidCopySource)
+}
+
+enum ApplyResult {
+    Ok,
+    Replace(Value<'static>),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("error deserializing Value: {0}")]
+    ValueDeserialization(#[from] pot::ValueError),
+    #[error("a change refers to an index past the end of its container")]
+    IndexOutOfRange,
+    #[error("a change can't be applied at this point in the diff")]
+    UnexpectedChange,
+    #[error("Change::Splice doesn't start and end on character boundaries of the string")]
+    InvalidSplice,
+    #[error("Change::Delta copies bytes from past the end of the original bytes")]
+    InvalidDelta,
+    #[error("Change::Copy refers to a path that isn't in the original value")]
+    InvalidCopySource,
+    #[error("numeric overflow while applying Change::Add")]
+    Overflow,
+    #[error("a change can't be applied to a value of a different type")]
+    TypeMismatch,
+    #[error("a change refers to a key that isn't in the mapping")]
+    MissingKey,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    EnterSequence {
+        index: Option<usize>,
+        key: bool,
+    },
+    EnterMap {
+        index: Option<usize>,
+        key: bool,
+    },
+    Exit,
+    Replace {
+        index: Option<usize>,
+        value: Value<'static>,
+    },
+    ReplaceKey {
+        index: usize,
+        key: Value<'static>,
+    },
+    ReplaceMapping {
+        index: usize,
+        key: Value<'static>,
+        value: Value<'static>,
+    },
+    ReplaceRange {
+        index: usize,
+        values: Vec<Value<'static>>,
+    },
+    ReplaceMappingRange {
+        index: usize,
+        mappings: Vec<(Value<'static>, Value<'static>)>,
+    },
+    Remove {
+        index: usize,
+        length: usize,
+    },
+    Truncate {
+        length: usize,
+    },
+    Move {
+        from: usize,
+        to: usize,
+        length: usize,
+    },
+    Splice {
+        index: Option<usize>,
+        offset: usize,
+        delete: usize,
+        insert: String,
+    },
+    Delta {
+        index: Option<usize>,
+        instructions: Vec<DeltaInstruction>,
+    },
+    Add {
+        index: Option<usize>,
+        delta: Value<'static>,
+    },
+    Copy {
+        index: Option<usize>,
+        source: Vec<usize>,
+    },
+    InsertCopy {
+        index: usize,
+        source: Vec<usize>,
+    },
+    Insert {
+        index: usize,
+        value: Value<'static>,
+    },
+    InsertMapping {
+        index: usize,
+        key: Value<'static>,
+        value: Value<'static>,
+    },
+    EnterKey {
+        key: Value<'static>,
+    },
+    SetKey {
+        key: Value<'static>,
+        value: Value<'static>,
+    },
+    RemoveKey {
+        key: Value<'static>,
+    },
+    /// Adds a value to a sequence of members, unless an equal value is
+    /// already a member. Produced by [`PathPolicy::Set`].
+    InsertMember {
+        value: Value<'static>,
+    },
+    /// Removes every value equal to `value` from a sequence of members.
+    /// Produced by [`PathPolicy::Set`].
+    RemoveMember {
+        value: Value<'static>,
+    },
+}
+
+/// Returns the amount to add to `original` to produce `updated`, if it can be
+/// represented exactly.
+fn numeric_delta(original: &Value<'_>, updated: &EstimatedValue<'_>) -> Option<Value<'static>> {
+    match (original, updated) {
+        (Value::Integer(original), EstimatedValue::Integer(updated)) => {
+            let delta = updated
+                .as_i128()
+                .ok()?
+                .checked_sub(original.as_i128().ok()?)?;
+            Some(Value::Integer(Integer::from(delta)))
+        }
+        (Value::Float(original), EstimatedValue::Float(updated)) => {
+            let delta = updated.as_f64() - original.as_f64();
+            (delta.is_finite() && original.as_f64() + delta == updated.as_f64())
+                .then(|| Value::Float(Float::from(delta)))
+        }
+        _ => None,
+    }
+}
+
+fn add_to_value(value: &mut Value<'static>, delta: &Value<'static>) -> Result<(), Error> {
+    match (value, delta) {
+        (Value::Integer(value), Value::Integer(delta)) => {
+            let sum = value
+                .as_i128()
+                .ok()
+                .zip(delta.as_i128().ok())
+                .and_then(|(value, delta)| value.checked_add(delta))
+                .ok_or(Error::Overflow)?;
+            *value = narrowest_integer(value, sum);
+        }
+        (Value::Float(value), Value::Float(delta)) => {
+            let sum = value.as_f64() + delta.as_f64();
+            if !sum.is_finite() {
+                return Err(Error::Overflow);
+            }
+            // Keep single precision values single precision, as long as the
+            // sum doesn't need more precision.
+            let narrowed = sum as f32;
+            *value = if value.as_f32().is_ok() && f64::from(narrowed) == sum {
+                Float::from(narrowed)
+            } else {
+                Float::from(sum)
+            };
+        }
+        _ => return Err(Error::TypeMismatch),
+    }
+    Ok(())
+}
+
+/// Returns `sum` as the narrowest integer type that can hold both it and
+/// `original`, so that adding to a small integer doesn't widen it.
+fn narrowest_integer(original: &Integer, sum: i128) -> Integer {
+    macro_rules! narrowest {
+        ($($as:ident => $ty:ty),*) => {
+            $(if let (Ok(_), Ok(sum)) = (original.$as(), <$ty>::try_from(sum)) {
+                return Integer::from(sum);
+            })*
+        };
+    }
+    narrowest!(
+        as_u8 => u8, as_i8 => i8, as_u16 => u16, as_i16 => i16,
+        as_u32 => u32, as_i32 => i32, as_u64 => u64, as_i64 => i64
+    );
+    Integer::from(sum)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeltaInstruction {
+    Copy { offset: usize, length: usize },
+    Literal(Vec<u8>),
+}
+
+/// A list of changes along with their total cost.
+///
+/// Entering a container is only logged once a change within it is logged, as
+/// values that hash differently can still be equal, such as `0.0` and `-0.0`.
+/// Exits are only logged once another change follows them, as trailing exits
+/// are unnecessary.
+struct Changes<'a, S = Vec<Change>> {
+    costs: &'a dyn CostModel,
+    /// The logged changes, which are collected unless these are the changes
+    /// being streamed by [`Diff::write_between()`].
+    changes: S,
+    /// The cost of the logged changes.
+    cost: usize,
+    /// The number of exits logged since the last other change.
+    pending_exits: usize,
+    /// The containers that were entered without any changes being logged
+    /// within them yet, along with the cost of entering them.
+    pending_enters: Vec<(Change, usize)>,
+}
+
+/// A destination for the changes logged by [`Changes`].
+trait ChangeSink {
+    fn push(&mut self, change: Change);
+
+    /// Moves the changes that were collected while diffing a value
+    /// separately into this sink.
+    fn append(&mut self, changes: &mut Vec<Change>);
+}
+
+impl ChangeSink for Vec<Change> {
+    fn push(&mut self, change: Change) {
+        Vec::push(self, change);
+    }
+
+    fn append(&mut self, changes: &mut Vec<Change>) {
+        Vec::append(self, changes);
+    }
+}
+
+/// A sink that encodes changes into a writer as they are logged.
+struct ChangeStream<W> {
+    writer: W,
+    /// The result of writing the changes so far. Once writing fails, no more
+    /// changes are written.
+    result: io::Result<()>,
+}
+
+impl<W: io::Write> ChangeSink for ChangeStream<W> {
+    fn push(&mut self, change: Change) {
+        if self.result.is_ok() {
+            self.result = binary::encode_change(&change, &mut self.writer);
+        }
+    }
+
+    fn append(&mut self, changes: &mut Vec<Change>) {
+        for change in changes.drain(..) {
+            self.push(change);
+        }
+    }
+}
+
+impl<'a> Changes<'a> {
+    fn new(context: &Context<'a>) -> Self {
+        Self::with_sink(context, Vec::new())
+    }
+}
+
+impl<'a, S: ChangeSink> Changes<'a, S> {
+    /// Returns changes that are logged into `sink`.
+    fn with_sink(context: &Context<'a>, sink: S) -> Self {
+        Self {
+            costs: context.costs(),
+            changes: sink,
+            cost: 0,
+            pending_exits: 0,
+            pending_enters: Vec::new(),
+        }
+    }
+
+    /// Logs a change of `kind`, where `cost` is the cost of the change's
+    /// arguments as measured by the [`CostModel`].
+    fn log_change(&mut self, kind: ChangeKind, cost: usize, change: Change) {
+        self.flush_pending();
+        self.push(self.costs.operation(kind).saturating_add(cost), change);
+    }
+
+    /// Logs entering a container, where `cost` is the cost of the change's
+    /// arguments. The change is only logged if a change within the container
+    /// is logged before [`exit()`](Self::exit) is called.
+    fn enter(&mut self, kind: ChangeKind, cost: usize, change: Change) {
+        self.pending_enters
+            .push((change, self.costs.operation(kind).saturating_add(cost)));
+    }
+
+    /// Logs exiting the most recently entered container.
+    fn exit(&mut self) {
+        // If nothing was logged within the container, entering it is
+        // forgotten rather than exiting it.
+        if self.pending_enters.pop().is_none() {
+            self.pending_exits += 1;
+        }
+    }
+
+    /// Appends the changes of a value that was diffed separately.
+    fn append(&mut self, mut other: Changes<'_>) {
+        if other.changes.is_empty() {
+            return;
+        }
+        self.flush_pending();
+        self.cost = self.cost.saturating_add(other.cost);
+        self.changes.append(&mut other.changes);
+        self.pending_exits = other.pending_exits;
+    }
+
+    /// Logs the exits and enters that precede the next change.
+    fn flush_pending(&mut self) {
+        for _ in 0..std::mem::take(&mut self.pending_exits) {
+            self.push(self.costs.operation(ChangeKind::Exit), Change::Exit);
+        }
+        for (change, cost) in std::mem::take(&mut self.pending_enters) {
+            self.push(cost, change);
+        }
+    }
+
+    fn push(&mut self, cost: usize, change: Change) {
+        self.cost = self.cost.saturating_add(cost);
+        self.changes.push(change);
+    }
+
+    /// Marks these changes as costing more than replacing the value would,
+    /// which ensures the value is replaced by the caller.
+    fn require_replacement(&mut self) {
+        self.cost = usize::MAX;
+    }
+
+    fn requires_replacement(&self) -> bool {
+        self.cost == usize::MAX
+    }
+}
+
+/// A value being diffed against the original value, along with the cost of
+/// encoding it. Strings and bytes are borrowed from the updated [`Value`], and
+/// are only copied if they are included in a [`Change`].
+#[derive(Clone)]
+struct Estimated<'u> {
+    cost: usize,
+    /// The structural hash of the value, which is equal to the hash of the
+    /// [`Value`] it was measured from.
+    hash: u64,
+    /// The value this was measured from.
+    source: &'u Value<'u>,
+    value: EstimatedValue<'u>,
+}
+
+impl<'u> Estimated<'u> {
+    /// Measures the cost of encoding `value` using `costs`, and computes its
+    /// structural hash.
+    fn measure(value: &'u Value<'_>, costs: &dyn CostModel) -> Self {
+        let (cost, hash) = match value {
+            Value::Sequence(_) | Value::Mappings(_) => (0, 0),
+            scalar => (costs.scalar(scalar), hash::scalar(scalar)),
+        };
+        match value {
+            Value::None => Self::new(value, cost, hash, EstimatedValue::None),
+            Value::Unit => Self::new(value, cost, hash, EstimatedValue::Unit),
+            Value::Bool(bool) => Self::new(value, cost, hash, EstimatedValue::Bool(*bool)),
+            Value::Integer(integer) => {
+                Self::new(value, cost, hash, EstimatedValue::Integer(*integer))
+            }
+            Value::Float(float) => Self::new(value, cost, hash, EstimatedValue::Float(*float)),
+            Value::Bytes(bytes) => Self::new(value, cost, hash, EstimatedValue::Bytes(bytes)),
+            Value::String(string) => Self::new(value, cost, hash, EstimatedValue::String(string)),
+            Value::Sequence(values) => {
+                let values: Vec<Self> = values
+                    .iter()
+                    .map(|value| Self::measure(value, costs))
+                    .collect();
+                Self::new(
+                    value,
+                    values.iter().fold(
+                        costs.container(ContainerKind::Sequence, values.len()),
+                        |cost, value| cost.saturating_add(value.cost),
+                    ),
+                    hash::sequence(values.iter().map(|value| value.hash)),
+                    EstimatedValue::Sequence(values),
+                )
+            }
+            Value::Mappings(mappings) => {
+                let mappings: VecDeque<(Self, Self)> = mappings
+                    .iter()
+                    .map(|(key, value)| (Self::measure(key, costs), Self::measure(value, costs)))
+                    .collect();
+                Self::new(
+                    value,
+                    mappings.iter().fold(
+                        costs.container(ContainerKind::Mappings, mappings.len()),
+                        |cost, (key, value)| {
+                            cost.saturating_add(key.cost).saturating_add(value.cost)
+                        },
+                    ),
+                    hash::mappings(mappings.iter().map(|(key, value)| (key.hash, value.hash))),
+                    EstimatedValue::Mappings(mappings),
+                )
+            }
+        }
+    }
+
+    fn new(source: &'u Value<'u>, cost: usize, hash: u64, value: EstimatedValue<'u>) -> Self {
+        Self {
+            cost,
+            hash,
+            source,
+            value,
+        }
+    }
+
+    /// Collects the leaf hashes of the scalar values within this value, like
+    /// [`HashTree::leaves()`].
+    fn leaves(&self, path: u64, leaves: &mut Vec<u64>) {
+        match &self.value {
+            EstimatedValue::Sequence(values) => {
+                for value in values {
+                    value.leaves(path, leaves);
+                }
+            }
+            EstimatedValue::Mappings(mappings) => {
+                for (key, value) in mappings {
+                    value.leaves(hash::leaf(path, key.hash), leaves);
+                }
+            }
+            _ => leaves.push(hash::leaf(path, self.hash)),
+        }
+    }
+
+    /// Returns an owned copy of this value as a [`Value`].
+    fn to_value(&self) -> Value<'static> {
+        match &self.value {
+            EstimatedValue::None => Value::None,
+            EstimatedValue::Unit => Value::Unit,
+            EstimatedValue::Bool(bool) => Value::Bool(*bool),
+            EstimatedValue::Integer(integer) => Value::Integer(*integer),
+            EstimatedValue::Float(float) => Value::Float(*float),
+            EstimatedValue::Bytes(bytes) => Value::Bytes(Cow::Owned(bytes.to_vec())),
+            EstimatedValue::String(string) => Value::String(Cow::Owned((*string).to_string())),
+            EstimatedValue::Sequence(sequence) => {
+                Value::Sequence(sequence.iter().map(Self::to_value).collect())
+            }
+            EstimatedValue::Mappings(mappings) => Value::Mappings(
+                mappings
+                    .iter()
+                    .map(|(key, value)| (key.to_value(), value.to_value()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<'a> PartialEq<Value<'a>> for Estimated<'_> {
+    fn eq(&self, other: &Value<'a>) -> bool {
+        match (&self.value, other) {
+            (EstimatedValue::None, Value::None) | (EstimatedValue::Unit, Value::Unit) => true,
+            (EstimatedValue::Bool(a), Value::Bool(b)) => a == b,
+            (EstimatedValue::Integer(a), Value::Integer(b)) => a == b,
+            (EstimatedValue::Float(a), Value::Float(b)) => a == b,
+            (EstimatedValue::Bytes(a), Value::Bytes(b)) => *a == &**b,
+            (EstimatedValue::String(a), Value::String(b)) => *a == &**b,
+            (EstimatedValue::Sequence(a), Value::Sequence(b)) => a == b,
+            (EstimatedValue::Mappings(a), Value::Mappings(b)) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(a, b)| a.0 == b.0 && a.1 == b.1)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum EstimatedValue<'u> {
+    /// A value representing None.
+    None,
+    /// A value representing a Unit (`()`).
+    Unit,
+    /// A boolean value
+    Bool(bool),
+    /// An integer value.
+    Integer(Integer),
+    /// A floating point value.
+    Float(Float),
+    /// A value containing arbitrary bytes.
+    Bytes(&'u [u8]),
+    /// A string value.
+    String(&'u str),
+    /// A sequence of values.
+    Sequence(Vec<Estimated<'u>>),
+    /// A sequence of key-value mappings.
+    Mappings(VecDeque<(Estimated<'u>, Estimated<'u>)>),
+}
+
+/// A run of consecutive replacements. Runs of more than one replacement are
+/// logged as a single range change, which avoids repeating the change header
+/// and index for each value.
+struct ReplacementRun<T> {
+    index: usize,
+    replacements: Vec<T>,
+    cost: usize,
+}
+
+impl<T> Default for ReplacementRun<T> {
+    fn default() -> Self {
+        Self {
+            index: 0,
+            replacements: Vec::new(),
+            cost: 0,
+        }
+    }
+}
+
+impl<T> ReplacementRun<T>
+where
+    T: Replacement,
+{
+    /// Adds a replacement of the value at `index`. If `index` does not
+    /// continue the current run, the current run is logged first.
+    fn push<S: ChangeSink>(
+        &mut self,
+        index: usize,
+        replacement: T,
+        cost: usize,
+        context: &Context<'_>,
+        diff: &mut Changes<'_, S>,
+    ) {
+        if self.index + self.replacements.len() != index {
+            self.finish(context, diff);
+        }
+        if self.replacements.is_empty() {
+            self.index = index;
+        }
+        self.replacements.push(replacement);
+        self.cost = self.cost.saturating_add(cost);
+    }
+
+    /// Logs the current run, if any.
+    fn finish<S: ChangeSink>(&mut self, context: &Context<'_>, diff: &mut Changes<'_, S>) {
+        let index = self.index;
+        let mut replacements = std::mem::take(&mut self.replacements);
+        let cost = std::mem::take(&mut self.cost);
+        match replacements.len() {
+            0 => {}
+            1 => diff.log_change(
+                T::REPLACE,
+                cost + context.argument(index),
+                T::replace(index, replacements.pop().expect("length checked")),
+            ),
+            length => diff.log_change(
+                T::REPLACE_RANGE,
+                cost + context.argument(index) + context.argument(length),
+                T::replace_range(index, replacements),
+            ),
+        }
+    }
+}
+
+trait Replacement: Sized {
+    const REPLACE: ChangeKind;
+    const REPLACE_RANGE: ChangeKind;
+
+    fn replace(index: usize, replacement: Self) -> Change;
+    fn replace_range(index: usize, replacements: Vec<Self>) -> Change;
+}
+
+impl Replacement for &Estimated<'_> {
+    const REPLACE: ChangeKind = ChangeKind::Replace;
+    const REPLACE_RANGE: ChangeKind = ChangeKind::ReplaceRange;
+
+    fn replace(index: usize, replacement: Self) -> Change {
+        Change::Replace {
+            index: Some(index),
+            value: replacement.to_value(),
+        }
+    }
+
+    fn replace_range(index: usize, replacements: Vec<Self>) -> Change {
+        Change::ReplaceRange {
+            index,
+            values: replacements.into_iter().map(Estimated::to_value).collect(),
+        }
+    }
+}
+
+impl Replacement for &(Estimated<'_>, Estimated<'_>) {
+    const REPLACE: ChangeKind = ChangeKind::ReplaceMapping;
+    const REPLACE_RANGE: ChangeKind = ChangeKind::ReplaceMappingRange;
+
+    fn replace(index: usize, (key, value): Self) -> Change {
+        Change::ReplaceMapping {
+            index,
+            key: key.to_value(),
+            value: value.to_value(),
+        }
+    }
+
+    fn replace_range(index: usize, replacements: Vec<Self>) -> Change {
+        Change::ReplaceMappingRange {
+            index,
+            mappings: replacements
+                .into_iter()
+                .map(|(key, value)| (key.to_value(), value.to_value()))
+                .collect(),
+        }
+    }
+}
+
+/// A run of consecutive values that can be moved from the original list
+/// rather than being inserted.
+struct MoveRun {
+    original: usize,
+    updated: usize,
+    length: usize,
+    cost: usize,
+}
+
+/// Identifies the values of two sequences being diffed, which determines the
+/// values that are matched with each other.
+struct Identities<'a, 'o, 'u> {
+    original: &'a [Value<'o>],
+    original_hashes: &'a [HashTree],
+    updated: &'a [Estimated<'u>],
+    /// The keys of the values when values are matched by key. Values without
+    /// a key are identified by their entire value.
+    keys: Option<Keys<'a>>,
+}
+
+/// The keys of the values of an original and an updated sequence.
+struct Keys<'a> {
+    original: Vec<Option<Key<'a>>>,
+    updated: Vec<Option<Key<'a>>>,
+}
+
+/// A key that identifies a value in a sequence, along with its hash.
+type Key<'a> = (Cow<'a, Value<'a>>, u64);
+
+impl<'a, 'o, 'u> Identities<'a, 'o, 'u> {
+    fn new(
+        key: Option<SequenceKey<'_>>,
+        original: &'a [Value<'o>],
+        original_hashes: &'a [HashTree],
+        updated: &'a [Estimated<'u>],
+    ) -> Self {
+        Self {
+            original,
+            original_hashes,
+            updated,
+            keys: key.map(|key| Keys {
+                original: original.iter().map(|value| key.extract(value)).collect(),
+                updated: updated
+                    .iter()
+                    .map(|value| key.extract(value.source))
+                    .collect(),
+            }),
+        }
+    }
+
+    /// Returns true if values with the same identity are always equal.
+    fn are_values(&self) -> bool {
+        self.keys.is_none()
+    }
+
+    /// Returns the hash of the identity of the original value at `index`.
+    fn original_hash(&self, index: usize) -> u64 {
+        self.keys
+            .as_ref()
+            .and_then(|keys| keys.original[index].as_ref())
+            .map_or(self.original_hashes[index].hash, |(_, hash)| *hash)
+    }
+
+    /// Returns the hash of the identity of the updated value at `index`.
+    fn updated_hash(&self, index: usize) -> u64 {
+        self.keys
+            .as_ref()
+            .and_then(|keys| keys.updated[index].as_ref())
+            .map_or(self.updated[index].hash, |(_, hash)| *hash)
+    }
+
+    /// Returns true if the original value at `original` and the updated value
+    /// at `updated` have the same identity.
+    fn matches(&self, original: usize, updated: usize, context: &Context<'_>) -> bool {
+        match self
+            .keys
+            .as_ref()
+            .map(|keys| (&keys.original[original], &keys.updated[updated]))
+        {
+            Some((Some((original_key, original_hash)), Some((updated_key, updated_hash)))) => {
+                context.budget.compare();
+                original_hash == updated_hash && original_key == updated_key
+            }
+            None | Some((None, None)) => context.equal(
+                &self.original[original],
+                self.original_hashes[original].hash,
+                &self.updated[updated],
+            ),
+            Some(_) => false,
+        }
+    }
+}
+
+/// How the values of a sequence are identified when they are matched by key.
+#[derive(Clone, Copy)]
+enum SequenceKey<'a> {
+    /// The key is the value of the entry with this key in mappings.
+    Field(&'a str),
+    /// The key is returned by an extractor.
+    Extractor(&'a KeyExtractor),
+}
+
+impl SequenceKey<'_> {
+    /// Returns the key of `value` along with its hash, if it has one.
+    fn extract<'v>(&self, value: &'v Value<'v>) -> Option<Key<'v>> {
+        let key = match self {
+            Self::Field(field) => match value {
+                Value::Mappings(mappings) => mappings
+                    .iter()
+                    .find(|(key, _)| matches!(key, Value::String(key) if key == field))
+                    .map(|(_, value)| Cow::Borrowed(value)),
+                _ => None,
+            },
+            Self::Extractor(extractor) => (extractor.0)(value).map(Cow::Owned),
+        };
+        key.map(|key| {
+            let hash = HashTree::new(&key).hash;
+            (key, hash)
+        })
+    }
+}
+
+/// A step in transforming the entries of an original mapping into the entries
+/// of an updated mapping. Indices refer to the entries of the original and
+/// updated mappings.
+#[derive(Clone, Copy)]
+enum MapAlignment {
+    /// Original entries are removed before the updated entry at `index`.
+    Removed { index: usize, length: usize },
+    /// The entries have equal keys.
+    Matched { original: usize, updated: usize },
+    /// The original entry is replaced by the updated entry, which has a
+    /// different key.
+    Replaced { original: usize, updated: usize },
+    /// The updated entry is inserted.
+    Inserted { updated: usize },
+    /// The remaining original entries are removed.
+    Truncated { length: usize },
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum Error {
-    #[error("error deserializing Value: {0}")]
-    ValueDeserialization(#[from] pot::ValueError),
+/// Options that control the trade-off between the time spent creating a
+/// [`Diff`] and the size of the resulting diff.
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    max_depth: Option<usize>,
+    max_lookahead: Option<usize>,
+    replace_ratio: f64,
+    max_changes: Option<usize>,
+    cost_model: Arc<dyn CostModel>,
+    confirm_hash_matches: bool,
+    max_comparisons: Option<usize>,
+    time_limit: Option<Duration>,
+    unordered_mappings: bool,
+    policies: Vec<(Pattern, PathPolicy)>,
+    sequence_key: Option<KeyExtractor>,
+    similarity_threshold: f64,
+    tree_edit_distance: Option<usize>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Change {
-    EnterSequence {
-        index: Option<usize>,
-        key: bool,
-    },
-    EnterMap {
-        index: Option<usize>,
-        key: bool,
-    },
-    Exit,
-    Replace {
-        index: Option<usize>,
-        value: Value<'static>,
-    },
-    ReplaceKey {
-        index: usize,
-        key: Value<'static>,
-    },
-    ReplaceMapping {
-        index: usize,
-        key: Value<'static>,
-        value: Value<'static>,
-    },
-    Remove {
-        index: usize,
-        length: usize,
-    },
-    Truncate {
-        length: usize,
-    },
-    Insert {
-        index: usize,
-        value: Value<'static>,
-    },
-    InsertMapping {
-        index: usize,
-        key: Value<'static>,
-        value: Value<'static>,
-    },
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            max_lookahead: None,
+            replace_ratio: 1.,
+            max_changes: None,
+            cost_model: Arc::new(EncodedSize),
+            confirm_hash_matches: false,
+            max_comparisons: None,
+            time_limit: None,
+            unordered_mappings: false,
+            policies: Vec::new(),
+            sequence_key: None,
+            similarity_threshold: 0.5,
+            tree_edit_distance: None,
+        }
+    }
+}
+
+impl DiffOptions {
+    /// Limits how many nested sequences and mappings are entered. Changed
+    /// containers nested deeper than `max_depth` are replaced.
+    ///
+    /// A depth of 0 replaces the value being diffed if it is a container that
+    /// changed.
+    #[must_use]
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Limits how far ahead the differ searches for matching values when
+    /// diffing sequences, strings, and the keys of mappings. Values that can
+    /// only be matched beyond this distance are removed and inserted instead.
+    #[must_use]
+    pub fn max_lookahead(mut self, max_lookahead: usize) -> Self {
+        self.max_lookahead = Some(max_lookahead);
+        self
+    }
+
+    /// Sets the ratio of a value's size at which it is replaced rather than
+    /// diffed. A value is replaced when its estimated diff is larger than
+    /// `ratio` times its size. Defaults to 1.
+    ///
+    /// Values less than 1 prefer replacing values, which produces diffs that
+    /// are faster to apply.
+    #[must_use]
+    pub fn replace_ratio(mut self, ratio: f64) -> Self {
+        self.replace_ratio = ratio;
+        self
+    }
+
+    /// Limits the number of changes in a diff. If a diff would have more than
+    /// `max_changes` changes, the entire value is replaced instead.
+    ///
+    /// This is a cap on the whole diff, which is only checked once the root
+    /// value has been diffed. The values within it are not limited
+    /// individually, so a diff of a large value still does all of the work of
+    /// diffing its contents before it may be replaced. Use
+    /// [`DiffOptions::max_comparisons()`] or [`DiffOptions::time_limit()`] to
+    /// bound that work.
+    #[must_use]
+    pub fn max_changes(mut self, max_changes: usize) -> Self {
+        self.max_changes = Some(max_changes);
+        self
+    }
+
+    /// Sets the model used to compare the cost of the possible changes.
+    /// Defaults to [`EncodedSize`].
+    #[must_use]
+    pub fn cost_model<M: CostModel + 'static>(mut self, cost_model: M) -> Self {
+        self.cost_model = Arc::new(cost_model);
+        self
+    }
+
+    /// Sets whether values with matching structural hashes are compared
+    /// before being treated as equal. Defaults to false.
+    ///
+    /// Trusting matching hashes lets unchanged subtrees be skipped without
+    /// visiting their contents, so a diff takes time proportional to what
+    /// changed rather than to the size of the values. The risk is that a hash
+    /// collision makes a changed value look unchanged, and the diff then
+    /// silently leaves it out. Hashes are 64 bits, so distinct values are
+    /// very unlikely to share a hash by chance. They aren't keyed, though,
+    /// so colliding values can be crafted on purpose. Enable this when the
+    /// values being diffed are untrusted, which guarantees that a collision
+    /// can never produce an incorrect diff at the cost of comparing every
+    /// matching subtree.
+    #[must_use]
+    pub fn confirm_hash_matches(mut self, confirm: bool) -> Self {
+        self.confirm_hash_matches = confirm;
+        self
+    }
+
+    /// Limits the number of comparisons between values made while creating a
+    /// diff. Once the limit is reached, sequences, mappings, and strings that
+    /// haven't been diffed yet are replaced rather than diffed.
+    ///
+    /// The resulting diff is always correct, but may be larger than it would
+    /// be without the limit. Because which values are replaced depends on the
+    /// order the comparisons are made in, values are never diffed in parallel
+    /// when this limit is set, which keeps the diff the same on every run.
+    #[must_use]
+    pub fn max_comparisons(mut self, max_comparisons: usize) -> Self {
+        self.max_comparisons = Some(max_comparisons);
+        self
+    }
+
+    /// Limits how long creating a diff may take. Once `time_limit` has
+    /// elapsed, sequences, mappings, and strings that haven't been diffed yet
+    /// are replaced rather than diffed.
+    ///
+    /// The resulting diff is always correct, but may be larger than it would
+    /// be without the limit. Which values are replaced depends on how quickly
+    /// the diff is created, so the diff can differ from run to run.
+    #[must_use]
+    pub fn time_limit(mut self, time_limit: Duration) -> Self {
+        self.time_limit = Some(time_limit);
+        self
+    }
+
+    /// Sets whether the order of the entries in mappings is ignored. Defaults
+    /// to false.
+    ///
+    /// Types such as `HashMap` serialize their entries in an unpredictable
+    /// order. When enabled, entries are matched by their keys regardless of
+    /// their positions, and the diff only contains the entries that were
+    /// inserted, removed, or changed. These changes locate entries by their
+    /// keys, so applying the diff doesn't depend on the order of the entries
+    /// either.
+    #[must_use]
+    pub fn unordered_mappings(mut self, unordered: bool) -> Self {
+        self.unordered_mappings = unordered;
+        self
+    }
+
+    /// Diffs the values at the paths matching `pattern` according to
+    /// `policy`. If several patterns match a path, the policy that was added
+    /// first is used.
+    ///
+    /// A pattern lists the keys of the mappings and the indices of the
+    /// sequences that lead to a value from the root value, separated by `.`,
+    /// such as `items.0.id`. `*` matches any single key or index, and `**`
+    /// matches any number of them, so `**.updated_at` matches every
+    /// `updated_at` field. An empty pattern matches the root value.
+    #[must_use]
+    pub fn policy(mut self, pattern: &str, policy: PathPolicy) -> Self {
+        self.policies.push((Pattern::parse(pattern), policy));
+        self
+    }
+
+    /// Matches the values of sequences by the keys `extractor` returns for
+    /// them, like [`PathPolicy::KeyedBy`], unless a policy applies to the
+    /// sequence.
+    ///
+    /// Values without a key are still matched by their entire value, so
+    /// sequences of other values are diffed as usual.
+    #[must_use]
+    pub fn sequence_key(mut self, extractor: KeyExtractor) -> Self {
+        self.sequence_key = Some(extractor);
+        self
+    }
+
+    /// Sets how similar two values in a sequence must be to be diffed with
+    /// each other when they aren't otherwise matched. Defaults to 0.5.
+    ///
+    /// The similarity of two values is the fraction of their scalar values
+    /// that they share, where each scalar is identified along with the keys
+    /// of the mappings leading to it. Similar values are diffed rather than
+    /// removed and inserted, which keeps the diffs of records that changed
+    /// slightly small. A threshold above 1 pairs unmatched values by position
+    /// only.
+    #[must_use]
+    pub fn similarity_threshold(mut self, threshold: f64) -> Self {
+        self.similarity_threshold = threshold;
+        self
+    }
+
+    /// Also diffs values with at most `max_nodes` values in total using the
+    /// edit distance between them as trees, keeping whichever diff is
+    /// smaller.
+    ///
+    /// The usual diff only compares values at the same location, so a value
+    /// that was wrapped in a new container, or whose container was removed,
+    /// is replaced. The tree edit distance finds such restructurings, and the
+    /// diff copies the moved containers from the original value before
+    /// changing them. Finding the distance takes time and memory quadratic in
+    /// the number of values, so it is skipped for larger values. It is also
+    /// skipped when any policies are set or mappings are unordered, and by
+    /// [`Diff::write_between()`].
+    ///
+    /// Each pair of values compared while finding the distance counts
+    /// towards [`DiffOptions::max_comparisons()`], and the usual diff is kept
+    /// if the comparisons or the [`DiffOptions::time_limit()`] run out before
+    /// the distance is found. The usual diff is also kept if the restructured
+    /// diff enters containers nested deeper than
+    /// [`DiffOptions::max_depth()`].
+    #[must_use]
+    pub fn tree_edit_distance(mut self, max_nodes: usize) -> Self {
+        self.tree_edit_distance = Some(max_nodes);
+        self
+    }
 }
 
-trait Differ {
-    fn log_change<F: FnOnce() -> Change>(&mut self, estimated_bytes: usize, change: F);
+/// How the values at the paths matching a pattern passed to
+/// [`DiffOptions::policy()`] are diffed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathPolicy {
+    /// Changes to the value are left out of the diff. The value is still
+    /// compared when aligning the contents of the containers it is in, and is
+    /// included when a container holding it is inserted or replaced.
+    Ignore,
+    /// The value is replaced whenever it changes rather than being diffed.
+    Replace,
+    /// The value is a sequence whose order is insignificant, and whose
+    /// distinct values are its members. The diff only contains the members
+    /// that were removed and added, which are located by their values, so
+    /// applying it doesn't depend on the order of the sequence it is applied
+    /// to. Members that were already added or removed are left as they are,
+    /// allowing the diffs of concurrent edits to be applied in either order.
+    Set,
+    /// The value is a sequence of mappings that are matched by the value of
+    /// the entry with this key, such as an `id` field, rather than by their
+    /// entire value. Matching mappings are moved into place and diffed with
+    /// each other. Values without the entry are matched by their entire
+    /// value.
+    Keyed(String),
+    /// The value is a sequence whose values are matched by the keys the
+    /// extractor returns for them, like [`PathPolicy::Keyed`]. Values without
+    /// a key are matched by their entire value.
+    KeyedBy(KeyExtractor),
 }
 
-#[derive(Default)]
-struct Counter {
-    estimated_bytes: usize,
+/// Extracts keys that identify the values of a sequence, such as the `id` of
+/// each record in a list. Values with equal keys are matched with each other
+/// and diffed, even when other parts of the values differ.
+#[derive(Clone)]
+pub struct KeyExtractor(Arc<ExtractKey>);
+
+type ExtractKey = dyn Fn(&Value<'_>) -> Option<Value<'static>> + Send + Sync;
+
+impl KeyExtractor {
+    /// Returns an extractor that invokes `extract` with each value of a
+    /// sequence. Values that `extract` returns None for have no key.
+    pub fn new<F>(extract: F) -> Self
+    where
+        F: Fn(&Value<'_>) -> Option<Value<'static>> + Send + Sync + 'static,
+    {
+        Self(Arc::new(extract))
+    }
 }
 
-impl Differ for Counter {
-    fn log_change<F: FnOnce() -> Change>(&mut self, estimated_bytes: usize, _change: F) {
-        self.estimated_bytes += 1 + estimated_bytes;
+impl Debug for KeyExtractor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("KeyExtractor").finish_non_exhaustive()
     }
 }
 
-impl Differ for Diff {
-    fn log_change<F: FnOnce() -> Change>(&mut self, _estimated_bytes: usize, change: F) {
-        self.changes.push(change());
+impl PartialEq for KeyExtractor {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
     }
 }
 
-#[derive(Clone)]
-struct Estimated {
-    estimated_bytes: usize,
-    value: EstimatedValue,
+impl Eq for KeyExtractor {}
+
+/// Measures the cost of changes and values, which the differ uses to choose
+/// between changes that produce the same result, such as whether to diff a
+/// value or to replace it.
+///
+/// Sequences are aligned using the fewest changes rather than the cheapest
+/// ones, so a diff isn't always the smallest possible. When values differ in
+/// size, an alignment with more changes to smaller values can cost less.
+///
+/// The cost of a change is the sum of [`operation()`](Self::operation) for the
+/// change and the cost of each of its arguments. Indices and lengths are
+/// measured using [`argument()`](Self::argument), values using
+/// [`scalar()`](Self::scalar) and [`container()`](Self::container), and
+/// inserted strings and bytes using [`raw_bytes()`](Self::raw_bytes).
+pub trait CostModel: Debug + Send + Sync {
+    /// Returns the cost of a change of `kind`, excluding its arguments.
+    fn operation(&self, kind: ChangeKind) -> usize;
+    /// Returns the cost of an index or length argument.
+    fn argument(&self, argument: usize) -> usize;
+    /// Returns the cost of bytes included verbatim in a change.
+    fn raw_bytes(&self, bytes: &[u8]) -> usize;
+    /// Returns the cost of a value that is not a sequence or mappings.
+    fn scalar(&self, value: &Value<'_>) -> usize;
+    /// Returns the cost of a sequence or mappings containing `length` entries,
+    /// excluding the cost of the entries.
+    fn container(&self, kind: ContainerKind, length: usize) -> usize;
+}
+
+/// A [`CostModel`] that measures the number of bytes [`Diff::serialize`]
+/// produces.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EncodedSize;
+
+impl CostModel for EncodedSize {
+    fn operation(&self, _kind: ChangeKind) -> usize {
+        1
+    }
+
+    fn argument(&self, argument: usize) -> usize {
+        binary::argument_len(argument)
+    }
+
+    fn raw_bytes(&self, bytes: &[u8]) -> usize {
+        bytes.len()
+    }
+
+    fn scalar(&self, value: &Value<'_>) -> usize {
+        binary::value_len(value)
+    }
+
+    fn container(&self, kind: ContainerKind, length: usize) -> usize {
+        binary::container_header_len(kind, length)
+    }
+}
+
+/// The kind of a [`Change`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    EnterSequence,
+    EnterMap,
+    Exit,
+    Replace,
+    ReplaceKey,
+    ReplaceMapping,
+    ReplaceRange,
+    ReplaceMappingRange,
+    Remove,
+    Truncate,
+    Move,
+    Splice,
+    Delta,
+    Add,
+    Copy,
+    InsertCopy,
+    Insert,
+    InsertMapping,
+    EnterKey,
+    SetKey,
+    RemoveKey,
+    InsertMember,
+    RemoveMember,
 }
 
-impl Estimated {
-    fn new(commands: usize, data_bytes: usize, value: EstimatedValue) -> Self {
+/// The kind of a container value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContainerKind {
+    Sequence,
+    Mappings,
+}
+
+/// State shared by every step of creating a diff.
+#[derive(Clone, Copy)]
+struct Context<'a> {
+    original: &'a Value<'a>,
+    subtrees: &'a SubtreeIndex<'a>,
+    options: &'a DiffOptions,
+    budget: &'a Budget,
+    /// The number of containers entered to reach the value being diffed.
+    depth: usize,
+    /// The location of the value being diffed, which is None for the root
+    /// value.
+    path: Option<&'a Path<'a>>,
+}
+
+impl<'a> Context<'a> {
+    /// Returns the context for the values within a container at this depth.
+    fn entered(&self) -> Self {
         Self {
-            estimated_bytes: commands + data_bytes,
-            value,
+            depth: self.depth + 1,
+            ..*self
+        }
+    }
+
+    /// Returns the context for diffing the value at `path`.
+    fn at<'b>(&self, path: &'b Path<'b>) -> Context<'b>
+    where
+        'a: 'b,
+    {
+        Context {
+            path: Some(path),
+            ..*self
+        }
+    }
+
+    /// Returns the policy for the value being diffed, if a pattern passed to
+    /// [`DiffOptions::policy()`] matches its path.
+    fn policy(&self) -> Option<&'a PathPolicy> {
+        self.options
+            .policies
+            .iter()
+            .find(|(pattern, _)| pattern.matches(self.path))
+            .map(|(_, policy)| policy)
+    }
+
+    fn costs(&self) -> &'a dyn CostModel {
+        &*self.options.cost_model
+    }
+
+    fn argument(&self, argument: usize) -> usize {
+        self.options.cost_model.argument(argument)
+    }
+
+    /// Returns the cost of an argument that is only encoded when present,
+    /// such as the index of a change that may apply to the root value.
+    fn optional_argument(&self, argument: Option<usize>) -> usize {
+        argument.map_or(0, |argument| self.argument(argument))
+    }
+
+    fn can_enter(&self) -> bool {
+        self.options
+            .max_depth
+            .map_or(true, |max_depth| self.depth < max_depth)
+    }
+
+    /// Returns true if a value should be replaced rather than diffed, given
+    /// the cost of the diff and the kind and argument cost of the change that
+    /// replaces the value.
+    fn prefers_replacement(
+        &self,
+        diff_cost: usize,
+        replacement: ChangeKind,
+        replacement_arguments: usize,
+    ) -> bool {
+        let replacement_cost = self
+            .costs()
+            .operation(replacement)
+            .saturating_add(replacement_arguments);
+        diff_cost as f64 > replacement_cost as f64 * self.options.replace_ratio
+    }
+
+    /// Returns true if `updated` is equal to `original`, whose structural hash
+    /// is `original_hash`.
+    ///
+    /// Values with different hashes are never considered equal. Matching
+    /// hashes are only confirmed by comparing the values when
+    /// [`DiffOptions::confirm_hash_matches`] is enabled.
+    fn equal(&self, original: &Value<'_>, original_hash: u64, updated: &Estimated<'_>) -> bool {
+        self.budget.compare();
+        original_hash == updated.hash
+            && (!self.options.confirm_hash_matches || *updated == *original)
+    }
+
+    /// Returns true if the budget for creating the diff has been spent, after
+    /// which changed containers and strings are replaced.
+    fn is_exhausted(&self) -> bool {
+        self.budget.is_exhausted()
+    }
+
+    /// Returns the range of indices to search for a match, starting at
+    /// `start`.
+    fn lookahead(&self, start: usize) -> Range<usize> {
+        start..self.options.max_lookahead.map_or(usize::MAX, |lookahead| {
+            start.saturating_add(lookahead).saturating_add(1)
+        })
+    }
+
+    /// Returns the cost of referencing the subtree of the original value at
+    /// `source`.
+    fn copy_cost(&self, source: &[usize]) -> usize {
+        source
+            .iter()
+            .fold(self.argument(source.len()), |cost, index| {
+                cost.saturating_add(self.argument(*index))
+            })
+    }
+
+    /// Returns the location of a subtree of the original value that is equal
+    /// to `updated`, if referencing it is cheaper than encoding `updated`.
+    fn find_copy(&self, updated: &Estimated<'_>) -> Option<CopySource<'_>> {
+        if !matches!(
+            updated.value,
+            EstimatedValue::Bytes(_)
+                | EstimatedValue::String(_)
+                | EstimatedValue::Sequence(_)
+                | EstimatedValue::Mappings(_)
+        ) {
+            return None;
         }
+        let paths = self.subtrees.get(updated.hash)?;
+        paths
+            .iter()
+            .filter(|path| {
+                value_at(self.original, path)
+                    .map_or(false, |value| self.equal(value, updated.hash, updated))
+            })
+            .map(|path| CopySource {
+                source: path,
+                cost: self.copy_cost(path),
+            })
+            .min_by_key(|copy| copy.cost)
+            .filter(|copy| copy.cost < updated.cost)
     }
 }
 
-impl<'a> PartialEq<Value<'a>> for Estimated {
-    fn eq(&self, other: &Value<'a>) -> bool {
-        match (&self.value, other) {
-            (EstimatedValue::None, Value::None) | (EstimatedValue::Unit, Value::Unit) => true,
-            (EstimatedValue::Bool(a), Value::Bool(b)) => a == b,
-            (EstimatedValue::Integer(a), Value::Integer(b)) => a == b,
-            (EstimatedValue::Float(a), Value::Float(b)) => a == b,
-            (EstimatedValue::Bytes(a), Value::Bytes(b)) => a == b,
-            (EstimatedValue::String(a), Value::String(b)) => a == b,
-            (EstimatedValue::Sequence(a), Value::Sequence(b)) => a == b,
-            (EstimatedValue::Mappings(a), Value::Mappings(b)) => a
-                .iter()
-                .zip(b.iter())
-                .all(|(a, b)| a.0 == b.0 && a.1 == b.1),
-            _ => false,
+/// The work allowed while creating a diff, as limited by
+/// [`DiffOptions::max_comparisons()`] and [`DiffOptions::time_limit()`].
+///
+/// The budget is shared by every value being diffed. Values are only diffed
+/// on other threads when the number of comparisons isn't limited, so the
+/// comparisons are always counted in the same order.
+struct Budget {
+    max_comparisons: Option<usize>,
+    deadline: Option<Instant>,
+    comparisons: AtomicUsize,
+    exhausted: AtomicBool,
+}
+
+impl Budget {
+    fn new(options: &DiffOptions) -> Self {
+        Self {
+            max_comparisons: options.max_comparisons,
+            deadline: options
+                .time_limit
+                .and_then(|limit| Instant::now().checked_add(limit)),
+            comparisons: AtomicUsize::new(0),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether `count` more comparisons can be made before the budget
+    /// is spent. The deadline isn't checked.
+    fn affords(&self, count: usize) -> bool {
+        !self.is_exhausted()
+            && self.max_comparisons.map_or(true, |max_comparisons| {
+                self.comparisons
+                    .load(Ordering::Relaxed)
+                    .checked_add(count)
+                    .map_or(false, |comparisons| comparisons <= max_comparisons)
+            })
+    }
+
+    /// Records a comparison between two values, marking the budget as
+    /// exhausted once it has been spent.
+    fn compare(&self) {
+        self.spend(1);
+    }
+
+    /// Records `count` comparisons between values, marking the budget as
+    /// exhausted once it has been spent.
+    fn spend(&self, count: usize) {
+        if self.max_comparisons.is_none() && self.deadline.is_none() {
+            return;
+        }
+
+        let previous = self.comparisons.fetch_add(count, Ordering::Relaxed);
+        let comparisons = previous.saturating_add(count);
+        // Reading the clock is slower than comparing most values, so the
+        // deadline is only checked periodically.
+        if self
+            .max_comparisons
+            .map_or(false, |max_comparisons| comparisons > max_comparisons)
+            || (previous / DEADLINE_CHECK_INTERVAL != comparisons / DEADLINE_CHECK_INTERVAL
+                && self
+                    .deadline
+                    .map_or(false, |deadline| Instant::now() >= deadline))
+        {
+            self.exhausted.store(true, Ordering::Relaxed);
         }
     }
 }
 
-#[derive(Clone)]
-enum EstimatedValue {
-    /// A value representing None.
-    None,
-    /// A value representing a Unit (`()`).
-    Unit,
-    /// A boolean value
-    Bool(bool),
-    /// An integer value.
-    Integer(Integer),
-    /// A floating point value.
-    Float(Float),
-    /// A value containing arbitrary bytes.
-    Bytes(Cow<'static, [u8]>),
-    /// A string value.
-    String(Cow<'static, str>),
-    /// A sequence of values.
-    Sequence(VecDeque<Estimated>),
-    /// A sequence of key-value mappings.
-    Mappings(VecDeque<(Estimated, Estimated)>),
+struct CopySource<'a> {
+    source: &'a [usize],
+    cost: usize,
+}
+
+/// An index of the paths of every subtree of the original value by their
+/// structural hashes.
+///
+/// Each path is a list of indices, with each index selecting a value in a
+/// sequence or the value of an entry in a mapping. The index is built by the
+/// first lookup, so diffs that never look for a copy don't pay for it.
+#[derive(Default)]
+struct SubtreeIndex<'a> {
+    /// The original value and its hashes, or None if nothing can be copied.
+    original: Option<(&'a Value<'a>, &'a HashTree)>,
+    paths: OnceLock<HashMap<u64, Vec<Vec<usize>>>>,
 }
 
-impl From<Value<'static>> for Estimated {
-    fn from(value: Value<'static>) -> Self {
+impl<'a> SubtreeIndex<'a> {
+    fn new(original: &'a Value<'a>, hashes: &'a HashTree) -> Self {
+        Self {
+            original: Some((original, hashes)),
+            paths: OnceLock::new(),
+        }
+    }
+
+    /// Returns the paths of the subtrees whose structural hash is `hash`.
+    fn get(&self, hash: u64) -> Option<&[Vec<usize>]> {
+        let (original, hashes) = self.original?;
+        self.paths
+            .get_or_init(|| {
+                let mut paths = HashMap::new();
+                Self::insert(&mut paths, original, hashes, &mut Vec::new());
+                paths
+            })
+            .get(&hash)
+            .map(Vec::as_slice)
+    }
+
+    fn insert(
+        paths: &mut HashMap<u64, Vec<Vec<usize>>>,
+        value: &Value<'_>,
+        hashes: &HashTree,
+        path: &mut Vec<usize>,
+    ) {
         match value {
-            Value::None => Self::new(1, 0, EstimatedValue::None),
-            Value::Unit => Self::new(1, 0, EstimatedValue::Unit),
-            Value::Bool(bool) => Self::new(1, 1, EstimatedValue::Bool(bool)),
-            Value::Integer(integer) => {
-                Self::new(1, integer_size(integer), EstimatedValue::Integer(integer))
-            }
-            Value::Float(float) => Self::new(
-                1,
-                if float.as_f32().is_ok() { 4 } else { 8 },
-                EstimatedValue::Float(float),
-            ),
-            Value::Bytes(bytes) => Self::new(1, bytes.len(), EstimatedValue::Bytes(bytes)),
-            Value::String(string) => Self::new(1, string.len(), EstimatedValue::String(string)),
             Value::Sequence(values) => {
-                let values: VecDeque<Self> = values.into_iter().map(Self::from).collect();
-                Self::new(
-                    values.len() + 1,
-                    values.iter().map(|v| v.estimated_bytes).sum::<usize>(),
-                    EstimatedValue::Sequence(values),
-                )
+                for (index, (value, hashes)) in values.iter().zip(hashes.sequence()).enumerate() {
+                    path.push(index);
+                    Self::insert(paths, value, hashes, path);
+                    path.pop();
+                }
             }
             Value::Mappings(mappings) => {
-                let mappings: VecDeque<(Self, Self)> = mappings
-                    .into_iter()
-                    .map(|(key, value)| (Self::from(key), Self::from(value)))
-                    .collect();
-                Self::new(
-                    mappings.len() * 2 + 1,
-                    mappings
-                        .iter()
-                        .map(|(key, value)| key.estimated_bytes + value.estimated_bytes)
-                        .sum::<usize>(),
-                    EstimatedValue::Mappings(mappings),
-                )
+                for (index, ((_, value), (_, hashes))) in
+                    mappings.iter().zip(hashes.mappings()).enumerate()
+                {
+                    path.push(index);
+                    Self::insert(paths, value, hashes, path);
+                    path.pop();
+                }
             }
+            _ => {}
         }
-    }
-}
-impl From<Estimated> for Value<'static> {
-    fn from(value: Estimated) -> Self {
-        Self::from(value.value)
-    }
-}
 
-impl From<EstimatedValue> for Value<'static> {
-    fn from(value: EstimatedValue) -> Self {
-        match value {
-            EstimatedValue::None => Value::None,
-            EstimatedValue::Unit => Value::Unit,
-            EstimatedValue::Bool(bool) => Value::Bool(bool),
-            EstimatedValue::Integer(integer) => Value::Integer(integer),
-            EstimatedValue::Float(float) => Value::Float(float),
-            EstimatedValue::Bytes(bytes) => Value::Bytes(bytes),
-            EstimatedValue::String(string) => Value::String(string),
-            EstimatedValue::Sequence(sequence) => {
-                Value::Sequence(sequence.into_iter().map(Self::from).collect())
-            }
-            EstimatedValue::Mappings(mappings) => Value::Mappings(
-                mappings
-                    .into_iter()
-                    .map(|(key, value)| (Self::from(key), Self::from(value)))
-                    .collect(),
-            ),
+        // Scalars are always cheaper to encode than to reference.
+        if matches!(
+            value,
+            Value::Bytes(_) | Value::String(_) | Value::Sequence(_) | Value::Mappings(_)
+        ) {
+            paths.entry(hashes.hash).or_default().push(path.clone());
         }
     }
 }
 
-fn integer_size(integer: Integer) -> usize {
-    if integer.as_i8().is_ok() || integer.as_u8().is_ok() {
-        1
-    } else if integer.as_i16().is_ok() || integer.as_u16().is_ok() {
-        2
-    } else if integer.as_i32().is_ok() || integer.as_u32().is_ok() {
-        4
-    } else if integer.as_i64().is_ok() || integer.as_u64().is_ok() {
-        8
-    } else {
-        16
-    }
+/// Maps each item using `diff`, lazily returning the results in the same
+/// order.
+///
+/// Items are diffed in batches, which limits how many results are held at
+/// once. When the `parallel` feature is enabled and the `work` of a batch is
+/// large enough to outweigh the overhead, the batch is spread across threads,
+/// unless [`DiffOptions::max_comparisons()`] is set. Because each result then
+/// only depends on its item, the results are identical either way.
+fn diff_each<'i, T, R, W, F>(
+    items: &'i [T],
+    context: &'i Context<'_>,
+    work: W,
+    diff: F,
+) -> impl Iterator<Item = R> + 'i
+where
+    T: Sync,
+    R: Send + 'i,
+    W: Fn(&T) -> usize + 'i,
+    F: Fn(&T) -> R + Send + Sync + 'i,
+{
+    items.chunks(DIFF_BATCH_SIZE).flat_map(move |batch| {
+        let work = batch.iter().map(&work).fold(0, usize::saturating_add);
+        diff_batch(batch, context, work, &diff)
+    })
 }
 
-enum CowDeque<'a, T> {
-    Owned(VecDeque<T>),
-    Borrowed {
-        deque: &'a VecDeque<T>,
-        index: usize,
-    },
+#[cfg(feature = "parallel")]
+fn diff_batch<T, R, F>(batch: &[T], context: &Context<'_>, work: usize, diff: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Send + Sync,
+{
+    use rayon::prelude::*;
+
+    // Comparisons are only counted in a predictable order on a single thread.
+    let counts_comparisons = context.options.max_comparisons.is_some();
+    if work >= PARALLEL_MINIMUM_WORK && batch.len() > 1 && !counts_comparisons {
+        batch.par_iter().map(diff).collect()
+    } else {
+        batch.iter().map(diff).collect()
+    }
 }
 
-impl<'a, T> CowDeque<'a, T>
+#[cfg(not(feature = "parallel"))]
+fn diff_batch<T, R, F>(batch: &[T], _context: &Context<'_>, _work: usize, diff: F) -> Vec<R>
 where
-    T: Clone,
+    T: Sync,
+    F: Fn(&T) -> R + Send + Sync,
 {
-    fn pop_front(&mut self) -> Option<Cow<'a, T>> {
-        match self {
-            CowDeque::Owned(queue) => queue.pop_front().map(Cow::Owned),
-            CowDeque::Borrowed { deque, index } => {
-                if let Some(borrowed) = deque.get(*index) {
-                    *index += 1;
-                    Some(Cow::Borrowed(borrowed))
-                } else {
-                    None
-                }
-            }
-        }
+    batch.iter().map(diff).collect()
+}
+
+/// Returns the value located at `path` within `value`.
+fn value_at<'v, 'a>(value: &'v Value<'a>, path: &[usize]) -> Option<&'v Value<'a>> {
+    path.iter().try_fold(value, |value, index| match value {
+        Value::Sequence(values) => values.get(*index),
+        Value::Mappings(mappings) => mappings.get(*index).map(|(_, value)| value),
+        _ => None,
+    })
+}
+
+/// Returns the number of bytes at the start and at the end of two strings that
+/// are unchanged, at character boundaries.
+fn common_text_affixes(original: &str, updated: &str) -> (usize, usize) {
+    let (original_bytes, updated_bytes) = (original.as_bytes(), updated.as_bytes());
+    let (mut prefix, mut suffix) = myers::common_affixes(
+        original_bytes.len(),
+        updated_bytes.len(),
+        |original, updated| original_bytes[original] == updated_bytes[updated],
+    );
+    while !original.is_char_boundary(prefix) || !updated.is_char_boundary(prefix) {
+        prefix -= 1;
     }
+    while !original.is_char_boundary(original.len() - suffix)
+        || !updated.is_char_boundary(updated.len() - suffix)
+    {
+        suffix -= 1;
+    }
+    (prefix, suffix)
+}
 
-    fn iter(&self) -> iter::Skip<vec_deque::Iter<'_, T>> {
-        match self {
-            CowDeque::Owned(queue) => queue.iter().skip(0),
-            CowDeque::Borrowed { deque, index } => deque.iter().skip(*index),
-        }
+/// An index of hashed keys to the positions they can be found at.
+#[derive(Default)]
+struct KeyIndex(HashMap<u64, Vec<usize>>);
+
+impl KeyIndex {
+    fn insert(&mut self, hash: u64, index: usize) {
+        self.0.entry(hash).or_default().push(index);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Removes and returns the first index whose key has `hash` and satisfies
+    /// `is_match`.
+    fn take(&mut self, hash: u64, mut is_match: impl FnMut(usize) -> bool) -> Option<usize> {
+        let indices = self.0.get_mut(&hash)?;
+        let position = indices.iter().position(|index| is_match(*index))?;
+        Some(indices.remove(position))
+    }
+
+    /// Returns the first index within `range` whose key has `hash` and
+    /// satisfies `is_match`.
+    fn find(
+        &self,
+        hash: u64,
+        range: Range<usize>,
+        mut is_match: impl FnMut(usize) -> bool,
+    ) -> Option<usize> {
+        let indices = self.0.get(&hash)?;
+        let first = indices.partition_point(|index| *index < range.start);
+        indices[first..]
+            .iter()
+            .copied()
+            .take_while(|index| *index < range.end)
+            .find(|index| is_match(*index))
     }
 }
 
@@ -893,6 +3604,10 @@ pub struct Diffable<T> {
     active: T,
     dirty: bool,
     latest: Value<'static>,
+    /// The hashes of `latest`, which are kept so that they are only computed
+    /// once for each value.
+    latest_hashes: HashTree,
+    options: DiffOptions,
 }
 
 impl<T> Diffable<T>
@@ -900,11 +3615,17 @@ where
     T: Serialize + DeserializeOwned,
 {
     pub fn new(value: T) -> Self {
+        Self::with_options(value, DiffOptions::default())
+    }
+
+    pub fn with_options(value: T, options: DiffOptions) -> Self {
         let latest = Value::from_serialize(&value);
         Self {
+            latest_hashes: HashTree::new(&latest),
             latest,
             active: value,
             dirty: false,
+            options,
         }
     }
 
@@ -913,9 +3634,10 @@ where
             self.dirty = false;
             // TODO make a Value method to recycle buffers yet reload from a Serialize.
             let updated = Value::from_serialize(&self.active);
-            // TODO this shouldn't be a clone.
-            let diff = Diff::between_values(&self.latest, updated.clone());
+            let (diff, hashes) =
+                Diff::create_hashed(&self.latest, &self.latest_hashes, &updated, &self.options);
             self.latest = updated;
+            self.latest_hashes = hashes;
             if diff.changes.is_empty() {
                 None
             } else {
@@ -945,21 +3667,27 @@ impl<T> DerefMut for Diffable<T> {
 #[cfg(test)]
 mod tests;
 
-const fn estimate_usize_bytes(value: usize) -> usize {
-    const U8_MAX_AS_USIZE: usize = u8::MAX as usize;
-    const U8_MAX_PLUS_1_AS_USIZE: usize = U8_MAX_AS_USIZE + 1;
-    const U16_MAX_AS_USIZE: usize = u16::MAX as usize;
-    const U16_MAX_PLUS_1_AS_USIZE: usize = U16_MAX_AS_USIZE + 1;
-    const U32_MAX_AS_USIZE: usize = u32::MAX as usize;
-    match value {
-        0..=U8_MAX_AS_USIZE => 1,
-        U8_MAX_PLUS_1_AS_USIZE..=U16_MAX_AS_USIZE => 2,
-        #[cfg(target_pointer_width = "32")]
-        _ => 4,
+/// The number of unchanged bytes between two edited regions of a string below
+/// which the regions are combined into a single [`Change::Splice`].
+const SPLICE_MERGE_DISTANCE: usize = 5;
 
-        #[cfg(target_pointer_width = "64")]
-        U16_MAX_PLUS_1_AS_USIZE..=U32_MAX_AS_USIZE => 4,
-        #[cfg(target_pointer_width = "64")]
-        _ => 8,
-    }
-}
+/// The minimum length of an updated [`Value::Bytes`] before a
+/// [`Change::Delta`] is considered.
+const DELTA_MINIMUM_BYTES: usize = 64;
+
+/// The number of comparisons between checks of the deadline set by
+/// [`DiffOptions::time_limit()`].
+const DEADLINE_CHECK_INTERVAL: usize = 256;
+
+/// The number of values searched for a value similar to an unmatched value,
+/// beyond which the unmatched values are paired by position.
+const SIMILARITY_LOOKAHEAD: usize = 8;
+
+/// The number of values within a container that are diffed before deciding
+/// which changes to keep for them.
+const DIFF_BATCH_SIZE: usize = 256;
+
+/// The total cost of a batch of updated values being diffed below
+/// which the values are diffed on a single thread.
+#[cfg(feature = "parallel")]
+const PARALLEL_MINIMUM_WORK: usize = 16 * 1024;
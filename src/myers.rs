@@ -0,0 +1,366 @@
+//! An implementation of the linear-space variant of Eugene W. Myers' "An O(ND)
+//! Difference Algorithm and Its Variations".
+//!
+//! Rather than operating on slices directly, these functions only deal with
+//! indices. This allows the caller to compare values of different types, such
+//! as a [`Value`](pot::Value) and an updated value that has already been
+//! measured.
+
+/// Returns the longest common subsequence between two lists of `original_len`
+/// and `updated_len` items as a list of `(original_index, updated_index)`
+/// pairs, in ascending order.
+///
+/// `max_edit_distance` limits the number of edits searched each time the
+/// problem is split. When the limit is reached, the remaining items in that
+/// region are treated as having nothing in common, trading a longer edit
+/// script for less work.
+///
+/// `equal` is invoked with an original index and an updated index, and should
+/// return true if the two items are equal.
+///
+/// `exhausted` is checked before each step of the search. Once it returns
+/// true, the remaining items are treated as having nothing in common, as if
+/// `max_edit_distance` was reached.
+pub fn longest_common_subsequence<F, E>(
+    original_len: usize,
+    updated_len: usize,
+    max_edit_distance: Option<usize>,
+    mut equal: F,
+    mut exhausted: E,
+) -> Vec<(usize, usize)>
+where
+    F: FnMut(usize, usize) -> bool,
+    E: FnMut() -> bool,
+{
+    let mut matches = Vec::new();
+    let mut state = State {
+        max_edit_distance: max_edit_distance
+            .map_or(isize::MAX, |max| isize::try_from(max).unwrap_or(isize::MAX)),
+        equal: &mut equal,
+        exhausted: &mut exhausted,
+        forward: Vec::new(),
+        reverse: Vec::new(),
+    };
+    state.diff(0, original_len, 0, updated_len, &mut matches);
+    matches
+}
+
+/// Returns the lengths of the common prefix and the common suffix of two lists
+/// of `original_len` and `updated_len` items. The suffix never overlaps the
+/// prefix.
+///
+/// `equal` is invoked with an original index and an updated index, and should
+/// return true if the two items are equal.
+pub fn common_affixes<F>(original_len: usize, updated_len: usize, mut equal: F) -> (usize, usize)
+where
+    F: FnMut(usize, usize) -> bool,
+{
+    let shortest = original_len.min(updated_len);
+    let prefix = (0..shortest)
+        .find(|&offset| !equal(offset, offset))
+        .unwrap_or(shortest);
+    let suffix = (0..shortest - prefix)
+        .find(|&offset| !equal(original_len - offset - 1, updated_len - offset - 1))
+        .unwrap_or(shortest - prefix);
+    (prefix, suffix)
+}
+
+struct State<'a, F, E> {
+    max_edit_distance: isize,
+    equal: &'a mut F,
+    exhausted: &'a mut E,
+    forward: Vec<isize>,
+    reverse: Vec<isize>,
+}
+
+impl<'a, F, E> State<'a, F, E>
+where
+    F: FnMut(usize, usize) -> bool,
+    E: FnMut() -> bool,
+{
+    fn diff(
+        &mut self,
+        mut original_start: usize,
+        mut original_end: usize,
+        mut updated_start: usize,
+        mut updated_end: usize,
+        matches: &mut Vec<(usize, usize)>,
+    ) {
+        let (prefix, suffix) = common_affixes(
+            original_end - original_start,
+            updated_end - updated_start,
+            |original, updated| (self.equal)(original_start + original, updated_start + updated),
+        );
+        matches.extend((0..prefix).map(|offset| (original_start + offset, updated_start + offset)));
+        original_start += prefix;
+        updated_start += prefix;
+        // The suffix matches are pushed after the middle has been processed to
+        // keep `matches` sorted.
+        original_end -= suffix;
+        updated_end -= suffix;
+
+        if original_start < original_end && updated_start < updated_end {
+            if let Some((split_original, split_updated)) =
+                self.bisect(original_start, original_end, updated_start, updated_end)
+            {
+                self.diff(
+                    original_start,
+                    split_original,
+                    updated_start,
+                    split_updated,
+                    matches,
+                );
+                self.diff(
+                    split_original,
+                    original_end,
+                    split_updated,
+                    updated_end,
+                    matches,
+                );
+            }
+        }
+
+        matches.extend((0..suffix).map(|offset| (original_end + offset, updated_end + offset)));
+    }
+
+    /// Finds the "middle snake" of the edit graph, returning the point at
+    /// which the problem can be split into two smaller problems. Returns None
+    /// if the two ranges have nothing in common, or if no split was found
+    /// within the maximum edit distance or before the search was exhausted.
+    fn bisect(
+        &mut self,
+        original_start: usize,
+        original_end: usize,
+        updated_start: usize,
+        updated_end: usize,
+    ) -> Option<(usize, usize)> {
+        let original_len = to_isize(original_end - original_start);
+        let updated_len = to_isize(updated_end - updated_start);
+        let max_d = (original_len + updated_len + 1) / 2;
+        let v_offset = max_d;
+        let v_length = to_usize(2 * max_d + 2);
+
+        self.forward.clear();
+        self.forward.resize(v_length, -1);
+        self.reverse.clear();
+        self.reverse.resize(v_length, -1);
+        self.forward[to_usize(v_offset + 1)] = 0;
+        self.reverse[to_usize(v_offset + 1)] = 0;
+
+        let delta = original_len - updated_len;
+        // If the total number of items is odd, the front path will collide
+        // with the reverse path.
+        let front = delta % 2 != 0;
+        // Offsets for the start and end of k loops, which prevent mapping of
+        // space beyond the grid.
+        let mut k1_start = 0;
+        let mut k1_end = 0;
+        let mut k2_start = 0;
+        let mut k2_end = 0;
+
+        for d in 0..max_d.min(self.max_edit_distance) {
+            if (self.exhausted)() {
+                break;
+            }
+
+            // Walk the front path one step.
+            let mut k1 = -d + k1_start;
+            while k1 <= d - k1_end {
+                let k1_offset = to_usize(v_offset + k1);
+                let mut x1 = if k1 == -d
+                    || (k1 != d && self.forward[k1_offset - 1] < self.forward[k1_offset + 1])
+                {
+                    self.forward[k1_offset + 1]
+                } else {
+                    self.forward[k1_offset - 1] + 1
+                };
+                let mut y1 = x1 - k1;
+                while x1 < original_len
+                    && y1 < updated_len
+                    && (self.equal)(original_start + to_usize(x1), updated_start + to_usize(y1))
+                {
+                    x1 += 1;
+                    y1 += 1;
+                }
+                self.forward[k1_offset] = x1;
+                if x1 > original_len {
+                    // Ran off the right of the graph.
+                    k1_end += 2;
+                } else if y1 > updated_len {
+                    // Ran off the bottom of the graph.
+                    k1_start += 2;
+                } else if front {
+                    let k2_offset = v_offset + delta - k1;
+                    if k2_offset >= 0
+                        && k2_offset < to_isize(v_length)
+                        && self.reverse[to_usize(k2_offset)] != -1
+                    {
+                        // Mirror x2 onto the top-left coordinate system.
+                        let x2 = original_len - self.reverse[to_usize(k2_offset)];
+                        if x1 >= x2 {
+                            return Some((
+                                original_start + to_usize(x1),
+                                updated_start + to_usize(y1),
+                            ));
+                        }
+                    }
+                }
+                k1 += 2;
+            }
+
+            // Walk the reverse path one step.
+            let mut k2 = -d + k2_start;
+            while k2 <= d - k2_end {
+                let k2_offset = to_usize(v_offset + k2);
+                let mut x2 = if k2 == -d
+                    || (k2 != d && self.reverse[k2_offset - 1] < self.reverse[k2_offset + 1])
+                {
+                    self.reverse[k2_offset + 1]
+                } else {
+                    self.reverse[k2_offset - 1] + 1
+                };
+                let mut y2 = x2 - k2;
+                while x2 < original_len
+                    && y2 < updated_len
+                    && (self.equal)(
+                        original_end - to_usize(x2) - 1,
+                        updated_end - to_usize(y2) - 1,
+                    )
+                {
+                    x2 += 1;
+                    y2 += 1;
+                }
+                self.reverse[k2_offset] = x2;
+                if x2 > original_len {
+                    // Ran off the left of the graph.
+                    k2_end += 2;
+                } else if y2 > updated_len {
+                    // Ran off the top of the graph.
+                    k2_start += 2;
+                } else if !front {
+                    let k1_offset = v_offset + delta - k2;
+                    if k1_offset >= 0
+                        && k1_offset < to_isize(v_length)
+                        && self.forward[to_usize(k1_offset)] != -1
+                    {
+                        let x1 = self.forward[to_usize(k1_offset)];
+                        let y1 = v_offset + x1 - k1_offset;
+                        // Mirror x2 onto the top-left coordinate system.
+                        if x1 >= original_len - x2 {
+                            return Some((
+                                original_start + to_usize(x1),
+                                updated_start + to_usize(y1),
+                            ));
+                        }
+                    }
+                }
+                k2 += 2;
+            }
+        }
+
+        None
+    }
+}
+
+fn to_isize(value: usize) -> isize {
+    isize::try_from(value).expect("sequence too large")
+}
+
+fn to_usize(value: isize) -> usize {
+    usize::try_from(value).expect("negative index")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{common_affixes, longest_common_subsequence};
+
+    fn lcs_len(a: &[u8], b: &[u8]) -> usize {
+        let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                table[i][j] = if a[i] == b[j] {
+                    table[i + 1][j + 1] + 1
+                } else {
+                    table[i + 1][j].max(table[i][j + 1])
+                };
+            }
+        }
+        table[0][0]
+    }
+
+    #[track_caller]
+    fn check(a: &[u8], b: &[u8]) {
+        let matches =
+            longest_common_subsequence(a.len(), b.len(), None, |o, u| a[o] == b[u], || false);
+        for window in matches.windows(2) {
+            assert!(window[0].0 < window[1].0 && window[0].1 < window[1].1);
+        }
+        for &(o, u) in &matches {
+            assert_eq!(a[o], b[u]);
+        }
+        assert_eq!(matches.len(), lcs_len(a, b), "{a:?} {b:?}");
+    }
+
+    #[test]
+    fn basics() {
+        check(b"", b"");
+        check(b"abc", b"");
+        check(b"", b"abc");
+        check(b"abc", b"abc");
+        check(b"abcabba", b"cbabac");
+        check(b"xaxbxcx", b"abc");
+        check(b"abcdefgh", b"hgfedcba");
+        check(b"1234567890", b"0987654321");
+        check(b"the quick brown fox", b"a quick brown dog jumped");
+    }
+
+    #[test]
+    fn limited_edit_distance() {
+        let a = b"abcdefghijklmnopqrstuvwxyz";
+        let b = b"zyxwvutsrqponmlkjihgfedcba";
+        let limited =
+            longest_common_subsequence(a.len(), b.len(), Some(2), |o, u| a[o] == b[u], || false);
+        for &(o, u) in &limited {
+            assert_eq!(a[o], b[u]);
+        }
+        assert!(limited.is_empty());
+
+        // Common prefixes and suffixes are found regardless of the limit.
+        let a = b"prefix-abc-suffix";
+        let b = b"prefix-xyz-suffix";
+        let limited =
+            longest_common_subsequence(a.len(), b.len(), Some(0), |o, u| a[o] == b[u], || false);
+        assert_eq!(limited.len(), 14);
+    }
+
+    #[test]
+    fn affixes() {
+        let affixes = |a: &[u8], b: &[u8]| common_affixes(a.len(), b.len(), |o, u| a[o] == b[u]);
+        assert_eq!(affixes(b"", b"abc"), (0, 0));
+        assert_eq!(affixes(b"abc", b"abc"), (3, 0));
+        assert_eq!(affixes(b"abc", b"abcd"), (3, 0));
+        assert_eq!(affixes(b"abxc", b"abyc"), (2, 1));
+        // The suffix doesn't overlap the prefix.
+        assert_eq!(affixes(b"aa", b"aaa"), (2, 0));
+        assert_eq!(affixes(b"ba", b"bca"), (1, 1));
+    }
+
+    #[test]
+    fn exhaustive_small() {
+        // Every pair of sequences up to length 6 over a two-letter alphabet.
+        let sequences = (0..=6_u32)
+            .flat_map(|len| {
+                (0..1_u32 << len).map(move |bits| {
+                    (0..len)
+                        .map(|bit| b'a' + ((bits >> bit) & 1) as u8)
+                        .collect()
+                })
+            })
+            .collect::<Vec<Vec<u8>>>();
+        for a in &sequences {
+            for b in &sequences {
+                check(a, b);
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Paths to the values being diffed, and the patterns that select them for a
+//! [`PathPolicy`](crate::PathPolicy).
+//!
+//! A pattern is a list of segments separated by `.`, which is matched against
+//! the path from the root value. A segment matches the key of a mapping entry
+//! whose key is that string or integer, or an index within a sequence. `*`
+//! matches any single key or index, and `**` matches any number of them.
+
+use pot::Value;
+
+/// The location of the value being diffed, linked from the value back to the
+/// root value. The root value has no path.
+#[derive(Clone, Copy)]
+pub struct Path<'a> {
+    segment: Segment<'a>,
+    parent: Option<&'a Path<'a>>,
+}
+
+impl<'a> Path<'a> {
+    pub fn new(segment: Segment<'a>, parent: Option<&'a Path<'a>>) -> Self {
+        Self { segment, parent }
+    }
+}
+
+/// A step from a container to one of the values it contains.
+#[derive(Clone, Copy)]
+pub enum Segment<'a> {
+    /// The value at an index within a sequence.
+    Index(usize),
+    /// The value of the entry with a key within a mapping.
+    Key(&'a Value<'a>),
+}
+
+/// A pattern that matches paths, such as `items.*.id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern(Vec<PatternSegment>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PatternSegment {
+    Name(String),
+    Any,
+    AnyDepth,
+}
+
+impl Pattern {
+    /// Parses `pattern`. An empty pattern only matches the root value.
+    pub fn parse(pattern: &str) -> Self {
+        if pattern.is_empty() {
+            return Self(Vec::new());
+        }
+        Self(
+            pattern
+                .split('.')
+                .map(|segment| match segment {
+                    "*" => PatternSegment::Any,
+                    "**" => PatternSegment::AnyDepth,
+                    name => PatternSegment::Name(name.to_string()),
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns true if this pattern matches `path`.
+    pub fn matches(&self, path: Option<&Path<'_>>) -> bool {
+        let mut segments = Vec::new();
+        let mut path = path;
+        while let Some(current) = path {
+            segments.push(current.segment);
+            path = current.parent;
+        }
+        segments.reverse();
+        matches(&self.0, &segments)
+    }
+}
+
+fn matches(pattern: &[PatternSegment], path: &[Segment<'_>]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((PatternSegment::AnyDepth, pattern)) => {
+            (0..=path.len()).any(|skipped| matches(pattern, &path[skipped..]))
+        }
+        Some((expected, pattern)) => path.split_first().map_or(false, |(segment, path)| {
+            expected.matches(segment) && matches(pattern, path)
+        }),
+    }
+}
+
+impl PatternSegment {
+    fn matches(&self, segment: &Segment<'_>) -> bool {
+        match (self, segment) {
+            (Self::Any | Self::AnyDepth, _) => true,
+            (Self::Name(name), Segment::Index(index)) => name.parse() == Ok(*index),
+            (Self::Name(name), Segment::Key(Value::String(key))) => name == key,
+            (Self::Name(name), Segment::Key(Value::Integer(key))) => *name == key.to_string(),
+            (Self::Name(_), Segment::Key(_)) => false,
+        }
+    }
+}
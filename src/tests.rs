@@ -1,7 +1,9 @@
+use std::collections::BTreeMap;
+
 use pot::{OwnedValue, Value};
 use serde::{Deserialize, Serialize};
 
-use crate::Diff;
+use crate::{Change, DeltaInstruction, Diff, Diffable};
 
 #[track_caller]
 fn test<T: Serialize + for<'de> Deserialize<'de> + PartialEq + std::fmt::Debug>(
@@ -134,6 +136,55 @@ fn map_sequence_apply() {
     );
 }
 
+#[test]
+fn unchanged_neighbours() {
+    // Unchanged values make replacing the container more expensive than
+    // changing the values within it.
+    test(
+        &vec![3, 4, 5, 6, 7],
+        &vec![1, 2, 3, 4, 5, 6, 7],
+        "[;+0;1+1;2",
+    );
+    test(
+        &OwnedValue(Value::from_mappings([
+            (Value::from(1), Value::from(2)),
+            (Value::from(5), Value::from("unchanged")),
+        ])),
+        &OwnedValue(Value::from_mappings([
+            (Value::from(1), Value::from(3)),
+            (Value::from(5), Value::from("unchanged")),
+        ])),
+        "{;~0;3",
+    );
+    test(
+        &OwnedValue(Value::from_mappings([
+            (
+                Value::from(1),
+                Value::from_sequence([
+                    Value::from(1),
+                    Value::from(3),
+                    Value::from(5),
+                    Value::from(7),
+                ]),
+            ),
+            (Value::from(5), Value::from("unchanged")),
+        ])),
+        &OwnedValue(Value::from_mappings([
+            (
+                Value::from(1),
+                Value::from_sequence([
+                    Value::from(2),
+                    Value::from(3),
+                    Value::from(5),
+                    Value::from(7),
+                ]),
+            ),
+            (Value::from(5), Value::from("unchanged")),
+        ])),
+        "{;[0;~0;2",
+    );
+}
+
 #[test]
 fn root_operations() {
     test(&1, &2, "~;2");
@@ -166,3 +217,1839 @@ fn root_operations() {
     // replace instead of update
     test(&vec![0, 1, 2, 3, 4, 5, 6, 7], &vec![1, 7], "~;[1,7]")
 }
+
+#[test]
+fn trailing_containers() {
+    // A container can end exactly where the encoded diff does.
+    test(
+        &OwnedValue(Value::from(1)),
+        &OwnedValue(Value::from_sequence(Vec::<Value<'_>>::new())),
+        "~;[]",
+    );
+    test(
+        &OwnedValue(Value::from(1)),
+        &OwnedValue(Value::from_sequence([Value::None])),
+        "~;[none]",
+    );
+    test(
+        &OwnedValue(Value::from(1)),
+        &OwnedValue(Value::from_mappings([(Value::None, Value::None)])),
+        "~;{none:none}",
+    );
+}
+
+#[test]
+fn sequence_edit_script() {
+    test(
+        &vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+        &vec![2, 1, 3, 4, 5, 6, 7, 8, 9, 10],
+        "[;>0;1;1",
+    );
+    test(
+        &vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+        &vec![1, 2, 4, 5, 6, 7, 11, 8, 9, 10],
+        "[;-2;1+6;11",
+    );
+    test(
+        &vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+        &vec![10, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+        "[;>9;0;1",
+    );
+}
+
+#[test]
+fn map_key_lookup() {
+    // Replacing a mapping this small is cheaper than changing its entries.
+    test(
+        &OwnedValue(Value::from_mappings([(Value::from(3), Value::from(4))])),
+        &OwnedValue(Value::from_mappings([
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(5)),
+        ])),
+        "~;{1:2,3:5}",
+    );
+    // The entry matching the original key must still have its value compared
+    // after the new entries before it are inserted.
+    test(
+        &OwnedValue(Value::from_mappings([
+            (Value::from(3), Value::from(4)),
+            (Value::from(5), Value::from("unchanged")),
+        ])),
+        &OwnedValue(Value::from_mappings([
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(5)),
+            (Value::from(5), Value::from("unchanged")),
+        ])),
+        "{;+0;1;2~1;5",
+    );
+
+    let original = (0..1_000)
+        .map(|index| (format!("key{index:04}"), index))
+        .collect::<BTreeMap<_, _>>();
+    let mut updated = original.clone();
+    updated.remove("key0500");
+    updated.insert(String::from("key0750a"), 1);
+    *updated.get_mut("key0999").unwrap() = 0;
+    test(&original, &updated, r#"{;-500;1+750;"key0750a";1~999;0"#);
+}
+
+#[test]
+fn sequence_moves() {
+    let rows = |order: &[usize]| {
+        order
+            .iter()
+            .map(|index| format!("row number {index}"))
+            .collect::<Vec<_>>()
+    };
+    // Dragging a single row.
+    test(
+        &rows(&[0, 1, 2, 3, 4, 5]),
+        &rows(&[0, 1, 4, 2, 3, 5]),
+        "[;>4;2;1",
+    );
+    // Dragging a block of rows.
+    test(
+        &rows(&[0, 1, 2, 3, 4, 5]),
+        &rows(&[3, 4, 0, 1, 2, 5]),
+        "[;>3;0;2",
+    );
+    // Moving rows while also inserting and removing others.
+    test(
+        &rows(&[0, 1, 2, 3, 4, 5]),
+        &rows(&[5, 1, 6, 2, 3, 0]),
+        r#"[;>5;0;1>1;4;1+2;"row number 6"$6"#,
+    );
+
+    // Moves that don't fit within the sequence can't be applied.
+    for (from, to) in [(4, 0), (0, 4), (usize::MAX, 0)] {
+        let diff = Diff {
+            changes: vec![
+                Change::EnterSequence {
+                    index: None,
+                    key: false,
+                },
+                Change::Move {
+                    from,
+                    to,
+                    length: 2,
+                },
+            ],
+        };
+        assert!(matches!(
+            diff.apply(&rows(&[0, 1, 2, 3, 4])),
+            Err(crate::Error::IndexOutOfRange)
+        ));
+    }
+}
+
+/// A small deterministic pseudo-random number generator for generating test
+/// inputs.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, max: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 33) % max
+    }
+}
+
+#[test]
+fn random_sequence_edits() {
+    let mut rng = Lcg(0);
+    for _ in 0..500 {
+        let original = (0..rng.next(20))
+            .map(|_| rng.next(8) as u8)
+            .collect::<Vec<_>>();
+        let mut updated = original.clone();
+        for _ in 0..rng.next(6) {
+            match rng.next(3) {
+                0 if !updated.is_empty() => {
+                    updated.remove(rng.next(updated.len() as u64) as usize);
+                }
+                1 => {
+                    let index = rng.next(updated.len() as u64 + 1) as usize;
+                    updated.insert(index, rng.next(8) as u8);
+                }
+                _ if updated.len() > 1 => {
+                    let from = rng.next(updated.len() as u64) as usize;
+                    let value = updated.remove(from);
+                    let to = rng.next(updated.len() as u64 + 1) as usize;
+                    updated.insert(to, value);
+                }
+                _ => {}
+            }
+        }
+
+        let diff = Diff::between(&original, &updated);
+        assert_eq!(diff.apply(&original).unwrap(), updated, "{diff}");
+        let decoded = Diff::deserialize(&diff.serialize()).unwrap();
+        assert_eq!(decoded.apply(&original).unwrap(), updated, "{diff}");
+    }
+}
+
+#[test]
+fn mapping_moves() {
+    let diff = Diff {
+        changes: vec![
+            Change::EnterMap {
+                index: None,
+                key: false,
+            },
+            Change::Move {
+                from: 2,
+                to: 0,
+                length: 1,
+            },
+        ],
+    };
+    let decoded = Diff::deserialize(&diff.serialize()).unwrap();
+    assert_eq!(decoded, diff);
+    assert_eq!(diff.to_string(), "{;>2;0;1");
+    let applied = diff
+        .apply_to_value(Value::from_mappings([
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(4)),
+            (Value::from(5), Value::from(6)),
+        ]))
+        .unwrap();
+    assert_eq!(
+        applied,
+        Value::from_mappings([
+            (Value::from(5), Value::from(6)),
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(4)),
+        ])
+    );
+}
+
+#[test]
+fn string_splices() {
+    test(
+        &String::from("The quick brown fox jumps over the lazy dog"),
+        &String::from("The quick red fox jumps over the lazy dog"),
+        r#"^;10;5;"red""#,
+    );
+    test(
+        &vec![String::from("hello, world. this is a longer string")],
+        &vec![String::from("hello, wörld. this is a longer string!")],
+        r#"[;^0;8;1;"ö"^0;38;0;"!""#,
+    );
+    // Unrelated strings are cheaper to replace outright.
+    test(&String::from("abc"), &String::from("xyz"), r#"~;"xyz""#);
+    // Splices must land on character boundaries.
+    test(
+        &String::from("naïve café owners in München"),
+        &String::from("naive cafe owners in Munchen"),
+        r#"^;2;2;"i"^;9;2;"e"^;22;2;"u""#,
+    );
+
+    let splice = |offset, delete| Diff {
+        changes: vec![Change::Splice {
+            index: None,
+            offset,
+            delete,
+            insert: String::from("e"),
+        }],
+    };
+    // Splices that split a character or run past the end can't be applied.
+    for (offset, delete) in [(4, 1), (3, 3), (usize::MAX, 1)] {
+        assert!(matches!(
+            splice(offset, delete).apply(&String::from("café")),
+            Err(crate::Error::InvalidSplice)
+        ));
+    }
+    assert_eq!(splice(3, 2).apply(&String::from("café")).unwrap(), "cafe");
+    assert!(matches!(
+        splice(0, 0).apply(&1),
+        Err(crate::Error::TypeMismatch)
+    ));
+}
+
+#[test]
+fn bytes_deltas() {
+    let original = (0..=255).collect::<Vec<u8>>();
+    let mut updated = original.clone();
+    updated[100] = 0;
+    updated.extend_from_slice(b"appended");
+    let diff = Diff::between_values(&Value::from(original.clone()), Value::from(updated.clone()));
+    assert_eq!(diff.to_string(), "%;=0,100;#00;=101,155;#|appended");
+    let decoded = Diff::deserialize(&diff.serialize()).unwrap();
+    assert_eq!(decoded, diff);
+    assert_eq!(
+        decoded
+            .apply_to_value(Value::from(original.clone()))
+            .unwrap(),
+        Value::from(updated)
+    );
+
+    // Small values are always replaced.
+    let diff = Diff::between_values(
+        &Value::from(&original[..32]),
+        Value::from(original[1..33].to_vec()),
+    );
+    assert!(matches!(diff.changes[..], [Change::Replace { .. }]));
+
+    // A delta is only used when it is smaller than the updated value.
+    let unrelated = original.iter().map(|byte| byte ^ 0x55).collect::<Vec<u8>>();
+    let diff = Diff::between_values(&Value::from(original.clone()), Value::from(unrelated));
+    assert!(matches!(diff.changes[..], [Change::Replace { .. }]));
+
+    // Deltas that copy from past the end of the original can't be applied.
+    let delta = Diff {
+        changes: vec![Change::Delta {
+            index: None,
+            instructions: vec![DeltaInstruction::Copy {
+                offset: 200,
+                length: 100,
+            }],
+        }],
+    };
+    assert!(matches!(
+        delta.apply_to_value(Value::from(original)),
+        Err(crate::Error::InvalidDelta)
+    ));
+    assert!(matches!(
+        delta.apply_to_value(Value::from("bytes")),
+        Err(crate::Error::TypeMismatch)
+    ));
+}
+
+#[test]
+fn numeric_adds() {
+    test(&1_000_000_u64, &1_000_001_u64, "!;1");
+    test(
+        &vec![-5_000_000_i64, 7],
+        &vec![-4_999_990_i64, 7],
+        "[;!0;10",
+    );
+    test(&10_000_000_000.5_f64, &10_000_000_002.5_f64, "!;2");
+    // Small values are cheaper to replace.
+    test(&1_u8, &2_u8, "~;2");
+    // Deltas that can't be represented exactly fall back to replacing.
+    test(&u128::MAX, &0_u128, "~;0");
+    let diff = Diff::between(&f64::MAX, &f64::MIN);
+    assert!(matches!(diff.changes[..], [Change::Replace { .. }]));
+
+    let diff = Diff {
+        changes: vec![Change::Add {
+            index: None,
+            delta: Value::from(1),
+        }],
+    };
+    assert!(matches!(
+        diff.apply_to_value(Value::from(i128::MAX)),
+        Err(crate::Error::Overflow)
+    ));
+    assert!(matches!(
+        diff.apply_to_value(Value::from("1")),
+        Err(crate::Error::TypeMismatch)
+    ));
+    let nested = Diff {
+        changes: vec![
+            Change::EnterSequence {
+                index: None,
+                key: false,
+            },
+            Change::Add {
+                index: Some(1),
+                delta: Value::from(1),
+            },
+        ],
+    };
+    assert!(matches!(
+        nested.apply_to_value(Value::from_sequence([
+            Value::from(0),
+            Value::from(i128::MAX)
+        ])),
+        Err(crate::Error::Overflow)
+    ));
+    assert!(matches!(
+        nested.apply_to_value(Value::from_sequence([Value::from(0)])),
+        Err(crate::Error::IndexOutOfRange)
+    ));
+    // The sum keeps the width of the original value.
+    assert_eq!(
+        pot::to_vec(&diff.apply_to_value(Value::from(2_u8)).unwrap()).unwrap(),
+        pot::to_vec(&Value::from(3_u8)).unwrap()
+    );
+}
+
+#[test]
+fn replace_ranges() {
+    let original = (0..8).map(|i| format!("a{i}")).collect::<Vec<_>>();
+    let mut updated = original.clone();
+    for (index, value) in updated.iter_mut().enumerate().skip(2).take(4) {
+        *value = format!("b{index}");
+    }
+    test(&original, &updated, r#"[;~2..6;"b2";"b3";"b4";"b5""#);
+
+    let original = (0..8)
+        .map(|i| (format!("a{i}"), i))
+        .collect::<BTreeMap<_, _>>();
+    let mut updated = original.clone();
+    for value in updated.values_mut().skip(2).take(3) {
+        *value += 100;
+    }
+    updated.remove("a6");
+    updated.remove("a7");
+    updated.insert(String::from("b6"), 6);
+    updated.insert(String::from("b7"), 7);
+    test(
+        &original,
+        &updated,
+        r#"{;~2..5;102;103;104~@6;"b6"~@7;"b7""#,
+    );
+    updated.insert(String::from("b6"), 60);
+    updated.insert(String::from("b7"), 70);
+    test(
+        &original,
+        &updated,
+        r#"{;~2..5;102;103;104~6..8;"b6";60;"b7";70"#,
+    );
+
+    let sequence = Value::from_sequence([Value::from(0), Value::from(1)]);
+    let mappings = Value::from_mappings([(Value::from(0), Value::from(1))]);
+    let enter_sequence = Change::EnterSequence {
+        index: None,
+        key: false,
+    };
+    let enter_map = Change::EnterMap {
+        index: None,
+        key: false,
+    };
+    for (value, changes) in [
+        (
+            &sequence,
+            vec![
+                enter_sequence.clone(),
+                Change::ReplaceRange {
+                    index: 1,
+                    values: vec![Value::None, Value::None],
+                },
+            ],
+        ),
+        (
+            &sequence,
+            vec![
+                enter_sequence,
+                Change::ReplaceRange {
+                    index: usize::MAX,
+                    values: vec![Value::None],
+                },
+            ],
+        ),
+        (
+            &mappings,
+            vec![
+                enter_map.clone(),
+                Change::ReplaceRange {
+                    index: 1,
+                    values: vec![Value::None],
+                },
+            ],
+        ),
+        (
+            &mappings,
+            vec![
+                enter_map,
+                Change::ReplaceMappingRange {
+                    index: 0,
+                    mappings: vec![(Value::None, Value::None), (Value::None, Value::None)],
+                },
+            ],
+        ),
+    ] {
+        assert!(matches!(
+            Diff { changes }.apply_to_value(value.clone()),
+            Err(crate::Error::IndexOutOfRange)
+        ));
+    }
+}
+
+#[test]
+fn subtree_copies() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Folder {
+        name: String,
+        items: Vec<String>,
+    }
+
+    let original = vec![
+        Folder {
+            name: String::from("inbox"),
+            items: vec![
+                String::from("quarterly report draft"),
+                String::from("meeting notes from monday"),
+            ],
+        },
+        Folder {
+            name: String::from("archive"),
+            items: vec![String::from("old invoice")],
+        },
+    ];
+    // Move an item from one folder to another.
+    let mut updated = original.clone();
+    let item = updated[0].items.remove(1);
+    updated[1].items.push(item);
+    test(&original, &updated, "[;{0;[1;$1]}{1;[1;&+1;/0/1/1");
+
+    // Duplicate an entire folder.
+    let mut updated = original.clone();
+    updated.push(original[0].clone());
+    test(&original, &updated, "[;&+2;/0");
+
+    let value = Value::from_sequence([Value::from(0)]);
+    for (changes, error) in [
+        (
+            vec![Change::Copy {
+                index: None,
+                source: vec![1],
+            }],
+            "source",
+        ),
+        (
+            vec![
+                Change::EnterSequence {
+                    index: None,
+                    key: false,
+                },
+                Change::Copy {
+                    index: Some(1),
+                    source: vec![0],
+                },
+            ],
+            "index",
+        ),
+        (
+            vec![
+                Change::EnterSequence {
+                    index: None,
+                    key: false,
+                },
+                Change::InsertCopy {
+                    index: 2,
+                    source: vec![0],
+                },
+            ],
+            "index",
+        ),
+    ] {
+        let result = Diff { changes }.apply_to_value(value.clone());
+        match error {
+            "source" => assert!(matches!(result, Err(crate::Error::InvalidCopySource))),
+            _ => assert!(matches!(result, Err(crate::Error::IndexOutOfRange))),
+        }
+    }
+}
+
+#[test]
+fn diff_options() {
+    use crate::DiffOptions;
+
+    let original = vec![vec![1, 2, 3], vec![4, 5, 6]];
+    let updated = vec![vec![1, 2, 3], vec![4, 5, 7]];
+    assert_eq!(Diff::between(&original, &updated).to_string(), "[;[1;~2;7");
+    let diff = Diff::between_with(&original, &updated, &DiffOptions::default().max_depth(1));
+    assert_eq!(diff.to_string(), "[;~1;[4,5,7]");
+    let diff = Diff::between_with(&original, &updated, &DiffOptions::default().max_depth(0));
+    assert_eq!(diff.to_string(), "~;[[1,2,3],[4,5,7]]");
+
+    let original = (0..6)
+        .map(|i| (format!("b{i}"), i))
+        .collect::<BTreeMap<_, _>>();
+    let mut updated = original.clone();
+    updated.extend((0..4).map(|i| (format!("a{i}"), i)));
+    assert_eq!(
+        Diff::between(&original, &updated).to_string(),
+        r#"{;+0;"a0";0+1;"a1";1+2;"a2";2+3;"a3";3"#
+    );
+    let diff = Diff::between_with(
+        &original,
+        &updated,
+        &DiffOptions::default().max_lookahead(2),
+    );
+    assert_eq!(
+        diff.to_string(),
+        r#"~;{"a0":0,"a1":1,"a2":2,"a3":3,"b0":0,"b1":1,"b2":2,"b3":3,"b4":4,"b5":5}"#
+    );
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+
+    let diff = Diff::between_with(
+        &original,
+        &updated,
+        &DiffOptions::default().replace_ratio(0.1),
+    );
+    assert!(matches!(
+        diff.changes[..],
+        [Change::Replace { index: None, .. }]
+    ));
+
+    let original = (0..20).map(|i| vec![i, 0]).collect::<Vec<_>>();
+    let updated = (0..20).map(|i| vec![i, 1]).collect::<Vec<_>>();
+    let diff = Diff::between_with(&original, &updated, &DiffOptions::default().max_changes(10));
+    assert!(matches!(
+        diff.changes[..],
+        [Change::Replace { index: None, .. }]
+    ));
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+
+    let mut diffable = Diffable::with_options(original, DiffOptions::default().max_depth(0));
+    diffable[0][1] = 1;
+    assert!(matches!(
+        diffable.diff().unwrap().changes[..],
+        [Change::Replace { index: None, .. }]
+    ));
+}
+
+#[test]
+fn cost_models() {
+    use crate::{ChangeKind, ContainerKind, CostModel, DiffOptions, EncodedSize};
+
+    /// Measures encoded bytes, except that moves are too expensive to use.
+    #[derive(Debug)]
+    struct NoMoves;
+
+    impl CostModel for NoMoves {
+        fn operation(&self, kind: ChangeKind) -> usize {
+            if kind == ChangeKind::Move {
+                usize::MAX / 2
+            } else {
+                EncodedSize.operation(kind)
+            }
+        }
+
+        fn argument(&self, argument: usize) -> usize {
+            EncodedSize.argument(argument)
+        }
+
+        fn raw_bytes(&self, bytes: &[u8]) -> usize {
+            EncodedSize.raw_bytes(bytes)
+        }
+
+        fn scalar(&self, value: &Value<'_>) -> usize {
+            EncodedSize.scalar(value)
+        }
+
+        fn container(&self, kind: ContainerKind, length: usize) -> usize {
+            EncodedSize.container(kind, length)
+        }
+    }
+
+    let original = (1..=10).collect::<Vec<_>>();
+    let updated = [10].into_iter().chain(1..10).collect::<Vec<_>>();
+    assert_eq!(Diff::between(&original, &updated).to_string(), "[;>9;0;1");
+    let diff = Diff::between_with(
+        &original,
+        &updated,
+        &DiffOptions::default().cost_model(NoMoves),
+    );
+    assert_eq!(diff.to_string(), "[;+0;10$10");
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+
+    /// Makes every change as expensive as possible, so every sum of costs
+    /// saturates.
+    #[derive(Debug)]
+    struct Unaffordable;
+
+    impl CostModel for Unaffordable {
+        fn operation(&self, _kind: ChangeKind) -> usize {
+            usize::MAX
+        }
+
+        fn argument(&self, argument: usize) -> usize {
+            EncodedSize.argument(argument)
+        }
+
+        fn raw_bytes(&self, bytes: &[u8]) -> usize {
+            EncodedSize.raw_bytes(bytes)
+        }
+
+        fn scalar(&self, value: &Value<'_>) -> usize {
+            EncodedSize.scalar(value)
+        }
+
+        fn container(&self, kind: ContainerKind, length: usize) -> usize {
+            EncodedSize.container(kind, length)
+        }
+    }
+
+    let options = DiffOptions::default().cost_model(Unaffordable);
+    let diff = Diff::between_with(&original, &updated, &options);
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+    let original = (0..4)
+        .map(|i| (format!("a{i}"), vec![i]))
+        .collect::<BTreeMap<_, _>>();
+    let mut updated = original.clone();
+    updated.get_mut("a1").unwrap().push(1);
+    let diff = Diff::between_with(&original, &updated, &options.unordered_mappings(true));
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+}
+
+fn random_string(rng: &mut Lcg) -> String {
+    (0..rng.next(40))
+        .map(|_| char::from(b'a' + rng.next(6) as u8))
+        .collect()
+}
+
+fn random_value(rng: &mut Lcg, depth: u32) -> Value<'static> {
+    match rng.next(if depth == 0 { 7 } else { 9 }) {
+        0 => Value::None,
+        1 => Value::from(rng.next(2) == 0),
+        2 => Value::from(rng.next(1 << 40) as i64 - (1 << 39)),
+        3 => Value::from(rng.next(1 << 20) as f64 / 8.),
+        4 => Value::from(random_string(rng)),
+        5 => Value::from(
+            (0..rng.next(200))
+                .map(|_| rng.next(4) as u8)
+                .collect::<Vec<_>>(),
+        ),
+        6 => Value::from(rng.next(1000)),
+        7 => Value::from_sequence(
+            (0..rng.next(10))
+                .map(|_| random_value(rng, depth - 1))
+                .collect::<Vec<_>>(),
+        ),
+        _ => Value::from_mappings(
+            (0..rng.next(10))
+                .map(|key| (Value::from(key * 2), random_value(rng, depth - 1)))
+                .collect::<Vec<_>>(),
+        ),
+    }
+}
+
+/// Returns a copy of `value` with random edits made throughout it.
+fn mutate(rng: &mut Lcg, value: &Value<'static>, depth: u32) -> Value<'static> {
+    if rng.next(8) == 0 {
+        return random_value(rng, depth);
+    }
+    match value {
+        Value::Integer(integer) if rng.next(2) == 0 => {
+            Value::from(integer.as_i64().unwrap() + rng.next(1000) as i64)
+        }
+        Value::String(string) => {
+            let mut string = string.to_string();
+            for _ in 0..rng.next(3) {
+                let index = rng.next(string.len() as u64 + 1) as usize;
+                string.insert_str(index, &random_string(rng));
+            }
+            Value::from(string)
+        }
+        Value::Bytes(bytes) => {
+            let mut bytes = bytes.to_vec();
+            for _ in 0..rng.next(4) {
+                let index = rng.next(bytes.len() as u64 + 1) as usize;
+                bytes.insert(index, rng.next(256) as u8);
+            }
+            Value::from(bytes)
+        }
+        Value::Sequence(values) if depth > 0 => {
+            let mut values = values
+                .iter()
+                .map(|value| mutate(rng, value, depth - 1))
+                .collect::<Vec<_>>();
+            for _ in 0..rng.next(3) {
+                if !values.is_empty() && rng.next(2) == 0 {
+                    values.remove(rng.next(values.len() as u64) as usize);
+                } else {
+                    let index = rng.next(values.len() as u64 + 1) as usize;
+                    values.insert(index, random_value(rng, depth - 1));
+                }
+            }
+            Value::Sequence(values)
+        }
+        Value::Mappings(mappings) if depth > 0 => {
+            let mut mappings = mappings
+                .iter()
+                .map(|(key, value)| (key.clone(), mutate(rng, value, depth - 1)))
+                .collect::<Vec<_>>();
+            for _ in 0..rng.next(3) {
+                if !mappings.is_empty() && rng.next(2) == 0 {
+                    mappings.remove(rng.next(mappings.len() as u64) as usize);
+                } else {
+                    // Original keys are even, so odd keys are always new.
+                    let index = rng.next(mappings.len() as u64 + 1) as usize;
+                    let key = Value::from(rng.next(1000) * 2 + 1);
+                    if !mappings.iter().any(|(existing, _)| existing == &key) {
+                        mappings.insert(index, (key, random_value(rng, depth - 1)));
+                    }
+                }
+            }
+            Value::Mappings(mappings)
+        }
+        value => value.clone(),
+    }
+}
+
+/// Returns `value` as it decodes after being encoded, which turns bytes that
+/// are valid UTF-8 into strings.
+fn decoded(value: &Value<'_>) -> Value<'static> {
+    pot::from_slice::<OwnedValue>(&pot::to_vec(value).unwrap())
+        .unwrap()
+        .0
+}
+
+#[test]
+fn random_round_trips() {
+    use crate::hash::HashTree;
+    use crate::{CostModel, DiffOptions, EncodedSize, Estimated, KeyExtractor};
+
+    let first_entry = KeyExtractor::new(|value| match value {
+        Value::Mappings(mappings) => match mappings.first() {
+            Some((_, Value::Integer(id))) => Some(Value::Integer(*id)),
+            _ => None,
+        },
+        _ => None,
+    });
+    let option_sets = [
+        DiffOptions::default(),
+        DiffOptions::default().max_depth(1),
+        DiffOptions::default().max_lookahead(2),
+        DiffOptions::default().replace_ratio(0.5),
+        DiffOptions::default().max_changes(5),
+        DiffOptions::default().max_comparisons(50),
+        DiffOptions::default().unordered_mappings(true),
+        DiffOptions::default().sequence_key(first_entry),
+        DiffOptions::default().similarity_threshold(0.),
+        DiffOptions::default().similarity_threshold(0.9),
+        DiffOptions::default().tree_edit_distance(1_000),
+    ];
+    let mut rng = Lcg(1);
+    for options in &option_sets {
+        let confirmed = options.clone().confirm_hash_matches(true);
+        for _ in 0..200 {
+            let original = random_value(&mut rng, 3);
+            let updated = mutate(&mut rng, &original, 3);
+            let wrapped = Value::from_sequence([Value::None, updated.clone()]);
+            for (original, updated) in [
+                (&original, &updated),
+                (&original, &wrapped),
+                (&wrapped, &updated),
+            ] {
+                let (diff, cost) = Diff::create(original, updated, options);
+                // The encoded diff begins with a version byte and the change
+                // count.
+                let predicted = 1 + EncodedSize.argument(diff.changes.len()) + cost;
+                assert_eq!(predicted, diff.serialize().len(), "{diff}");
+                assert_eq!(
+                    HashTree::new(updated).hash,
+                    Estimated::measure(updated, &EncodedSize).hash
+                );
+                assert_eq!(
+                    Diff::between_borrowed_with(original, updated, options),
+                    diff
+                );
+                // Confirming matching hashes doesn't change the diff without
+                // collisions.
+                assert_eq!(
+                    Diff::between_borrowed_with(original, updated, &confirmed),
+                    diff
+                );
+                assert_eq!(
+                    sorted(diff.apply_to_value(original.clone()).unwrap()),
+                    sorted(updated.clone()),
+                    "{diff}"
+                );
+
+                // Decoding can't distinguish bytes from strings, so decoded
+                // diffs are compared against decoded values.
+                let mut bytes = Vec::new();
+                Diff::write_between(original, updated, options, &mut bytes).unwrap();
+                let streamed = Diff::deserialize(&bytes).unwrap();
+                let applied = streamed.apply_to_value(original.clone()).unwrap();
+                assert_eq!(
+                    sorted(decoded(&applied)),
+                    sorted(decoded(updated)),
+                    "{streamed}"
+                );
+                let encoded = Diff::between_encoded_with(
+                    &pot::to_vec(original).unwrap(),
+                    &pot::to_vec(updated).unwrap(),
+                    options,
+                )
+                .unwrap();
+                assert_eq!(
+                    sorted(encoded.apply_to_value(decoded(original)).unwrap()),
+                    sorted(decoded(updated)),
+                    "{encoded}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn predicted_sizes() {
+    use crate::{CostModel, DiffOptions, EncodedSize, PathPolicy};
+
+    let text = |text: &str| Value::from(text.to_string());
+    let words = |words: &[&str]| {
+        Value::from_sequence(words.iter().map(|word| text(word)).collect::<Vec<_>>())
+    };
+    let paragraph = "a paragraph with enough text that copying it is cheaper";
+    let cases = [
+        // A splice within a string.
+        (
+            text("the quick brown fox"),
+            text("the quick red fox"),
+            DiffOptions::default(),
+            9,
+        ),
+        // A delta of bytes.
+        (
+            Value::from((0..100).collect::<Vec<u8>>()),
+            Value::from(
+                (0..100)
+                    .map(|byte| if byte == 50 { 0 } else { byte })
+                    .collect::<Vec<u8>>(),
+            ),
+            DiffOptions::default(),
+            13,
+        ),
+        // An addition to an integer.
+        (
+            Value::from(1_000_000),
+            Value::from(1_000_100),
+            DiffOptions::default(),
+            5,
+        ),
+        // A value moved within a sequence.
+        (
+            words(&["a", "b", "c", "d"]),
+            words(&["b", "c", "d", "a"]),
+            DiffOptions::default(),
+            7,
+        ),
+        // A value copied from elsewhere.
+        (
+            words(&[paragraph, "b"]),
+            words(&[paragraph, "b", paragraph]),
+            DiffOptions::default(),
+            7,
+        ),
+        // A range of values replaced together.
+        (
+            words(&["a", "b", "c", "d"]),
+            words(&["a", "x", "y", "d"]),
+            DiffOptions::default(),
+            10,
+        ),
+        // A member added to a set.
+        (
+            words(&["a", "b"]),
+            words(&["a", "b", "c"]),
+            DiffOptions::default().policy("", PathPolicy::Set),
+            6,
+        ),
+        // An entry changed within a mapping.
+        (
+            Value::from_mappings([
+                (text("key"), Value::from(1)),
+                (text("other"), text(paragraph)),
+            ]),
+            Value::from_mappings([
+                (text("key"), Value::from(2)),
+                (text("other"), text(paragraph)),
+            ]),
+            DiffOptions::default().unordered_mappings(true),
+            10,
+        ),
+    ];
+    for (original, updated, options, size) in &cases {
+        let (diff, cost) = Diff::create(original, updated, options);
+        assert_eq!(diff.serialize().len(), *size, "{diff}");
+        // The encoded diff begins with a version byte and the change count.
+        assert_eq!(1 + EncodedSize.argument(diff.changes.len()) + cost, *size);
+    }
+}
+
+#[test]
+fn structural_hashes() {
+    use crate::hash::HashTree;
+    use crate::{DiffOptions, EncodedSize, Estimated};
+
+    let value = Value::from_sequence([
+        Value::from_mappings([(Value::from("key"), Value::from(1_u8))]),
+        Value::from(vec![1_u8, 2, 3]),
+        Value::from(1.5),
+    ]);
+    let hash = HashTree::new(&value).hash;
+    let estimated = Estimated::measure(&value, &EncodedSize);
+    assert_eq!(estimated.hash, hash);
+    // The hashes computed while measuring can be kept as a hash tree.
+    let kept = HashTree::from(&estimated);
+    assert_eq!(kept.hash, hash);
+    assert_eq!(kept.sequence().len(), 3);
+    assert_eq!(
+        kept.sequence()[0].mappings()[0].1.hash,
+        HashTree::new(&value).sequence()[0].mappings()[0].1.hash
+    );
+    // Integers hash by their value rather than by how they are stored.
+    let widened = Value::from_sequence([
+        Value::from_mappings([(Value::from("key"), Value::from(1_i64))]),
+        Value::from(vec![1_u8, 2, 3]),
+        Value::from(1.5),
+    ]);
+    assert_eq!(HashTree::new(&widened).hash, hash);
+    // Bytes and strings with the same contents hash differently.
+    let string = Value::from_sequence([
+        Value::from_mappings([(Value::from("key"), Value::from(1_u8))]),
+        Value::from("\u{1}\u{2}\u{3}"),
+        Value::from(1.5),
+    ]);
+    assert_ne!(HashTree::new(&string).hash, hash);
+
+    // Confirming matching hashes doesn't change the diff without collisions.
+    let confirmed = DiffOptions::default().confirm_hash_matches(true);
+    let updated = Value::from_sequence([Value::from(1.5), value.clone(), widened]);
+    let diff = Diff::between_values(&value, updated.clone());
+    assert_eq!(diff.to_string(), "[;-0;2&+1;/&+2;/");
+    assert_eq!(Diff::between_values_with(&value, updated, &confirmed), diff);
+
+    // Equal values can hash differently, but are still left unchanged.
+    let diff = Diff::between(&vec![vec![0.0_f64]], &vec![vec![-0.0_f64]]);
+    assert!(diff.changes.is_empty(), "{diff}");
+}
+
+#[test]
+#[cfg(feature = "parallel")]
+fn parallel_diffs() {
+    use crate::DiffOptions;
+
+    let rows = |changed: usize| {
+        Value::from_sequence(
+            (0..64)
+                .map(|row| {
+                    Value::from_sequence(
+                        (0..200)
+                            .map(|column| {
+                                let column = if column % 3 == 0 {
+                                    column + changed
+                                } else {
+                                    column
+                                };
+                                Value::from(format!("value {row} {column}"))
+                            })
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+    };
+    let (original, updated) = (rows(0), rows(1_000));
+    let single_thread = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    let threads = rayon::ThreadPoolBuilder::new()
+        .num_threads(8)
+        .build()
+        .unwrap();
+    // Large enough that the rows are diffed across threads.
+    let serial = single_thread.install(|| Diff::between_values(&original, updated.clone()));
+    let diff = threads.install(|| Diff::between_values(&original, updated.clone()));
+    assert_eq!(diff, serial);
+    assert_eq!(diff.apply_to_value(original.clone()).unwrap(), updated);
+
+    // Running out of comparisons replaces the same values on every run, no
+    // matter how many threads there are.
+    let budgeted = DiffOptions::default().max_comparisons(100_000);
+    let serial =
+        single_thread.install(|| Diff::between_values_with(&original, updated.clone(), &budgeted));
+    assert_ne!(serial, diff);
+    for _ in 0..5 {
+        let diff =
+            threads.install(|| Diff::between_values_with(&original, updated.clone(), &budgeted));
+        assert_eq!(diff, serial);
+    }
+    assert_eq!(serial.apply_to_value(original).unwrap(), updated);
+}
+
+#[test]
+fn borrowed_values() {
+    let original = Value::from_mappings([
+        (Value::from("name"), Value::from("original")),
+        (
+            Value::from("tags"),
+            Value::from_sequence([Value::from("a"), Value::from("b")]),
+        ),
+    ]);
+    let updated = Value::from_mappings([
+        (Value::from("name"), Value::from("original name")),
+        (
+            Value::from("tags"),
+            Value::from_sequence([Value::from("b")]),
+        ),
+    ]);
+    let diff = Diff::between_borrowed(&original, &updated);
+    assert_eq!(diff.to_string(), r#"{;^0;8;0;" name"[1;-0;1"#);
+    assert_eq!(diff, Diff::between_values(&original, updated.clone()));
+
+    // Neither value needs to be 'static.
+    let name = String::from("updated");
+    let original = Value::from_sequence([Value::from("original"), Value::from(1)]);
+    let updated = Value::from_sequence([Value::String(name.as_str().into()), Value::from(1)]);
+    let diff = Diff::between_borrowed(&original, &updated);
+    assert_eq!(diff.to_string(), r#"[;~0;"updated""#);
+}
+
+#[test]
+fn streamed_diffs() {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use crate::DiffOptions;
+
+    let mut bytes = Vec::new();
+    Diff::write_between(
+        &Value::from_sequence([Value::from(1), Value::from("two"), Value::from(3)]),
+        &Value::from_sequence([Value::from(1), Value::from("four"), Value::from(3)]),
+        &DiffOptions::default(),
+        &mut bytes,
+    )
+    .unwrap();
+    assert_eq!(
+        Diff::deserialize(&bytes).unwrap().to_string(),
+        r#"[;~1;"four""#
+    );
+
+    // Nested changes are the same as when they are buffered, but the root is
+    // kept rather than replaced when there are more than `max_changes`.
+    let original = Value::from_sequence([
+        Value::from_sequence([Value::from("a"), Value::from("b"), Value::from("c")]),
+        Value::from_mappings([(Value::from("key"), Value::from(1))]),
+        Value::from("unchanged"),
+    ]);
+    let updated = Value::from_sequence([
+        Value::from_sequence([Value::from("b"), Value::from("c"), Value::from("d")]),
+        Value::from_mappings([(Value::from("key"), Value::from(2))]),
+        Value::from("unchanged"),
+    ]);
+    let limited = DiffOptions::default().max_changes(1);
+    for options in [DiffOptions::default(), limited.clone()] {
+        let mut bytes = Vec::new();
+        Diff::write_between(&original, &updated, &options, &mut bytes).unwrap();
+        let streamed = Diff::deserialize(&bytes).unwrap();
+        assert_eq!(streamed.to_string(), r#"[;[0;-0;1+2;"d"]{1;~0;2"#);
+    }
+    let buffered = Diff::between_borrowed_with(&original, &updated, &limited);
+    assert!(
+        matches!(
+            buffered.changes.as_slice(),
+            [Change::Replace { index: None, .. }]
+        ),
+        "{buffered}"
+    );
+
+    // The writer doesn't need to be `Send`, even when values are diffed on
+    // other threads.
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let written = Rc::new(RefCell::new(Vec::new()));
+    Diff::write_between(
+        &original,
+        &updated,
+        &DiffOptions::default(),
+        SharedWriter(written.clone()),
+    )
+    .unwrap();
+    assert_eq!(
+        Diff::deserialize(&written.borrow()).unwrap().to_string(),
+        r#"[;[0;-0;1+2;"d"]{1;~0;2"#
+    );
+}
+
+#[test]
+fn encoded_documents() {
+    use pot::format::{self, Integer, Kind};
+
+    #[derive(Serialize, Deserialize, Clone)]
+    struct Document {
+        title: String,
+        body: Vec<String>,
+        revision: u32,
+    }
+
+    let original = Document {
+        title: String::from("a document"),
+        body: (0..100).map(|line| format!("line {line}")).collect(),
+        revision: 1,
+    };
+    let mut updated = original.clone();
+    updated.revision = 2;
+    updated.body[50].push_str(" edited");
+    let diff = Diff::between_encoded(
+        &pot::to_vec(&original).unwrap(),
+        &pot::to_vec(&updated).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(diff.to_string(), r#"{;[1;^50;7;0;" edited"]~2;2"#);
+
+    // Containers whose lengths differ are aligned in place.
+    let mut updated = original.clone();
+    updated.body.remove(10);
+    updated.body[50].push_str(" edited");
+    updated.body.insert(80, String::from("inserted"));
+    updated.body.push(String::from("appended"));
+    let diff = Diff::between_encoded(
+        &pot::to_vec(&original).unwrap(),
+        &pot::to_vec(&updated).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        diff.to_string(),
+        r#"{;[1;-10;1^50;7;0;" edited"+80;"inserted"+100;"appended""#
+    );
+    let decoded = |document: &Document| {
+        pot::from_slice::<OwnedValue>(&pot::to_vec(document).unwrap())
+            .unwrap()
+            .0
+    };
+    assert_eq!(
+        diff.apply_to_value(decoded(&original)).unwrap(),
+        decoded(&updated)
+    );
+    let original = (0..20)
+        .map(|i| (format!("key{i:02}"), vec![i]))
+        .collect::<BTreeMap<_, _>>();
+    let mut updated = original.clone();
+    updated.remove("key05");
+    updated.get_mut("key10").unwrap().push(1);
+    updated.insert(String::from("key15a"), Vec::new());
+    updated.insert(String::from("key15b"), Vec::new());
+    let diff = Diff::between_encoded(
+        &pot::to_vec(&original).unwrap(),
+        &pot::to_vec(&updated).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        diff.to_string(),
+        r#"{;-5;1[9;+1;1]+15;"key15a";[]+16;"key15b";[]"#
+    );
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+
+    // Symbols are referenced by the order they were defined in, so identical
+    // bytes can still decode differently.
+    fn document(key: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        format::write_header(&mut bytes, 0).unwrap();
+        format::write_atom_header(&mut bytes, Kind::Sequence, Some(2)).unwrap();
+        format::write_atom_header(&mut bytes, Kind::Map, Some(1)).unwrap();
+        format::write_atom_header(&mut bytes, Kind::Symbol, Some((key.len() as u64) << 1)).unwrap();
+        bytes.extend_from_slice(key.as_bytes());
+        Integer::from(1).write_to(&mut bytes).unwrap();
+        format::write_atom_header(&mut bytes, Kind::Map, Some(1)).unwrap();
+        format::write_atom_header(&mut bytes, Kind::Symbol, Some(1)).unwrap();
+        Integer::from(1).write_to(&mut bytes).unwrap();
+        bytes
+    }
+    let value = |key: &str| {
+        Value::from_sequence([
+            Value::from_mappings([(Value::from(key.to_string()), Value::from(1))]),
+            Value::from_mappings([(Value::from(key.to_string()), Value::from(1))]),
+        ])
+    };
+    let diff = Diff::between_encoded(&document("a"), &document("b")).unwrap();
+    assert_eq!(
+        diff.apply_to_value(value("a")).unwrap(),
+        value("b"),
+        "{diff}"
+    );
+    assert!(Diff::between_encoded(&document("a"), &document("a"))
+        .unwrap()
+        .changes
+        .is_empty());
+}
+
+#[test]
+fn diff_budgets() {
+    use std::time::Duration;
+
+    use crate::DiffOptions;
+
+    // Sequences with nothing in common are replaced once the budget is spent.
+    let original = Value::from_sequence((0..2_000).map(Value::from).collect::<Vec<_>>());
+    let updated = Value::from_sequence((2_000..4_000).map(Value::from).collect::<Vec<_>>());
+    for options in [
+        DiffOptions::default().max_comparisons(1_000),
+        DiffOptions::default().time_limit(Duration::ZERO),
+    ] {
+        let diff = Diff::between_values_with(&original, updated.clone(), &options);
+        assert!(
+            matches!(
+                diff.changes.as_slice(),
+                [Change::Replace { index: None, .. }]
+            ),
+            "{diff}"
+        );
+    }
+
+    // Only the values that weren't diffed within the budget are replaced.
+    let unchanged = Value::from_sequence((4_000..6_000).map(Value::from).collect::<Vec<_>>());
+    let original = Value::from_sequence([original, unchanged.clone()]);
+    let updated = Value::from_sequence([updated, unchanged]);
+    let diff = Diff::between_values_with(
+        &original,
+        updated.clone(),
+        &DiffOptions::default().max_comparisons(1_000),
+    );
+    assert!(
+        matches!(
+            diff.changes.first(),
+            Some(Change::EnterSequence { index: None, .. })
+        ),
+        "{diff}"
+    );
+    assert_eq!(diff.apply_to_value(original).unwrap(), updated);
+}
+
+/// Returns `value` with the entries of every mapping sorted, allowing values
+/// to be compared regardless of the order of their entries.
+fn sorted(value: Value<'static>) -> Value<'static> {
+    match value {
+        Value::Sequence(values) => Value::Sequence(values.into_iter().map(sorted).collect()),
+        Value::Mappings(mappings) => {
+            let mut mappings = mappings
+                .into_iter()
+                .map(|(key, value)| (sorted(key), sorted(value)))
+                .collect::<Vec<_>>();
+            mappings.sort_by_cached_key(|(key, _)| format!("{key:?}"));
+            Value::Mappings(mappings)
+        }
+        other => other,
+    }
+}
+
+#[test]
+fn unordered_mappings() {
+    use std::collections::HashMap;
+
+    use crate::DiffOptions;
+
+    let options = DiffOptions::default().unordered_mappings(true);
+    let original = (0..50)
+        .map(|i| (format!("key{i}"), vec![i; 3]))
+        .collect::<HashMap<String, Vec<u32>>>();
+    // A map with its own hasher iterates in a different order.
+    let reordered = original.clone().into_iter().collect::<HashMap<_, _>>();
+    assert!(Diff::between_with(&original, &reordered, &options)
+        .changes
+        .is_empty());
+
+    let mut updated = reordered;
+    updated.remove("key1");
+    updated.insert(String::from("new"), vec![1]);
+    updated.get_mut("key2").unwrap().push(4);
+    updated.insert(String::from("key3"), Vec::new());
+    let diff = Diff::between_with(&original, &updated, &options);
+    assert_eq!(
+        diff.changes
+            .iter()
+            .filter(|change| matches!(
+                change,
+                Change::EnterKey { .. } | Change::SetKey { .. } | Change::RemoveKey { .. }
+            ))
+            .count(),
+        4,
+        "{diff}"
+    );
+    let reordered = original.clone().into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(diff.apply(&reordered).unwrap(), updated);
+    let decoded = Diff::deserialize(&diff.serialize()).unwrap();
+    assert_eq!(decoded.apply(&original).unwrap(), updated);
+
+    // Encoded mappings are decoded rather than walked in place.
+    let encoded = Diff::between_encoded_with(
+        &pot::to_vec(&original).unwrap(),
+        &pot::to_vec(&updated).unwrap(),
+        &options,
+    )
+    .unwrap();
+    assert_eq!(encoded.apply(&reordered).unwrap(), updated);
+
+    // Keys are only changed if they are present.
+    let mappings = Value::from_mappings([(Value::from("key"), Value::from(1))]);
+    let enter_map = Change::EnterMap {
+        index: None,
+        key: false,
+    };
+    for change in [
+        Change::EnterKey {
+            key: Value::from("missing"),
+        },
+        Change::RemoveKey {
+            key: Value::from("missing"),
+        },
+    ] {
+        let diff = Diff {
+            changes: vec![enter_map.clone(), change],
+        };
+        assert!(matches!(
+            diff.apply_to_value(mappings.clone()),
+            Err(crate::Error::MissingKey)
+        ));
+    }
+    let diff = Diff {
+        changes: vec![
+            enter_map,
+            Change::EnterKey {
+                key: Value::from("key"),
+            },
+            Change::EnterSequence {
+                index: None,
+                key: false,
+            },
+        ],
+    };
+    assert!(matches!(
+        diff.apply_to_value(mappings),
+        Err(crate::Error::TypeMismatch)
+    ));
+}
+
+#[test]
+fn path_policies() {
+    use crate::{DiffOptions, PathPolicy};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Task {
+        id: u32,
+        title: String,
+        updated_at: u64,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Board {
+        avatar: String,
+        tags: Vec<String>,
+        items: Vec<Task>,
+        updated_at: u64,
+    }
+
+    let original = Board {
+        avatar: String::from("https://example.com/avatars/1.png"),
+        tags: vec![String::from("a"), String::from("b"), String::from("c")],
+        items: (0..5)
+            .map(|id| Task {
+                id,
+                title: format!("task number {id}"),
+                updated_at: 1,
+            })
+            .collect(),
+        updated_at: 1,
+    };
+    let options = DiffOptions::default()
+        .policy("**.updated_at", PathPolicy::Ignore)
+        .policy("avatar", PathPolicy::Replace)
+        .policy("tags", PathPolicy::Set)
+        .policy("items", PathPolicy::Keyed(String::from("id")));
+
+    let mut updated = original.clone();
+    updated.updated_at = 2;
+    updated.items[3].updated_at = 2;
+    assert!(Diff::between_with(&original, &updated, &options)
+        .changes
+        .is_empty());
+
+    let mut updated = original.clone();
+    updated.avatar = String::from("https://example.com/avatars/2.png");
+    assert_eq!(
+        Diff::between(&original, &updated).to_string(),
+        r#"{;^0;28;1;"2""#
+    );
+    assert_eq!(
+        Diff::between_with(&original, &updated, &options).to_string(),
+        r#"{;~0;"https://example.com/avatars/2.png""#
+    );
+
+    let mut updated = original.clone();
+    updated.tags = vec![String::from("d"), String::from("c"), String::from("a")];
+    let diff = Diff::between_with(&original, &updated, &options);
+    assert_eq!(diff.to_string(), r#"{;[1;-:"b"+:"d""#);
+    assert_eq!(diff.apply(&original).unwrap().tags, ["a", "c", "d"]);
+
+    let mut updated = original.clone();
+    updated.items.swap(0, 4);
+    updated.items[0].title = String::from("task number four");
+    updated.items[3].updated_at = 2;
+    // Without a key, the edited task is inserted rather than diffed.
+    assert_eq!(
+        Diff::between(&original, &updated).to_string(),
+        r#"{;[2;>0;2;1+0;{"id":4,"title":"task number four","updated_at":1}+3;{"id":3,"title":"task number 3","updated_at":2}$5"#
+    );
+    let diff = Diff::between_with(&original, &updated, &options);
+    assert_eq!(diff.to_string(), r#"{;[2;>4;0;1>1;4;1{0;^1;12;1;"four""#);
+    let mut applied = diff.apply(&original).unwrap();
+    applied.items[3].updated_at = 2;
+    assert_eq!(applied, updated);
+
+    let mut diffable = Diffable::with_options(original, options);
+    diffable.items[1].updated_at = 2;
+    assert!(diffable.diff().is_none());
+}
+
+#[test]
+fn keyed_sequences() {
+    use crate::{DiffOptions, KeyExtractor, PathPolicy};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Task {
+        id: u64,
+        title: String,
+        done: bool,
+    }
+
+    let id = KeyExtractor::new(|value| match value {
+        Value::Mappings(mappings) => mappings.iter().find_map(|(key, value)| match (key, value) {
+            (Value::String(key), Value::Integer(id)) if key == "id" => Some(Value::Integer(*id)),
+            _ => None,
+        }),
+        _ => None,
+    });
+    let options = DiffOptions::default().sequence_key(id.clone());
+
+    let original = (0..6)
+        .map(|id| Task {
+            id,
+            title: format!("write the report for week {id}"),
+            done: false,
+        })
+        .collect::<Vec<_>>();
+    let mut updated = original.clone();
+    updated[2].done = true;
+    let task = updated.remove(4);
+    updated.insert(1, task);
+    updated[1].title.push_str(" again");
+    let diff = Diff::between_with(&original, &updated, &options);
+    assert_eq!(
+        diff.to_string(),
+        r#"[;>4;1;1{1;^1;27;0;" again"}{3;~2;true"#
+    );
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+    assert_eq!(
+        Diff::between_with(
+            &original,
+            &updated,
+            &DiffOptions::default().policy("", PathPolicy::KeyedBy(id))
+        ),
+        diff
+    );
+
+    // Values without keys are matched by their entire value.
+    let original = vec![vec![1, 2, 3], vec![4, 5, 6]];
+    let updated = vec![vec![4, 5, 6], vec![1, 2, 3], vec![7]];
+    assert_eq!(
+        Diff::between_with(&original, &updated, &options),
+        Diff::between(&original, &updated)
+    );
+
+    // Each task with a duplicate key is only matched once, so the extra
+    // task is copied and edited.
+    let task = |id, title: &str| Task {
+        id,
+        title: String::from(title),
+        done: false,
+    };
+    let original = vec![task(1, "first"), task(1, "second"), task(2, "third")];
+    let updated = vec![
+        task(2, "third"),
+        task(1, "second"),
+        task(1, "first"),
+        task(1, "fourth"),
+    ];
+    let diff = Diff::between_with(&original, &updated, &options);
+    assert_eq!(diff.to_string(), r#"[;>2;0;1&+1;/1{3;~1;"fourth""#);
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+
+    // Tasks whose keys aren't matched are diffed as usual.
+    let updated = vec![task(1, "first"), task(3, "second"), task(2, "third")];
+    let diff = Diff::between_with(&original, &updated, &options);
+    assert_eq!(diff.to_string(), "[;{1;~0;3");
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+}
+
+#[test]
+fn similar_values() {
+    use crate::DiffOptions;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Record {
+        name: String,
+        tags: Vec<String>,
+        score: u32,
+    }
+
+    let original = (0..10)
+        .map(|i| Record {
+            name: format!("record {i}"),
+            tags: vec![format!("tag {i}"), String::from("common")],
+            score: i,
+        })
+        .collect::<Vec<_>>();
+    let mut updated = original.clone();
+    for record in updated.iter_mut().step_by(2) {
+        record.score += 100;
+    }
+    updated[3].tags.push(String::from("new"));
+    updated.insert(
+        0,
+        Record {
+            name: String::from("inserted"),
+            tags: Vec::new(),
+            score: 0,
+        },
+    );
+    updated.remove(6);
+
+    let diff = Diff::between(&original, &updated);
+    assert_eq!(
+        diff.to_string(),
+        r#"[;+0;{"name":"inserted","tags":[],"score":0}{1;~2;100}{3;~2;102}{4;[1;+2;"new"]}{5;~2;104}-6;1{6;~2;106}{8;~2;108"#
+    );
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+    let positional = Diff::between_with(
+        &original,
+        &updated,
+        &DiffOptions::default().similarity_threshold(2.),
+    );
+    assert_eq!(positional.apply(&original).unwrap(), updated);
+    assert!(
+        diff.serialize().len() < positional.serialize().len(),
+        "{diff}\n{positional}"
+    );
+    let strict = Diff::between_with(
+        &original,
+        &updated,
+        &DiffOptions::default().similarity_threshold(0.9),
+    );
+    // Records that are no longer similar enough are inserted again rather
+    // than diffed.
+    assert_eq!(
+        strict.to_string(),
+        r#"[;{0;~0..2;"inserted";[]}+1;{"name":"record 0","tags":["tag 0","common"],"score":100}{3;~2;102}{4;[1;+2;"new"]}{5;~2;104}{6;&0;/6/0&1;/6/1~2;106}-7;1{8;~2;108"#
+    );
+    assert_eq!(strict.apply(&original).unwrap(), updated);
+}
+
+#[test]
+fn set_sequences() {
+    use crate::{DiffOptions, PathPolicy};
+
+    let options = DiffOptions::default().policy("", PathPolicy::Set);
+    let set = |members: &[&str]| {
+        members
+            .iter()
+            .map(|member| member.to_string())
+            .collect::<Vec<_>>()
+    };
+    let original = set(&["a", "b", "c", "b"]);
+
+    let diff = Diff::between_with(&original, &set(&["c", "a", "c"]), &options);
+    assert_eq!(diff.to_string(), r#"[;-:"b""#);
+    assert_eq!(diff.apply(&original).unwrap(), ["a", "c"]);
+    let decoded = Diff::deserialize(&diff.serialize()).unwrap();
+    assert_eq!(decoded, diff);
+
+    // Two concurrent edits can be applied in either order.
+    let first = Diff::between_with(&original, &set(&["a", "c", "d"]), &options);
+    let second = Diff::between_with(&original, &set(&["a", "b", "e"]), &options);
+    assert_eq!(first.to_string(), r#"[;-:"b"+:"d""#);
+    assert_eq!(second.to_string(), r#"[;-:"c"+:"e""#);
+    let mut merged = first.apply(&second.apply(&original).unwrap()).unwrap();
+    let mut reversed = second.apply(&first.apply(&original).unwrap()).unwrap();
+    merged.sort();
+    reversed.sort();
+    assert_eq!(merged, ["a", "d", "e"]);
+    assert_eq!(reversed, merged);
+
+    // Applying a diff doesn't depend on the order of the receiver.
+    let diff = Diff::between_with(&original, &set(&["d", "c", "a"]), &options);
+    assert_eq!(diff.apply(&set(&["c", "b", "a"])).unwrap(), ["c", "a", "d"]);
+}
+
+#[test]
+fn unchanged_ends() {
+    let original = (0..10_000)
+        .map(|index| format!("entry {index}"))
+        .collect::<Vec<_>>();
+    let mut updated = original.clone();
+    updated.push(String::from("appended"));
+    let diff = Diff::between(&original, &updated);
+    assert_eq!(diff.to_string(), r#"[;+10000;"appended""#);
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+
+    let mut updated = original.clone();
+    updated.truncate(9_998);
+    let diff = Diff::between(&original, &updated);
+    assert_eq!(diff.to_string(), "[;$9998");
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+
+    // Only the values between the unchanged ends are aligned, and the changes
+    // to them are offset by the unchanged start.
+    let mut updated = original.clone();
+    updated[5_000..5_010].rotate_left(3);
+    updated.remove(6_000);
+    updated[7_000].push_str(" edited");
+    let diff = Diff::between(&original, &updated);
+    assert_eq!(
+        diff.to_string(),
+        r#"[;>5000;5007;3-6000;1^7000;10;0;" edited""#
+    );
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+
+    // Unchanged ends of strings are kept at character boundaries.
+    let original = String::from("prefix aé suffix");
+    let updated = String::from("prefix aè suffix");
+    let diff = Diff::between(&original, &updated);
+    assert_eq!(diff.to_string(), r#"^;8;2;"è""#);
+    assert_eq!(diff.apply(&original).unwrap(), updated);
+}
+
+#[test]
+fn restructured_documents() {
+    use crate::hash::HashTree;
+    use crate::{tree, DiffOptions, Estimated};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Node {
+        text: String,
+        children: Vec<Node>,
+    }
+
+    let node = |text: &str, children: Vec<Node>| Node {
+        text: String::from(text),
+        children,
+    };
+    let paragraph = |index: usize| {
+        node(
+            &format!("paragraph {index} with enough text that copying it is cheaper"),
+            Vec::new(),
+        )
+    };
+    let original = node(
+        "outline",
+        vec![
+            paragraph(0),
+            paragraph(1),
+            paragraph(2),
+            node("appendix", vec![paragraph(3), paragraph(4)]),
+        ],
+    );
+    let options = DiffOptions::default().tree_edit_distance(1_000);
+
+    // Two paragraphs are nested under a new section, one of which is edited,
+    // and the appendix is unwrapped.
+    let mut updated = original.clone();
+    updated.children.splice(
+        1..3,
+        [node(
+            "section",
+            vec![
+                paragraph(1),
+                node(
+                    "paragraph 2 with enough text that copying it is cheap",
+                    Vec::new(),
+                ),
+            ],
+        )],
+    );
+    updated.children.splice(2..3, [paragraph(3), paragraph(4)]);
+
+    let positional = Diff::between(&original, &updated);
+    let restructured = Diff::between_with(&original, &updated, &options);
+    assert_eq!(
+        restructured.to_string(),
+        r#"{;[1;+1;{"text":"section","children":[none,none]}{1;[1;&0;/1/1&1;/1/2{1;^0;53;2;""}]}&+2;/1/3/1/0&+3;/1/3/1/1$4"#
+    );
+    assert_eq!(restructured.apply(&original).unwrap(), updated);
+    assert!(
+        restructured.serialize().len() < positional.serialize().len(),
+        "{restructured}\n{positional}"
+    );
+    let decoded = Diff::deserialize(&restructured.serialize()).unwrap();
+    assert_eq!(decoded, restructured);
+    assert_eq!(
+        Diff::between_encoded_with(
+            &pot::to_vec(&original).unwrap(),
+            &pot::to_vec(&updated).unwrap(),
+            &options
+        )
+        .unwrap(),
+        restructured
+    );
+
+    // Values with more nodes than the limit are diffed as usual.
+    assert_eq!(
+        Diff::between_with(
+            &original,
+            &updated,
+            &DiffOptions::default().tree_edit_distance(10)
+        ),
+        positional
+    );
+
+    // The usual diff is kept when finding the distance runs out of
+    // comparisons, or when the restructured diff is nested too deeply.
+    for limited in [
+        DiffOptions::default().max_comparisons(1_000),
+        DiffOptions::default().max_depth(4),
+    ] {
+        let usual = Diff::between_with(&original, &updated, &limited);
+        let limited = limited.tree_edit_distance(1_000);
+        assert_eq!(Diff::between_with(&original, &updated, &limited), usual);
+    }
+    assert_eq!(
+        Diff::between_with(&original, &updated, &options.clone().max_depth(5)),
+        restructured
+    );
+
+    // The distance isn't searched for when the budget can't cover comparing
+    // every pair of nodes once.
+    let original_value = pot::from_slice::<OwnedValue>(&pot::to_vec(&original).unwrap())
+        .unwrap()
+        .0;
+    let updated_value = pot::from_slice::<OwnedValue>(&pot::to_vec(&updated).unwrap())
+        .unwrap()
+        .0;
+    let hashes = HashTree::new(&original_value);
+    let limited = options.clone().max_comparisons(100);
+    Diff::with_context(&original_value, &hashes, &limited, |context| {
+        let measured = Estimated::measure(&updated_value, context.costs());
+        assert!(tree::diff(&original_value, &hashes, &measured, 1_000, context).is_none());
+        assert!(!context.is_exhausted());
+    });
+
+    // The restructured changes apply whether or not they are smaller.
+    let original = pot::from_slice::<OwnedValue>(&pot::to_vec(&original).unwrap())
+        .unwrap()
+        .0;
+    let wrapped = Value::from_sequence([Value::None, original.clone()]);
+    for (original, updated) in [(&original, &wrapped), (&wrapped, &original)] {
+        let hashes = HashTree::new(original);
+        let restructured = Diff::with_context(original, &hashes, &options, |context| {
+            let updated = Estimated::measure(updated, context.costs());
+            let changes = tree::diff(original, &hashes, &updated, 1_000, context).unwrap();
+            Diff {
+                changes: changes.changes,
+            }
+        });
+        assert_eq!(
+            &restructured.apply_to_value(original.clone()).unwrap(),
+            updated,
+            "{restructured}"
+        );
+    }
+}
@@ -46,7 +46,7 @@ impl<'a> Display for ValueDisplay<'a> {
     }
 }
 
-struct StringDisplay<'a>(pub &'a str);
+pub struct StringDisplay<'a>(pub &'a str);
 
 impl<'a> Display for StringDisplay<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -73,6 +73,21 @@ impl<'a> Display for StringDisplay<'a> {
     }
 }
 
+/// Displays a path of indices, such as `/0/3`. The root is displayed as `/`.
+pub struct PathDisplay<'a>(pub &'a [usize]);
+
+impl<'a> Display for PathDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_char('/');
+        }
+        for index in self.0 {
+            write!(f, "/{index}")?;
+        }
+        Ok(())
+    }
+}
+
 pub fn decode_string(string: &str, out: &mut String) -> Result<usize, DecodeError> {
     let mut bytes_read = 0;
     let string = if let Some(string) = string.strip_prefix('"') {
@@ -145,7 +160,7 @@ fn string_display_test() {
 /// hexadecimal. When printable characters are encountered, the mode can be
 /// switched back to ascii. Mode switches use the '|' character, which makes it
 /// an unprintable character.
-struct BytesDisplay<'a>(pub &'a [u8]);
+pub struct BytesDisplay<'a>(pub &'a [u8]);
 
 impl<'a> Display for BytesDisplay<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -0,0 +1,835 @@
+//! Diffing of restructured values using the edit distance between ordered
+//! trees, as described by Kaizhong Zhang and Dennis Shasha in "Simple Fast
+//! Algorithms for the Editing Distance between Trees and Related Problems".
+//!
+//! Every value is a node whose children are the values it contains. The edit
+//! distance counts the nodes that are removed, inserted, or relabeled, where
+//! inserting a node can wrap a run of siblings in a new container, and
+//! removing a node moves its children up to its parent. The mapping between
+//! the nodes of the two values that the distance is found with is turned into
+//! changes: values that are mapped to a value in the same container are
+//! diffed in place, containers that are mapped elsewhere are copied from the
+//! original value, and new containers are inserted with their mapped contents
+//! copied into them.
+
+use pot::Value;
+
+use crate::hash::HashTree;
+use crate::{Change, ChangeKind, Changes, Context, Diff, Estimated, EstimatedValue};
+
+/// The cost of removing a node from the original tree.
+const REMOVE: usize = 1;
+/// The cost of inserting a node into the updated tree.
+const INSERT: usize = 1;
+
+/// Returns the changes that restructure `original` into `updated`, or None if
+/// either value has more than `max_nodes` nodes, the budget for creating the
+/// diff was exhausted, or the changes enter containers nested deeper than
+/// [`DiffOptions::max_depth()`](crate::DiffOptions::max_depth).
+pub fn diff<'c>(
+    original: &Value<'_>,
+    hashes: &HashTree,
+    updated: &Estimated<'_>,
+    max_nodes: usize,
+    context: &Context<'c>,
+) -> Option<Changes<'c>> {
+    let mut originals = Vec::new();
+    if !add_original(
+        original,
+        hashes,
+        None,
+        &mut Vec::new(),
+        max_nodes,
+        &mut originals,
+    ) {
+        return None;
+    }
+    let mut updateds = Vec::new();
+    if !add_updated(updated, None, max_nodes, &mut updateds) {
+        return None;
+    }
+
+    let original_leftmost = originals
+        .iter()
+        .map(|node| node.leftmost)
+        .collect::<Vec<_>>();
+    let updated_leftmost = updateds
+        .iter()
+        .map(|node| node.leftmost)
+        .collect::<Vec<_>>();
+    // The distance between the roots alone compares every pair of nodes, so
+    // the distances between subtrees aren't allocated unless the budget
+    // covers at least that many comparisons.
+    if !context
+        .budget
+        .affords(originals.len().checked_mul(updateds.len())?)
+    {
+        return None;
+    }
+    let mut partners = vec![None; updateds.len()];
+    for (original, updated) in mapping(
+        &original_leftmost,
+        &updated_leftmost,
+        |original, updated| relabel(&originals[original], &updateds[updated], context),
+        |comparisons| {
+            context.budget.spend(comparisons);
+            context.is_exhausted()
+        },
+    )? {
+        partners[updated] = Some(original);
+    }
+
+    // Children always precede their parents.
+    let mut anchored = vec![false; updateds.len()];
+    for (index, node) in updateds.iter().enumerate() {
+        anchored[index] = !node.children.is_empty()
+            && (partners[index].is_some() || node.children.iter().any(|child| anchored[*child]));
+    }
+
+    let restructure = Restructure {
+        originals,
+        updateds,
+        partners,
+        anchored,
+        context,
+    };
+    let mut diff = Changes::new(context);
+    restructure.build(
+        restructure.updateds.len() - 1,
+        Some(restructure.originals.len() - 1),
+        None,
+        &mut diff,
+    );
+    let max_depth = context.options.max_depth.unwrap_or(usize::MAX);
+    (entered_depth(&diff.changes) <= max_depth).then_some(diff)
+}
+
+/// Returns the number of containers that `changes` enters at most at once.
+fn entered_depth(changes: &[Change]) -> usize {
+    let mut depth = 0;
+    let mut deepest = 0;
+    for change in changes {
+        match change {
+            Change::EnterSequence { .. } | Change::EnterMap { .. } => {
+                depth += 1;
+                deepest = deepest.max(depth);
+            }
+            Change::Exit => depth -= 1,
+            _ => {}
+        }
+    }
+    deepest
+}
+
+/// A value within the original value.
+struct Original<'a, 'o> {
+    value: &'a Value<'o>,
+    hashes: &'a HashTree,
+    /// The key of the mapping entry this value is in, along with its hashes.
+    key: Option<(&'a Value<'o>, &'a HashTree)>,
+    /// The location of this value within the original value.
+    path: Vec<usize>,
+    children: Vec<usize>,
+    /// The postorder index of this value's leftmost leaf descendant.
+    leftmost: usize,
+}
+
+/// A value within the updated value.
+struct Updated<'a, 'u> {
+    value: &'a Estimated<'u>,
+    /// The key of the mapping entry this value is in.
+    key: Option<&'a Estimated<'u>>,
+    children: Vec<usize>,
+    /// The postorder index of this value's leftmost leaf descendant.
+    leftmost: usize,
+}
+
+/// Adds the nodes of `value` to `nodes` in postorder, returning false if
+/// there are more than `max_nodes` of them.
+fn add_original<'a, 'o>(
+    value: &'a Value<'o>,
+    hashes: &'a HashTree,
+    key: Option<(&'a Value<'o>, &'a HashTree)>,
+    path: &mut Vec<usize>,
+    max_nodes: usize,
+    nodes: &mut Vec<Original<'a, 'o>>,
+) -> bool {
+    let leftmost = nodes.len();
+    let mut children = Vec::new();
+    let contents = match value {
+        Value::Sequence(values) => values
+            .iter()
+            .zip(hashes.sequence())
+            .map(|(value, hashes)| (None, value, hashes))
+            .collect(),
+        Value::Mappings(mappings) => mappings
+            .iter()
+            .zip(hashes.mappings())
+            .map(|((key, value), (key_hashes, hashes))| (Some((key, key_hashes)), value, hashes))
+            .collect(),
+        _ => Vec::new(),
+    };
+    for (index, (key, value, hashes)) in contents.into_iter().enumerate() {
+        path.push(index);
+        let added = add_original(value, hashes, key, path, max_nodes, nodes);
+        path.pop();
+        if !added {
+            return false;
+        }
+        children.push(nodes.len() - 1);
+    }
+    nodes.push(Original {
+        value,
+        hashes,
+        key,
+        path: path.clone(),
+        children,
+        leftmost,
+    });
+    nodes.len() <= max_nodes
+}
+
+/// Adds the nodes of `value` to `nodes` in postorder, returning false if
+/// there are more than `max_nodes` of them.
+fn add_updated<'a, 'u>(
+    value: &'a Estimated<'u>,
+    key: Option<&'a Estimated<'u>>,
+    max_nodes: usize,
+    nodes: &mut Vec<Updated<'a, 'u>>,
+) -> bool {
+    let leftmost = nodes.len();
+    let mut children = Vec::new();
+    let contents = match &value.value {
+        EstimatedValue::Sequence(values) => values.iter().map(|value| (None, value)).collect(),
+        EstimatedValue::Mappings(mappings) => mappings
+            .iter()
+            .map(|(key, value)| (Some(key), value))
+            .collect(),
+        _ => Vec::new(),
+    };
+    for (key, value) in contents {
+        if !add_updated(value, key, max_nodes, nodes) {
+            return false;
+        }
+        children.push(nodes.len() - 1);
+    }
+    nodes.push(Updated {
+        value,
+        key,
+        children,
+        leftmost,
+    });
+    nodes.len() <= max_nodes
+}
+
+/// Returns the cost of turning `original` into `updated`, or None if a
+/// container can't be turned into a different kind of value.
+fn relabel(
+    original: &Original<'_, '_>,
+    updated: &Updated<'_, '_>,
+    context: &Context<'_>,
+) -> Option<usize> {
+    let mut cost = usize::from(!same_key(original, updated, context));
+    match (original.value, &updated.value.value) {
+        (Value::Sequence(_), EstimatedValue::Sequence(_))
+        | (Value::Mappings(_), EstimatedValue::Mappings(_)) => {}
+        (Value::Sequence(_) | Value::Mappings(_), _)
+        | (_, EstimatedValue::Sequence(_) | EstimatedValue::Mappings(_)) => return None,
+        (original_value, _) => {
+            if !context.equal(original_value, original.hashes.hash, updated.value) {
+                cost += 1;
+            }
+        }
+    }
+    Some(cost)
+}
+
+/// Returns true if `original` and `updated` are in mapping entries with equal
+/// keys, or are both not in mapping entries.
+fn same_key(original: &Original<'_, '_>, updated: &Updated<'_, '_>, context: &Context<'_>) -> bool {
+    match (original.key, updated.key) {
+        (Some((original, hashes)), Some(updated)) => context.equal(original, hashes.hash, updated),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Builds the changes that turn the original value into the updated value,
+/// given the nodes of each value and the original node each updated node is
+/// mapped to.
+struct Restructure<'a, 'o, 'u, 'c> {
+    originals: Vec<Original<'a, 'o>>,
+    updateds: Vec<Updated<'a, 'u>>,
+    partners: Vec<Option<usize>>,
+    /// Whether each updated node is or contains a container with contents
+    /// that is mapped to an original node, whose contents are copied rather
+    /// than included in the diff.
+    anchored: Vec<bool>,
+    context: &'a Context<'c>,
+}
+
+impl Restructure<'_, '_, '_, '_> {
+    /// Logs the changes that turn the value at `index` into the updated node
+    /// `updated`, where the value is a copy of the original node `current`
+    /// or was newly inserted.
+    fn build(
+        &self,
+        updated: usize,
+        current: Option<usize>,
+        index: Option<usize>,
+        diff: &mut Changes<'_>,
+    ) {
+        let node = &self.updateds[updated];
+        if current.map_or(false, |current| self.equal(current, updated)) {
+            return;
+        }
+        let partner = self.partners[updated];
+
+        if !matches!(
+            node.value.value,
+            EstimatedValue::Sequence(_) | EstimatedValue::Mappings(_)
+        ) {
+            if let Some(current) = current.filter(|current| partner == Some(*current)) {
+                let original = &self.originals[current];
+                let mut changes = Changes::new(self.context);
+                Diff::create_diff(
+                    index,
+                    original.value,
+                    original.hashes,
+                    node.value,
+                    false,
+                    self.context,
+                    &mut changes,
+                );
+                if !self.context.prefers_replacement(
+                    changes.cost,
+                    ChangeKind::Replace,
+                    node.value.cost + self.context.optional_argument(index),
+                ) {
+                    diff.append(changes);
+                    return;
+                }
+            }
+            self.replace(index, node.value.to_value(), diff);
+            return;
+        }
+
+        if let Some(current) = current.filter(|current| partner == Some(*current)) {
+            self.enter(updated, index, diff);
+            self.rebuild(updated, current, diff);
+            diff.exit();
+        } else if let Some(partner) = self
+            .copy_source(updated)
+            // The root value can't be entered after it was copied.
+            .filter(|partner| index.is_some() || self.equal(*partner, updated))
+        {
+            self.copy(index, partner, diff);
+            self.change_copy(updated, partner, index, diff);
+        } else if let Some(current) = current.filter(|current| self.same_kind(*current, updated)) {
+            self.enter(updated, index, diff);
+            self.rebuild(updated, current, diff);
+            diff.exit();
+        } else if index.is_some() && self.anchored[updated] {
+            self.replace(index, self.skeleton(updated), diff);
+            self.enter(updated, index, diff);
+            self.fill(updated, diff);
+            diff.exit();
+        } else {
+            self.replace(index, node.value.to_value(), diff);
+        }
+    }
+
+    /// Logs the changes that turn the contents of the entered container,
+    /// which holds the contents of the original node `current`, into the
+    /// contents of the updated node `updated`.
+    fn rebuild(&self, updated: usize, current: usize, diff: &mut Changes<'_>) {
+        let children = &self.updateds[updated].children;
+        let is_mapping = matches!(self.originals[current].value, Value::Mappings(_));
+        let originals = &self.originals[current].children;
+        let mut next_original = 0;
+        for (index, &child) in children.iter().enumerate() {
+            // Values mapped to a value in the same container keep their
+            // position relative to the other values that are kept.
+            let kept = self.partners[child].and_then(|partner| {
+                originals[next_original..]
+                    .iter()
+                    .position(|original| *original == partner)
+                    .map(|offset| next_original + offset)
+            });
+            if let Some(kept) = kept {
+                if kept > next_original {
+                    let length = kept - next_original;
+                    diff.log_change(
+                        ChangeKind::Remove,
+                        self.context.argument(index) + self.context.argument(length),
+                        Change::Remove { index, length },
+                    );
+                }
+                next_original = kept + 1;
+                let key = self.updateds[child].key.filter(|_| {
+                    !same_key(
+                        &self.originals[originals[kept]],
+                        &self.updateds[child],
+                        self.context,
+                    )
+                });
+                if let Some(key) = key {
+                    diff.log_change(
+                        ChangeKind::ReplaceKey,
+                        self.context.argument(index) + key.cost,
+                        Change::ReplaceKey {
+                            index,
+                            key: key.to_value(),
+                        },
+                    );
+                }
+                self.build(child, Some(originals[kept]), Some(index), diff);
+            } else {
+                self.insert(child, index, is_mapping, diff);
+            }
+        }
+        if next_original < originals.len() {
+            diff.log_change(
+                ChangeKind::Truncate,
+                self.context.argument(children.len()),
+                Change::Truncate {
+                    length: children.len(),
+                },
+            );
+        }
+    }
+
+    /// Logs the changes that insert the updated node `updated` at `index`
+    /// within the entered container.
+    fn insert(&self, updated: usize, index: usize, is_mapping: bool, diff: &mut Changes<'_>) {
+        let node = &self.updateds[updated];
+        let partner = self.copy_source(updated);
+        let value = if partner.is_some() {
+            Value::None
+        } else {
+            self.skeleton(updated)
+        };
+        let costs = self.context.costs();
+        match (is_mapping, partner, node.key) {
+            (true, _, Some(key)) => diff.log_change(
+                ChangeKind::InsertMapping,
+                self.context.argument(index) + key.cost + Estimated::measure(&value, costs).cost,
+                Change::InsertMapping {
+                    index,
+                    key: key.to_value(),
+                    value,
+                },
+            ),
+            (_, Some(partner), _) => {
+                let source = self.originals[partner].path.clone();
+                diff.log_change(
+                    ChangeKind::InsertCopy,
+                    self.context.argument(index) + self.context.copy_cost(&source),
+                    Change::InsertCopy { index, source },
+                );
+            }
+            _ => diff.log_change(
+                ChangeKind::Insert,
+                self.context.argument(index) + Estimated::measure(&value, costs).cost,
+                Change::Insert { index, value },
+            ),
+        }
+
+        if let Some(partner) = partner {
+            if is_mapping {
+                self.copy(Some(index), partner, diff);
+            }
+            self.change_copy(updated, partner, Some(index), diff);
+        } else if self.anchored[updated] {
+            self.enter(updated, Some(index), diff);
+            self.fill(updated, diff);
+            diff.exit();
+        }
+    }
+
+    /// Logs the changes that turn a copy of the original node `original` at
+    /// `index` into the updated node `updated`.
+    fn change_copy(
+        &self,
+        updated: usize,
+        original: usize,
+        index: Option<usize>,
+        diff: &mut Changes<'_>,
+    ) {
+        if !self.equal(original, updated) {
+            self.enter(updated, index, diff);
+            self.rebuild(updated, original, diff);
+            diff.exit();
+        }
+    }
+
+    /// Logs the changes that copy the contents left out of the
+    /// [`skeleton()`](Self::skeleton) of the updated node `updated` into the
+    /// entered container.
+    fn fill(&self, updated: usize, diff: &mut Changes<'_>) {
+        for (index, &child) in self.updateds[updated].children.iter().enumerate() {
+            if let Some(partner) = self.copy_source(child) {
+                self.copy(Some(index), partner, diff);
+                self.change_copy(child, partner, Some(index), diff);
+            } else if self.anchored[child] {
+                self.enter(child, Some(index), diff);
+                self.fill(child, diff);
+                diff.exit();
+            }
+        }
+    }
+
+    /// Returns the value of the updated node `updated`, with each of the
+    /// values within it that are copied from the original value left as None.
+    fn skeleton(&self, updated: usize) -> Value<'static> {
+        let node = &self.updateds[updated];
+        if !self.anchored[updated] {
+            return node.value.to_value();
+        }
+        let child = |child: &usize| {
+            if self.copy_source(*child).is_some() {
+                Value::None
+            } else {
+                self.skeleton(*child)
+            }
+        };
+        match node.value.value {
+            EstimatedValue::Sequence(_) => {
+                Value::Sequence(node.children.iter().map(child).collect())
+            }
+            _ => Value::Mappings(
+                node.children
+                    .iter()
+                    .map(|index| {
+                        let key = self.updateds[*index]
+                            .key
+                            .map_or(Value::None, Estimated::to_value);
+                        (key, child(index))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Returns the original node that the updated node `updated` is copied
+    /// from when it isn't kept in place, if it is a container with contents
+    /// that is mapped to one.
+    fn copy_source(&self, updated: usize) -> Option<usize> {
+        self.partners[updated].filter(|_| !self.updateds[updated].children.is_empty())
+    }
+
+    fn enter(&self, updated: usize, index: Option<usize>, diff: &mut Changes<'_>) {
+        let argument = self.context.optional_argument(index);
+        if matches!(
+            self.updateds[updated].value.value,
+            EstimatedValue::Sequence(_)
+        ) {
+            diff.enter(
+                ChangeKind::EnterSequence,
+                argument,
+                Change::EnterSequence { index, key: false },
+            );
+        } else {
+            diff.enter(
+                ChangeKind::EnterMap,
+                argument,
+                Change::EnterMap { index, key: false },
+            );
+        }
+    }
+
+    fn replace(&self, index: Option<usize>, value: Value<'static>, diff: &mut Changes<'_>) {
+        let cost = Estimated::measure(&value, self.context.costs()).cost;
+        diff.log_change(
+            ChangeKind::Replace,
+            self.context.optional_argument(index) + cost,
+            Change::Replace { index, value },
+        );
+    }
+
+    fn copy(&self, index: Option<usize>, original: usize, diff: &mut Changes<'_>) {
+        let source = self.originals[original].path.clone();
+        diff.log_change(
+            ChangeKind::Copy,
+            self.context.optional_argument(index) + self.context.copy_cost(&source),
+            Change::Copy { index, source },
+        );
+    }
+
+    fn equal(&self, original: usize, updated: usize) -> bool {
+        let original = &self.originals[original];
+        self.context.equal(
+            original.value,
+            original.hashes.hash,
+            self.updateds[updated].value,
+        )
+    }
+
+    fn same_kind(&self, original: usize, updated: usize) -> bool {
+        matches!(
+            (
+                self.originals[original].value,
+                &self.updateds[updated].value.value
+            ),
+            (Value::Sequence(_), EstimatedValue::Sequence(_))
+                | (Value::Mappings(_), EstimatedValue::Mappings(_))
+        )
+    }
+}
+
+/// Returns the mapping between the nodes of two trees with the smallest edit
+/// distance, as a list of `(original_index, updated_index)` pairs.
+///
+/// Each tree is given as the index of the leftmost leaf descendant of each of
+/// its nodes, with the nodes in postorder. `relabel` is invoked with an
+/// original index and an updated index, and should return the cost of
+/// turning the original node into the updated node, or None if it can't be.
+///
+/// `spend` is invoked with the number of pairs of nodes that are about to be
+/// compared before each row of the distances between two forests is
+/// computed, and returns whether the budget for comparing them is exhausted.
+/// Once it is, None is returned. None is also returned if the distances
+/// between every pair of subtrees don't fit in memory.
+fn mapping<R, S>(
+    original: &[usize],
+    updated: &[usize],
+    relabel: R,
+    spend: S,
+) -> Option<Vec<(usize, usize)>>
+where
+    R: FnMut(usize, usize) -> Option<usize>,
+    S: FnMut(usize) -> bool,
+{
+    if original.is_empty() || updated.is_empty() {
+        return Some(Vec::new());
+    }
+
+    let mut state = State {
+        original,
+        updated,
+        relabel,
+        spend,
+        trees: vec![0; original.len().checked_mul(updated.len())?],
+        forest: Vec::new(),
+    };
+    for &original_root in &keyroots(original) {
+        for &updated_root in &keyroots(updated) {
+            state.forest_distance(original_root, updated_root)?;
+        }
+    }
+
+    // Walk back through the distances between forests to find the nodes
+    // that were mapped, starting from the roots of both trees.
+    let mut mapping = Vec::new();
+    let mut pending = vec![(original.len() - 1, updated.len() - 1)];
+    while let Some((original_root, updated_root)) = pending.pop() {
+        state.forest_distance(original_root, updated_root)?;
+        let original_start = original[original_root];
+        let updated_start = updated[updated_root];
+        let width = updated_root - updated_start + 2;
+        let forest = &state.forest;
+        let mut original_len = original_root - original_start + 1;
+        let mut updated_len = updated_root - updated_start + 1;
+        while original_len > 0 || updated_len > 0 {
+            let distance = forest[original_len * width + updated_len];
+            if original_len > 0 && updated_len > 0 {
+                let original_node = original_start + original_len - 1;
+                let updated_node = updated_start + updated_len - 1;
+                if original[original_node] == original_start
+                    && updated[updated_node] == updated_start
+                {
+                    if (state.relabel)(original_node, updated_node).map_or(false, |cost| {
+                        distance == forest[(original_len - 1) * width + updated_len - 1] + cost
+                    }) {
+                        mapping.push((original_node, updated_node));
+                        original_len -= 1;
+                        updated_len -= 1;
+                        continue;
+                    }
+                } else {
+                    let original_before = original[original_node] - original_start;
+                    let updated_before = updated[updated_node] - updated_start;
+                    if distance
+                        == forest[original_before * width + updated_before]
+                            + state.trees[original_node * updated.len() + updated_node]
+                    {
+                        pending.push((original_node, updated_node));
+                        original_len = original_before;
+                        updated_len = updated_before;
+                        continue;
+                    }
+                }
+            }
+            if original_len > 0
+                && distance == forest[(original_len - 1) * width + updated_len] + REMOVE
+            {
+                original_len -= 1;
+            } else {
+                updated_len -= 1;
+            }
+        }
+    }
+    Some(mapping)
+}
+
+/// Returns the nodes that have no ancestor sharing their leftmost leaf, in
+/// ascending order.
+fn keyroots(leftmost: &[usize]) -> Vec<usize> {
+    let mut roots = vec![None; leftmost.len()];
+    for (node, &leaf) in leftmost.iter().enumerate() {
+        roots[leaf] = Some(node);
+    }
+    let mut keyroots = roots.into_iter().flatten().collect::<Vec<_>>();
+    keyroots.sort_unstable();
+    keyroots
+}
+
+struct State<'a, R, S> {
+    original: &'a [usize],
+    updated: &'a [usize],
+    relabel: R,
+    spend: S,
+    /// The distance between each pair of subtrees.
+    trees: Vec<usize>,
+    /// The distances between the forests of the last pair of subtrees whose
+    /// distance was computed.
+    forest: Vec<usize>,
+}
+
+impl<R, S> State<'_, R, S>
+where
+    R: FnMut(usize, usize) -> Option<usize>,
+    S: FnMut(usize) -> bool,
+{
+    /// Computes the distances between the forests of the subtrees rooted at
+    /// `original_root` and `updated_root`, storing the distance between each
+    /// pair of subtrees that share their leftmost leaves with the roots.
+    /// Returns None if the budget was exhausted first.
+    fn forest_distance(&mut self, original_root: usize, updated_root: usize) -> Option<()> {
+        let original_start = self.original[original_root];
+        let updated_start = self.updated[updated_root];
+        let height = original_root - original_start + 2;
+        let width = updated_root - updated_start + 2;
+        self.forest.clear();
+        self.forest.resize(height * width, 0);
+        for original_len in 1..height {
+            self.forest[original_len * width] = self.forest[(original_len - 1) * width] + REMOVE;
+        }
+        for updated_len in 1..width {
+            self.forest[updated_len] = self.forest[updated_len - 1] + INSERT;
+        }
+
+        for original_len in 1..height {
+            if (self.spend)(width - 1) {
+                return None;
+            }
+            let original_node = original_start + original_len - 1;
+            for updated_len in 1..width {
+                let updated_node = updated_start + updated_len - 1;
+                let removed = self.forest[(original_len - 1) * width + updated_len] + REMOVE;
+                let inserted = self.forest[original_len * width + updated_len - 1] + INSERT;
+                let tree = original_node * self.updated.len() + updated_node;
+                let distance = if self.original[original_node] == original_start
+                    && self.updated[updated_node] == updated_start
+                {
+                    // Both forests are whole trees.
+                    let relabeled = (self.relabel)(original_node, updated_node).map(|cost| {
+                        self.forest[(original_len - 1) * width + updated_len - 1] + cost
+                    });
+                    let distance = removed.min(inserted).min(relabeled.unwrap_or(usize::MAX));
+                    self.trees[tree] = distance;
+                    distance
+                } else {
+                    let original_before = self.original[original_node] - original_start;
+                    let updated_before = self.updated[updated_node] - updated_start;
+                    removed.min(inserted).min(
+                        self.forest[original_before * width + updated_before] + self.trees[tree],
+                    )
+                };
+                self.forest[original_len * width + updated_len] = distance;
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mapping;
+
+    /// Returns the leftmost leaves and labels of a tree written as nested
+    /// parentheses, such as `a(b c)`, in postorder.
+    fn parse(tree: &str) -> (Vec<usize>, Vec<char>) {
+        fn node(
+            chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+            leftmost: &mut Vec<usize>,
+            labels: &mut Vec<char>,
+        ) {
+            let label = chars.next().expect("label");
+            let first = leftmost.len();
+            if chars.peek() == Some(&'(') {
+                chars.next();
+                while chars.peek() != Some(&')') {
+                    if chars.peek() == Some(&' ') {
+                        chars.next();
+                    } else {
+                        node(chars, leftmost, labels);
+                    }
+                }
+                chars.next();
+            }
+            leftmost.push(first);
+            labels.push(label);
+        }
+        let mut leftmost = Vec::new();
+        let mut labels = Vec::new();
+        node(&mut tree.chars().peekable(), &mut leftmost, &mut labels);
+        (leftmost, labels)
+    }
+
+    #[track_caller]
+    fn distance(original: &str, updated: &str) -> usize {
+        let (original_leftmost, original_labels) = parse(original);
+        let (updated_leftmost, updated_labels) = parse(updated);
+        let relabel =
+            |o: usize, u: usize| Some(usize::from(original_labels[o] != updated_labels[u]));
+        let mapping = mapping(&original_leftmost, &updated_leftmost, relabel, |_| false).unwrap();
+        let relabeled = mapping
+            .iter()
+            .filter(|(o, u)| original_labels[*o] != updated_labels[*u])
+            .count();
+        original_labels.len() + updated_labels.len() - 2 * mapping.len() + relabeled
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(distance("a", "a"), 0);
+        assert_eq!(distance("a", "b"), 1);
+        assert_eq!(distance("a(b c)", "a(b c)"), 0);
+        // Wrapping and unwrapping a run of siblings.
+        assert_eq!(distance("a(b c d)", "a(b x(c d))"), 1);
+        assert_eq!(distance("a(b x(c d))", "a(b c d)"), 1);
+        // The example from Zhang and Shasha's paper.
+        assert_eq!(distance("f(d(a c(b)) e)", "f(c(d(a b)) e)"), 2);
+
+        // No mapping is found once the budget is exhausted.
+        let (leftmost, _) = parse("a(b c)");
+        let mut spent = 0;
+        let spend = |pairs: usize| {
+            spent += pairs;
+            spent > 5
+        };
+        assert!(mapping(&leftmost, &leftmost, |_, _| Some(0), spend).is_none());
+        assert!(mapping(&leftmost, &leftmost, |_, _| Some(0), |_| false).is_some());
+        // The budget is spent one row at a time, so finding the distance
+        // between two deep trees stops partway through their roots.
+        let (deep, _) = parse(&format!("{}b{}", "a(".repeat(1_000), ")".repeat(1_000)));
+        let mut spent = 0;
+        let spend = |pairs: usize| {
+            spent += pairs;
+            spent > 2_500
+        };
+        assert!(mapping(&deep, &deep, |_, _| Some(0), spend).is_none());
+        assert_eq!(spent, 3 * 1_001);
+    }
+}